@@ -0,0 +1,80 @@
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const RED: &str = "31";
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+const CYAN: &str = "36";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// `auto` only colors a terminal, and NO_COLOR turns it off unless it's asked for explicitly.
+pub fn init(choice: &str) {
+    let enabled = match choice {
+        "always" => true,
+        "never" => false,
+        _ => {
+            env::var_os("NO_COLOR").map(|value| value.is_empty()).unwrap_or(true) &&
+            env::var("TERM").map(|term| term != "dumb").unwrap_or(true) &&
+            unsafe { ::libc::isatty(::libc::STDOUT_FILENO) } != 0
+        }
+    };
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+fn paint(code: &str, text: &str) -> String {
+    if ENABLED.load(Ordering::Relaxed) {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        String::from(text)
+    }
+}
+
+// The `modified:` labels in front of each changed file, padded before they're colored because
+// the escapes would throw the alignment off.
+pub fn change(change: &str) -> String {
+    let code = match change {
+        "added" | "new" => GREEN,
+        "deleted" => RED,
+        _ => YELLOW,
+    };
+    paint(code, &format!("{:9}", format!("{}:", change)))
+}
+
+// The `[ok]` labels of the doctor checks.
+pub fn status(name: &str) -> String {
+    let code = match name {
+        "ok" => GREEN,
+        "warning" => YELLOW,
+        _ => RED,
+    };
+    paint(code, &format!("{:9}", format!("[{}]", name)))
+}
+
+// Colors a unified diff line by line, like `git diff`.
+pub fn diff(patch: &str) -> String {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return String::from(patch);
+    }
+
+    let mut colored = String::with_capacity(patch.len());
+    for line in patch.lines() {
+        let code = if line.starts_with("+++") || line.starts_with("---") {
+            None
+        } else if line.starts_with('+') {
+            Some(GREEN)
+        } else if line.starts_with('-') {
+            Some(RED)
+        } else if line.starts_with("@@") {
+            Some(CYAN)
+        } else {
+            None
+        };
+        match code {
+            Some(code) => colored.push_str(&paint(code, line)),
+            None => colored.push_str(line),
+        }
+        colored.push('\n');
+    }
+    colored
+}
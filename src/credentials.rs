@@ -0,0 +1,457 @@
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use git2::Config;
+use secret_service::{Collection, EncryptionType, Item};
+use secret_service::SecretService;
+
+const TOKEN_ATTRIBUTE: &str = "github";
+const TOKEN_VALUE: &str = "access_token";
+
+// Tokens saved before labels existed were all labeled "flake", it keeps finding them.
+pub const DEFAULT_LABEL: &str = "flake";
+
+// Where the GitHub access token lives. The backend is chosen with `flake auth --cred-backend`
+// and remembered in the `flake.credentials` setting.
+#[derive(Clone)]
+pub enum CredentialStore {
+    // Every label, set with `flake auth --label`, holds an independent token. Tokens are saved in
+    // the default keyring and found in any, unless a collection is named.
+    SecretService {
+        label: String,
+        collection: Option<String>,
+    },
+    Gpg { path: PathBuf, recipient: Option<String> },
+    // A plaintext file, only for environments where neither of the others work.
+    File { path: PathBuf },
+}
+
+impl CredentialStore {
+    pub fn from_config(config: &Config, config_dir: PathBuf) -> Result<CredentialStore, String> {
+        let name = config.get_string("flake.credentials")
+            .unwrap_or_else(|_| String::from("secret-service"));
+        CredentialStore::from_name(&name, config, config_dir)
+    }
+
+    pub fn from_name(name: &str,
+                     config: &Config,
+                     config_dir: PathBuf)
+                     -> Result<CredentialStore, String> {
+        match name {
+            "secret-service" => {
+                Ok(CredentialStore::SecretService {
+                    label: config.get_string("flake.credentialslabel")
+                        .unwrap_or_else(|_| String::from(DEFAULT_LABEL)),
+                    collection: config.get_string("flake.keyringcollection").ok(),
+                })
+            }
+            "gpg" => {
+                Ok(CredentialStore::Gpg {
+                    path: config_dir.join("credentials.gpg"),
+                    recipient: config.get_string("flake.gpgrecipient").ok(),
+                })
+            }
+            "file" => {
+                let path = config.get_path("flake.credentialsfile")
+                    .unwrap_or_else(|_| config_dir.join("credentials"));
+                Ok(CredentialStore::File { path })
+            }
+            _ => {
+                Err(format!("unknown credential backend `{}`, use secret-service, gpg or file",
+                            name))
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CredentialStore::SecretService { .. } => "secret-service",
+            CredentialStore::Gpg { .. } => "gpg",
+            CredentialStore::File { .. } => "file",
+        }
+    }
+
+    // Tokens saved for a host, with `flake auth --host`, are only used for the repositories
+    // on that host, like a self-hosted mirror. Every other host uses the token saved without one.
+    pub fn save(&self, token: &str, host: Option<&str>) -> Result<(), String> {
+        match *self {
+            CredentialStore::SecretService { ref label, ref collection } => {
+                save_secret_service(label, collection.as_ref(), &token_value(label, host), token)
+            }
+            CredentialStore::Gpg { ref path, ref recipient } => {
+                save_gpg(&host_path(path, host), recipient.as_ref(), token)
+            }
+            CredentialStore::File { ref path } => save_file(&host_path(path, host), token),
+        }
+    }
+
+    pub fn token(&self, host: Option<&str>) -> Result<String, String> {
+        match *self {
+            CredentialStore::SecretService { ref label, ref collection } => {
+                secret_service_token(collection.as_ref(), &token_value(label, host))
+            }
+            CredentialStore::Gpg { ref path, .. } => gpg_token(&host_path(path, host)),
+            CredentialStore::File { ref path } => file_token(&host_path(path, host)),
+        }
+    }
+
+    // The token for a host, or the default one when the host doesn't have its own.
+    pub fn token_for_host(&self, host: &str) -> Result<String, String> {
+        if self.count(Some(host)) > 0 {
+            debug!("Using the access token saved for {}", host);
+            return self.token(Some(host));
+        }
+        self.token(None)
+    }
+
+    // Saves a new token over the existing one, refusing to create a token that wasn't there.
+    pub fn replace(&self, token: &str, host: Option<&str>) -> Result<(), String> {
+        if self.count(host) == 0 {
+            return Err(String::from("There's no access token to replace, use `flake auth` to \
+                                     store one"));
+        }
+
+        self.save(token, host)?;
+        match self.count(host) {
+            1 => Ok(()),
+            count => Err(format!("Found {} access tokens after replacing the old one", count)),
+        }
+    }
+
+    // How many tokens are stored, only the secret service can ever hold more than one.
+    fn count(&self, host: Option<&str>) -> usize {
+        match *self {
+            CredentialStore::SecretService { ref label, ref collection } => {
+                let ss = match SecretService::new(EncryptionType::Dh) {
+                    Ok(ss) => ss,
+                    Err(_) => return 0,
+                };
+                let value = token_value(label, host);
+                search_secret_service(&ss, collection.as_ref(), &value, |items| items.len())
+                    .unwrap_or(0)
+            }
+            CredentialStore::Gpg { ref path, .. } |
+            CredentialStore::File { ref path } => {
+                if host_path(path, host).exists() { 1 } else { 0 }
+            }
+        }
+    }
+}
+
+// The default label keeps the attributes of old tokens, other labels get their own.
+fn token_value(label: &str, host: Option<&str>) -> String {
+    let value = if label == DEFAULT_LABEL {
+        String::from(TOKEN_VALUE)
+    } else {
+        format!("{}/{}", TOKEN_VALUE, label)
+    };
+    match host {
+        Some(host) => format!("{}@{}", value, host),
+        None => value,
+    }
+}
+
+// `credentials.gpg` keeps the token for a host in `credentials@HOST.gpg`.
+fn host_path(path: &Path, host: Option<&str>) -> PathBuf {
+    let host = match host {
+        Some(host) => host,
+        None => return PathBuf::from(path),
+    };
+
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    let name = match path.extension() {
+        Some(extension) => format!("{}@{}.{}", stem, host, extension.to_string_lossy()),
+        None => format!("{}@{}", stem, host),
+    };
+    path.with_file_name(name)
+}
+
+fn save_secret_service(label: &str,
+                       collection: Option<&String>,
+                       value: &str,
+                       token: &str)
+                       -> Result<(), String> {
+    let ss = SecretService::new(EncryptionType::Dh)
+        .map_err(|error| format!("Unable to connect with the secret service: {}", error))?;
+    let collection = match collection {
+        Some(name) => {
+            match find_collection(&ss, name)? {
+                Some(collection) => collection,
+                None => {
+                    ss.create_collection(name, "").map_err(|error| {
+                        format!("Unable to create the keyring {}: {}", name, error)
+                    })?
+                }
+            }
+        }
+        None => {
+            ss.get_default_collection()
+                .map_err(|error| format!("Unable to open the default keyring: {}", error))?
+        }
+    };
+
+    replace_token(&collection, label, value, token)
+}
+
+// The part of a keyring saving a token needs.
+trait Keyring {
+    fn remove_tokens(&self, value: &str);
+    fn create_token(&self, label: &str, value: &str, token: &str) -> Result<(), String>;
+}
+
+impl<'a> Keyring for Collection<'a> {
+    fn remove_tokens(&self, value: &str) {
+        let existing = self.search_items(vec![(TOKEN_ATTRIBUTE, value)]).unwrap_or_default();
+        for item in existing {
+            if let Err(error) = item.delete() {
+                warn!("Unable to remove an old access token: {}", error);
+            }
+        }
+    }
+
+    fn create_token(&self, label: &str, value: &str, token: &str) -> Result<(), String> {
+        self.create_item(label,
+                         vec![(TOKEN_ATTRIBUTE, value)],
+                         token.as_bytes(),
+                         true,
+                         "text/plain")
+            .map(|_| ())
+            .map_err(|error| format!("Something went wrong saving the access token :/ {}", error))
+    }
+}
+
+// `create_item` only replaces items with exactly the same attributes,
+// remove every previous token so there's only ever one in the keyring.
+fn replace_token<K: Keyring>(keyring: &K,
+                             label: &str,
+                             value: &str,
+                             token: &str)
+                             -> Result<(), String> {
+    keyring.remove_tokens(value);
+    keyring.create_token(label, value, token)
+}
+
+fn secret_service_token(collection: Option<&String>, value: &str) -> Result<String, String> {
+    let ss = SecretService::new(EncryptionType::Dh)
+        .map_err(|error| format!("Unable to connect with the secret service: {}", error))?;
+
+    search_secret_service(&ss, collection, value, most_recent_token)?
+}
+
+fn most_recent_token(items: Vec<Item>) -> Result<String, String> {
+    if items.len() > 1 {
+        warn!("Found {} GitHub access tokens in the secret service, using the most recent one. \
+               Run `flake auth` again to clean them up",
+              items.len());
+    }
+
+    // The secret service doesn't return items in any particular order,
+    // so pick the most recently modified one to always use the same token.
+    let item = items.iter()
+        .max_by_key(|item| item.get_modified().or_else(|_| item.get_created()).unwrap_or(0))
+        .ok_or_else(|| {
+            String::from("GitHub credentials are not in the store, use `flake auth` to set them \
+                          up")
+        })?;
+
+    let bytes = item.get_secret()
+        .map_err(|_| String::from("Missing access token, use `flake auth` to set it up"))?;
+    String::from_utf8(bytes)
+        .map_err(|_| String::from("The stored access token is not valid UTF-8"))
+}
+
+// Collections are named by their label, like the keyrings in Seahorse.
+fn find_collection<'a>(ss: &'a SecretService,
+                       name: &str)
+                       -> Result<Option<Collection<'a>>, String> {
+    let collections = ss.get_all_collections()
+        .map_err(|error| format!("Unable to list the keyrings: {}", error))?;
+    Ok(collections.into_iter()
+        .find(|collection| collection.get_label().map(|label| label == name).unwrap_or(false)))
+}
+
+// The items borrow the collection they're found in, so they're handed to `f` instead of returned.
+fn search_secret_service<T, F>(ss: &SecretService,
+                               collection: Option<&String>,
+                               value: &str,
+                               f: F)
+                               -> Result<T, String>
+    where F: FnOnce(Vec<Item>) -> T
+{
+    let attributes = vec![(TOKEN_ATTRIBUTE, value)];
+    match collection {
+        Some(name) => {
+            match find_collection(ss, name)? {
+                Some(collection) => Ok(f(collection.search_items(attributes).unwrap_or_default())),
+                None => Ok(f(Vec::new())),
+            }
+        }
+        None => Ok(f(ss.search_items(attributes).unwrap_or_default())),
+    }
+}
+
+fn save_gpg(path: &Path, recipient: Option<&String>, token: &str) -> Result<(), String> {
+    let mut command = Command::new("gpg");
+    command.arg("--batch").arg("--yes").arg("--encrypt");
+    match recipient {
+        Some(recipient) => command.arg("--recipient").arg(recipient),
+        None => command.arg("--default-recipient-self"),
+    };
+
+    let mut child = command.arg("--output")
+        .arg(path)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|error| format!("Unable to run gpg: {}", error))?;
+
+    if let Some(stdin) = child.stdin.as_mut() {
+        stdin.write_all(token.as_bytes())
+            .map_err(|error| format!("Unable to send the token to gpg: {}", error))?;
+    }
+
+    match child.wait() {
+        Ok(ref status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("gpg failed to encrypt the access token with {}", status)),
+        Err(error) => Err(format!("Unable to run gpg: {}", error)),
+    }
+}
+
+fn gpg_token(path: &Path) -> Result<String, String> {
+    if !path.exists() {
+        return Err(format!("{} doesn't exist, use `flake auth --cred-backend gpg` to create it",
+                           path.display()));
+    }
+
+    let output = Command::new("gpg")
+        .arg("--batch")
+        .arg("--quiet")
+        .arg("--decrypt")
+        .arg(path)
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|error| format!("Unable to run gpg: {}", error))?;
+
+    if !output.status.success() {
+        return Err(format!("gpg failed to decrypt {}", path.display()));
+    }
+
+    String::from_utf8(output.stdout)
+        .map(|token| String::from(token.trim()))
+        .map_err(|_| String::from("The stored access token is not valid UTF-8"))
+}
+
+fn save_file(path: &Path, token: &str) -> Result<(), String> {
+    let mut file = OpenOptions::new().write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|error| format!("Unable to create {}: {}", path.display(), error))?;
+
+    // `mode` only applies to new files, tighten the permissions of an existing one too.
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .and_then(|_| file.write_all(token.as_bytes()))
+        .map_err(|error| format!("Unable to write {}: {}", path.display(), error))
+}
+
+fn file_token(path: &Path) -> Result<String, String> {
+    let metadata = fs::metadata(path).map_err(|_| {
+            format!("{} doesn't exist, use `flake auth --cred-backend file` to create it",
+                    path.display())
+        })?;
+
+    let mode = metadata.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(format!("Refusing to read the access token from {}, other users can access \
+                            it. Run `chmod 600 {}` to fix it",
+                           path.display(),
+                           path.display()));
+    }
+
+    let mut token = String::new();
+    fs::File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut token))
+        .map_err(|error| format!("Unable to read {}: {}", path.display(), error))?;
+    Ok(String::from(token.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    // A keyring in memory that, unlike the secret service, never replaces an item on its own.
+    struct MemoryKeyring {
+        items: RefCell<Vec<(String, String, String)>>,
+    }
+
+    impl MemoryKeyring {
+        fn with(items: &[(&str, &str, &str)]) -> MemoryKeyring {
+            let items = items.iter()
+                .map(|item| (String::from(item.0), String::from(item.1), String::from(item.2)))
+                .collect();
+            MemoryKeyring { items: RefCell::new(items) }
+        }
+
+        fn tokens(&self, value: &str) -> Vec<String> {
+            self.items
+                .borrow()
+                .iter()
+                .filter(|item| item.1 == value)
+                .map(|item| item.2.clone())
+                .collect()
+        }
+    }
+
+    impl Keyring for MemoryKeyring {
+        fn remove_tokens(&self, value: &str) {
+            self.items.borrow_mut().retain(|item| item.1 != value);
+        }
+
+        fn create_token(&self, label: &str, value: &str, token: &str) -> Result<(), String> {
+            self.items
+                .borrow_mut()
+                .push((String::from(label), String::from(value), String::from(token)));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn saving_again_replaces_the_token() {
+        let keyring = MemoryKeyring::with(&[]);
+        let value = token_value(DEFAULT_LABEL, None);
+
+        replace_token(&keyring, DEFAULT_LABEL, &value, "first").unwrap();
+        replace_token(&keyring, DEFAULT_LABEL, &value, "second").unwrap();
+        assert_eq!(keyring.tokens(&value), vec!["second"]);
+    }
+
+    #[test]
+    fn saving_removes_duplicated_tokens() {
+        let value = token_value(DEFAULT_LABEL, None);
+        let keyring = MemoryKeyring::with(&[("GitHub token", &value, "old"),
+                                            ("flake", &value, "older"),
+                                            ("flake", &value, "oldest")]);
+
+        replace_token(&keyring, DEFAULT_LABEL, &value, "new").unwrap();
+        assert_eq!(keyring.tokens(&value), vec!["new"]);
+    }
+
+    #[test]
+    fn saving_keeps_the_tokens_of_other_labels_and_hosts() {
+        let value = token_value(DEFAULT_LABEL, None);
+        let work = token_value("work", None);
+        let mirror = token_value(DEFAULT_LABEL, Some("git.example.com"));
+        let keyring = MemoryKeyring::with(&[("work", &work, "work token"),
+                                            ("flake", &mirror, "mirror token")]);
+
+        replace_token(&keyring, DEFAULT_LABEL, &value, "new").unwrap();
+        assert_eq!(keyring.tokens(&work), vec!["work token"]);
+        assert_eq!(keyring.tokens(&mirror), vec!["mirror token"]);
+        assert_eq!(keyring.tokens(&value), vec!["new"]);
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::BTreeMap;
+
+use rustc_serialize::json::{Json, ToJson};
+
+use color;
+
+// Bump it when the JSON report changes in a way that breaks existing consumers.
+const SCHEMA_VERSION: i64 = 1;
+
+#[derive(PartialEq)]
+pub enum Status {
+    Ok,
+    Warning,
+    Error,
+}
+
+impl Status {
+    fn name(&self) -> &'static str {
+        match *self {
+            Status::Ok => "ok",
+            Status::Warning => "warning",
+            Status::Error => "error",
+        }
+    }
+}
+
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub message: String,
+    pub hint: Option<String>,
+}
+
+impl Check {
+    pub fn ok(name: &'static str, message: String) -> Check {
+        Check {
+            name,
+            status: Status::Ok,
+            message,
+            hint: None,
+        }
+    }
+
+    pub fn failed(name: &'static str, status: Status, message: String, hint: &str) -> Check {
+        Check {
+            name,
+            status,
+            message,
+            hint: Some(String::from(hint)),
+        }
+    }
+}
+
+impl ToJson for Check {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert(String::from("name"), self.name.to_json());
+        object.insert(String::from("status"), self.status.name().to_json());
+        object.insert(String::from("message"), self.message.to_json());
+        if let Some(ref hint) = self.hint {
+            object.insert(String::from("hint"), hint.to_json());
+        }
+        Json::Object(object)
+    }
+}
+
+pub fn print_text(checks: &[Check]) {
+    for check in checks {
+        println!("{} {}: {}", color::status(check.status.name()), check.name, check.message);
+        if let Some(ref hint) = check.hint {
+            println!("{:9} {}", "", hint);
+        }
+    }
+}
+
+pub fn print_json(checks: &[Check]) {
+    let mut report = BTreeMap::new();
+    report.insert(String::from("version"), SCHEMA_VERSION.to_json());
+    report.insert(String::from("checks"),
+                  Json::Array(checks.iter().map(|check| check.to_json()).collect()));
+    println!("{}", Json::Object(report).pretty());
+}
@@ -0,0 +1,42 @@
+use std::time::Duration;
+
+// Parses durations like `90`, `30s`, `15m`, `2h`, `1d` or `1w`. Plain numbers are seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let number = number.parse::<u64>().map_err(|_| {
+            format!("invalid duration `{}`, use a number followed by s, m, h, d or w",
+                    value)
+        })?;
+
+    let multiplier = match unit.trim() {
+        "" | "s" | "sec" | "secs" | "seconds" => 1,
+        "m" | "min" | "mins" | "minutes" => 60,
+        "h" | "hour" | "hours" => 60 * 60,
+        "d" | "day" | "days" => 24 * 60 * 60,
+        "w" | "week" | "weeks" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("invalid duration unit in `{}`, use s, m, h, d or w", value)),
+    };
+
+    Ok(Duration::from_secs(number * multiplier))
+}
+
+pub fn humanize(seconds: u64) -> String {
+    let (value, unit) = if seconds < 60 {
+        return String::from("less than a minute");
+    } else if seconds < 60 * 60 {
+        (seconds / 60, "minute")
+    } else if seconds < 24 * 60 * 60 {
+        (seconds / (60 * 60), "hour")
+    } else {
+        (seconds / (24 * 60 * 60), "day")
+    };
+
+    if value == 1 {
+        format!("1 {}", unit)
+    } else {
+        format!("{} {}s", value, unit)
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::io;
+
+use git2::{ErrorClass, ErrorCode};
+use rustc_serialize::json::{Json, ToJson};
+
+#[derive(Debug)]
+pub enum FlakeError {
+    Config(String),
+    Auth(String),
+    Network(String),
+    Conflict(String),
+    Drift(String),
+    // `flake tracked` answered no, which isn't a failure but scripts need to tell it apart.
+    NotTracked(String),
+    Git(String),
+    Io(String),
+}
+
+impl FlakeError {
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            FlakeError::Git(_) | FlakeError::Io(_) => 1,
+            FlakeError::Config(_) => 2,
+            FlakeError::Auth(_) => 3,
+            FlakeError::Network(_) => 4,
+            FlakeError::Conflict(_) => 5,
+            FlakeError::Drift(_) => 6,
+            FlakeError::NotTracked(_) => 7,
+        }
+    }
+
+    // The stable name of the variant, for `--error-format json`.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            FlakeError::Config(_) => "config",
+            FlakeError::Auth(_) => "auth",
+            FlakeError::Network(_) => "network",
+            FlakeError::Conflict(_) => "conflict",
+            FlakeError::Drift(_) => "drift",
+            FlakeError::NotTracked(_) => "not-tracked",
+            FlakeError::Git(_) => "git",
+            FlakeError::Io(_) => "io",
+        }
+    }
+
+    pub fn hint(&self) -> Option<&'static str> {
+        match *self {
+            FlakeError::Config(_) => Some("Run `flake whoami` to see the settings flake uses"),
+            FlakeError::Auth(_) => Some("Run `flake auth --test` to check your credentials"),
+            FlakeError::Network(_) => {
+                Some("Check your connection, or pass `--timeout` to wait longer")
+            }
+            FlakeError::Conflict(_) => {
+                Some("Resolve it with git in the local repository, then run `flake sync` again")
+            }
+            FlakeError::Drift(_) => {
+                Some("Run `flake sync` to capture the changes or `flake apply` to undo them")
+            }
+            FlakeError::Git(_) => Some("Run `flake verify` to check the local repository"),
+            FlakeError::NotTracked(_) => Some("Run `flake add` to start tracking it"),
+            FlakeError::Io(_) => None,
+        }
+    }
+
+    pub fn context(self, context: &str) -> FlakeError {
+        match self {
+            FlakeError::Config(m) => FlakeError::Config(format!("{}: {}", context, m)),
+            FlakeError::Auth(m) => FlakeError::Auth(format!("{}: {}", context, m)),
+            FlakeError::Network(m) => FlakeError::Network(format!("{}: {}", context, m)),
+            FlakeError::Conflict(m) => FlakeError::Conflict(format!("{}: {}", context, m)),
+            FlakeError::Drift(m) => FlakeError::Drift(format!("{}: {}", context, m)),
+            FlakeError::NotTracked(m) => FlakeError::NotTracked(format!("{}: {}", context, m)),
+            FlakeError::Git(m) => FlakeError::Git(format!("{}: {}", context, m)),
+            FlakeError::Io(m) => FlakeError::Io(format!("{}: {}", context, m)),
+        }
+    }
+
+    fn message(&self) -> &str {
+        match *self {
+            FlakeError::Config(ref m) |
+            FlakeError::Auth(ref m) |
+            FlakeError::Network(ref m) |
+            FlakeError::Conflict(ref m) |
+            FlakeError::Drift(ref m) |
+            FlakeError::NotTracked(ref m) |
+            FlakeError::Git(ref m) |
+            FlakeError::Io(ref m) => m,
+        }
+    }
+}
+
+impl fmt::Display for FlakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl ToJson for FlakeError {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert(String::from("code"), self.exit_code().to_json());
+        object.insert(String::from("kind"), self.kind().to_json());
+        object.insert(String::from("message"), self.message().to_json());
+        if let Some(hint) = self.hint() {
+            object.insert(String::from("hint"), hint.to_json());
+        }
+        Json::Object(object)
+    }
+}
+
+impl error::Error for FlakeError {
+    fn description(&self) -> &str {
+        self.message()
+    }
+}
+
+impl From<::git2::Error> for FlakeError {
+    fn from(error: ::git2::Error) -> FlakeError {
+        let message = String::from(error.message());
+
+        match (error.code(), error.class()) {
+            (ErrorCode::Auth, _) |
+            (ErrorCode::Certificate, _) => FlakeError::Auth(message),
+            (ErrorCode::NotFastForward, _) |
+            (ErrorCode::Conflict, _) |
+            (ErrorCode::Unmerged, _) |
+            (_, ErrorClass::Merge) => FlakeError::Conflict(message),
+            (_, ErrorClass::Net) |
+            (_, ErrorClass::Ssl) |
+            (_, ErrorClass::Ssh) => FlakeError::Network(message),
+            _ => FlakeError::Git(message),
+        }
+    }
+}
+
+impl From<io::Error> for FlakeError {
+    fn from(error: io::Error) -> FlakeError {
+        FlakeError::Io(error.to_string())
+    }
+}
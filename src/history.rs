@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+
+use git2::{Error, Oid, Repository};
+
+// Squashes every commit older than the cutoff, or the whole history without one, into a
+// single root commit and replays the newer commits on top of it. The trees don't change,
+// only the history does. Returns the new head and how many commits were squashed.
+//
+// Replayed commits keep their author and committer. The root commit is committed by the
+// current user, unless `keep_identity` keeps the identity of the newest squashed commit.
+pub fn squash(repo: &Repository,
+              cutoff: Option<i64>,
+              keep_identity: bool)
+              -> Result<Option<(Oid, usize)>, Error> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(::git2::SORT_TOPOLOGICAL | ::git2::SORT_REVERSE);
+    revwalk.push_head()?;
+
+    let mut commits = Vec::new();
+    for oid in revwalk {
+        commits.push(repo.find_commit(oid?)?);
+    }
+
+    let old = match cutoff {
+        Some(cutoff) => commits.iter().take_while(|c| c.time().seconds() < cutoff).count(),
+        None => commits.len(),
+    };
+    if old < 2 {
+        return Ok(None);
+    }
+
+    let last_old = &commits[old - 1];
+    let signature = if keep_identity {
+        last_old.committer().to_owned()
+    } else {
+        repo.signature()?
+    };
+    let message = match cutoff {
+        Some(_) => format!("Squash {} commits of dotfiles history", old),
+        None => String::from("Squash dotfiles history"),
+    };
+    let root = repo.commit(None,
+                           &last_old.author(),
+                           &signature,
+                           &message,
+                           &last_old.tree()?,
+                           &[])?;
+
+    // Parents older than the cutoff, including side branches, all become the new root.
+    let mut rewritten = HashMap::new();
+    let mut head = root;
+    for commit in &commits[old..] {
+        let mut parents = Vec::new();
+        for parent in commit.parent_ids() {
+            let parent = rewritten.get(&parent).cloned().unwrap_or(root);
+            if !parents.contains(&parent) {
+                parents.push(parent);
+            }
+        }
+
+        let parents = parents.into_iter()
+            .map(|oid| repo.find_commit(oid))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let parents = parents.iter().collect::<Vec<_>>();
+
+        head = repo.commit(None,
+                           &commit.author(),
+                           &commit.committer(),
+                           commit.message().unwrap_or(""),
+                           &commit.tree()?,
+                           &parents)?;
+        rewritten.insert(commit.id(), head);
+    }
+
+    Ok(Some((head, old)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+
+    use git2::{Commit, Signature, Time};
+
+    use super::*;
+    use testing::{init_repo, TempDir};
+
+    // Commits a change to .vimrc as `name`, at `seconds`.
+    fn commit_as(repo: &Repository, name: &str, seconds: i64) -> Oid {
+        let path = repo.workdir().unwrap().join(".vimrc");
+        File::create(path).and_then(|mut f| f.write_all(name.as_bytes())).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(".vimrc")).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+
+        let signature = Signature::new(name, "dotfiles@example.com", &Time::new(seconds, 0))
+            .unwrap();
+        let parent = repo.head().ok().and_then(|head| head.target()).map(|oid| {
+            repo.find_commit(oid).unwrap()
+        });
+        let parents = parent.iter().collect::<Vec<_>>();
+        repo.commit(Some("HEAD"), &signature, &signature, name, &tree, &parents).unwrap()
+    }
+
+    fn names(commit: &Commit) -> (String, String) {
+        (String::from(commit.author().name().unwrap()),
+         String::from(commit.committer().name().unwrap()))
+    }
+
+    #[test]
+    fn the_root_is_committed_by_the_current_user() {
+        let dir = TempDir::new("squash-current");
+        let repo = init_repo(dir.path());
+        commit_as(&repo, "Laptop", 100);
+        let last = commit_as(&repo, "Desktop", 200);
+
+        let (head, squashed) = squash(&repo, None, false).unwrap().unwrap();
+        assert_eq!(squashed, 2);
+        let root = repo.find_commit(head).unwrap();
+        assert_eq!(root.parent_ids().count(), 0);
+        assert_eq!(names(&root), (String::from("Desktop"), String::from("Flake Test")));
+        assert_eq!(root.tree_id(), repo.find_commit(last).unwrap().tree_id());
+    }
+
+    #[test]
+    fn the_root_can_keep_the_original_identity() {
+        let dir = TempDir::new("squash-keep-identity");
+        let repo = init_repo(dir.path());
+        commit_as(&repo, "Laptop", 100);
+        commit_as(&repo, "Desktop", 200);
+
+        let (head, _) = squash(&repo, None, true).unwrap().unwrap();
+        let root = repo.find_commit(head).unwrap();
+        assert_eq!(names(&root), (String::from("Desktop"), String::from("Desktop")));
+        assert_eq!(root.committer().when().seconds(), 200);
+    }
+
+    #[test]
+    fn newer_commits_are_replayed_unchanged() {
+        let dir = TempDir::new("squash-cutoff");
+        let repo = init_repo(dir.path());
+        commit_as(&repo, "Laptop", 100);
+        commit_as(&repo, "Desktop", 200);
+        commit_as(&repo, "Server", 300);
+        let last = commit_as(&repo, "Phone", 400);
+
+        let (head, squashed) = squash(&repo, Some(250), true).unwrap().unwrap();
+        assert_eq!(squashed, 2);
+
+        let phone = repo.find_commit(head).unwrap();
+        assert_eq!(names(&phone), (String::from("Phone"), String::from("Phone")));
+        assert_eq!(phone.tree_id(), repo.find_commit(last).unwrap().tree_id());
+
+        let server = phone.parent(0).unwrap();
+        assert_eq!(names(&server), (String::from("Server"), String::from("Server")));
+        let root = server.parent(0).unwrap();
+        assert_eq!(root.parent_ids().count(), 0);
+        assert_eq!(root.message(), Some("Squash 2 commits of dotfiles history"));
+        assert_eq!(names(&root), (String::from("Desktop"), String::from("Desktop")));
+    }
+
+    #[test]
+    fn a_single_old_commit_is_not_squashed() {
+        let dir = TempDir::new("squash-nothing");
+        let repo = init_repo(dir.path());
+        commit_as(&repo, "Laptop", 100);
+        commit_as(&repo, "Desktop", 200);
+
+        assert!(squash(&repo, Some(150), false).unwrap().is_none());
+    }
+}
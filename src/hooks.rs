@@ -0,0 +1,138 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use git2::{Config, Oid};
+
+// Commands users run on flake's events with `git config --add flake.hook.EVENT COMMAND`.
+// They run with the store as their working directory and always get the same variables:
+//
+// FLAKE_STORE    the path of the store
+// FLAKE_EVENT    the event name, like post-commit
+// FLAKE_CHANGED  the changed paths, relative to the home directory, one per line
+// FLAKE_COMMIT   the commit the store is at, empty if it has none
+pub const POST_APPLY: &str = "post-apply";
+pub const POST_COMMIT: &str = "post-commit";
+pub const POST_SYNC: &str = "post-sync";
+
+// The repository's own setup script, like one that installs packages. `sync --bootstrap` runs it
+// on the first sync of a new machine, with the same working directory and FLAKE_STORE as a hook.
+pub const BOOTSTRAP_SCRIPT: &str = ".flake/bootstrap";
+
+const EVENTS: &[&str] = &[POST_APPLY, POST_COMMIT, POST_SYNC];
+
+#[derive(Clone)]
+pub struct Hooks {
+    hooks: Vec<(String, String)>,
+}
+
+impl Hooks {
+    pub fn load(config: &Config) -> Hooks {
+        let mut hooks = Vec::new();
+
+        let entries = match config.entries(Some("flake\\.hook\\.")) {
+            Ok(entries) => entries,
+            Err(_) => return Hooks { hooks },
+        };
+
+        for entry in &entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            let (name, command) = match (entry.name(), entry.value()) {
+                (Some(name), Some(command)) => (name, command),
+                _ => continue,
+            };
+
+            let event = name.replacen("flake.hook.", "", 1);
+            if EVENTS.contains(&event.as_str()) {
+                hooks.push((event, String::from(command)));
+            } else {
+                warn!("Ignoring hook for unknown event `{}`, use {}", event, EVENTS.join(", "));
+            }
+        }
+
+        Hooks { hooks }
+    }
+
+    pub fn run(&self, event: &str, store: &Path, changed: &[PathBuf], commit: Option<Oid>) {
+        let changed = changed.iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let commit = commit.map(|oid| oid.to_string()).unwrap_or_default();
+
+        for command in self.hooks.iter().filter(|hook| hook.0 == event).map(|hook| &hook.1) {
+            debug!("Running {} hook `{}`", event, command);
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .current_dir(store)
+                .env("FLAKE_STORE", store)
+                .env("FLAKE_EVENT", event)
+                .env("FLAKE_CHANGED", &changed)
+                .env("FLAKE_COMMIT", &commit)
+                .status();
+            match status {
+                Ok(ref status) if status.success() => {}
+                Ok(status) => warn!("The {} hook `{}` failed with {}", event, command, status),
+                Err(error) => warn!("Unable to run the {} hook `{}`: {}", event, command, error),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Read;
+
+    use super::*;
+    use testing::TempDir;
+
+    fn hooks(dir: &TempDir, hooks: &[(&str, &str)]) -> Hooks {
+        let mut config = Config::open(&dir.write("config", "")).unwrap();
+        for &(event, command) in hooks {
+            config.set_multivar(&format!("flake.hook.{}", event), "^$", command).unwrap();
+        }
+        Hooks::load(&config)
+    }
+
+    fn read(path: &Path) -> String {
+        let mut content = String::new();
+        File::open(path).unwrap().read_to_string(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn hooks_get_the_store_event_changed_paths_and_commit() {
+        let dir = TempDir::new("hooks-env");
+        dir.write("hook.sh",
+                  "printf '%s|%s|%s|%s' \"$FLAKE_STORE\" \"$FLAKE_EVENT\" \"$FLAKE_CHANGED\" \
+                   \"$FLAKE_COMMIT\" > env.out");
+        let hooks = hooks(&dir, &[(POST_COMMIT, "sh hook.sh")]);
+        let commit = Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+
+        hooks.run(POST_COMMIT,
+                  dir.path(),
+                  &[PathBuf::from(".vimrc"), PathBuf::from(".config/fish/config.fish")],
+                  Some(commit));
+        assert_eq!(read(&dir.path().join("env.out")),
+                   format!("{}|post-commit|.vimrc\n.config/fish/config.fish|{}",
+                           dir.path().display(),
+                           commit));
+    }
+
+    #[test]
+    fn hooks_only_run_for_their_event() {
+        let dir = TempDir::new("hooks-events");
+        let hooks = hooks(&dir,
+                          &[(POST_APPLY, "echo $FLAKE_EVENT >> events.out"),
+                            (POST_SYNC, "echo $FLAKE_EVENT >> events.out"),
+                            ("pre-sync", "echo unknown >> events.out")]);
+
+        hooks.run(POST_SYNC, dir.path(), &[], None);
+        hooks.run(POST_COMMIT, dir.path(), &[], None);
+        assert_eq!(read(&dir.path().join("events.out")), "post-sync\n");
+    }
+}
@@ -0,0 +1,150 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use glob::Pattern;
+
+use hooks::BOOTSTRAP_SCRIPT;
+use layout::LAYOUT_FILE;
+use xattrs::MANIFEST_FILE;
+
+pub const IGNORE_FILE: &str = ".flakeignore";
+pub const KEEP_FILE: &str = ".flake-keep";
+
+pub struct Ignore {
+    patterns: Vec<Pattern>,
+}
+
+impl Ignore {
+    pub fn load(workdir: &Path) -> Ignore {
+        Ignore { patterns: load_patterns(workdir, IGNORE_FILE) }
+    }
+
+    // The pattern lists, the attributes manifest and the bootstrap script only make sense inside
+    // the repository.
+    pub fn is_ignored(&self, name: &Path) -> bool {
+        if name == Path::new(IGNORE_FILE) || name == Path::new(KEEP_FILE) ||
+           name == Path::new(MANIFEST_FILE) || name == Path::new(LAYOUT_FILE) ||
+           name == Path::new(BOOTSTRAP_SCRIPT) {
+            return true;
+        }
+        matches_any(&self.patterns, name)
+    }
+}
+
+// Tracked files that are never deleted from the repository when they're missing from the home
+// directory, like files that only exist in some machines.
+pub struct Keep {
+    patterns: Vec<Pattern>,
+}
+
+impl Keep {
+    pub fn load(workdir: &Path) -> Keep {
+        Keep { patterns: load_patterns(workdir, KEEP_FILE) }
+    }
+
+    pub fn is_kept(&self, name: &Path) -> bool {
+        matches_any(&self.patterns, name)
+    }
+}
+
+fn load_patterns(workdir: &Path, list: &str) -> Vec<Pattern> {
+    let mut patterns = Vec::new();
+    let mut content = String::new();
+
+    if let Ok(mut file) = File::open(workdir.join(list)) {
+        if let Err(error) = file.read_to_string(&mut content) {
+            warn!("Unable to read {}: {}", list, error);
+        }
+    }
+
+    for line in content.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match Pattern::new(line.trim_matches('/')) {
+            Ok(pattern) => patterns.push(pattern),
+            Err(error) => warn!("Invalid pattern in {} `{}`: {}", list, line, error),
+        }
+    }
+
+    patterns
+}
+
+// Patterns without slashes match the file name at any depth, like in .gitignore.
+fn matches_any(patterns: &[Pattern], name: &Path) -> bool {
+    let file_name = match name.file_name() {
+        Some(f) => Path::new(f),
+        None => return false,
+    };
+
+    patterns.iter().any(|pattern| {
+        pattern.matches_path(name) ||
+        (!pattern.as_str().contains('/') && pattern.matches_path(file_name))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use glob::Pattern;
+
+    use super::*;
+    use testing::TempDir;
+
+    fn patterns(list: &[&str]) -> Vec<Pattern> {
+        list.iter().map(|pattern| Pattern::new(pattern).unwrap()).collect()
+    }
+
+    #[test]
+    fn patterns_without_slashes_match_at_any_depth() {
+        let patterns = patterns(&["*.swp", ".cache"]);
+        assert!(matches_any(&patterns, Path::new(".vimrc.swp")));
+        assert!(matches_any(&patterns, Path::new(".config/nvim/init.vim.swp")));
+        assert!(matches_any(&patterns, Path::new("projects/.cache")));
+        assert!(!matches_any(&patterns, Path::new(".vimrc")));
+    }
+
+    #[test]
+    fn patterns_with_slashes_match_the_whole_path() {
+        let patterns = patterns(&[".config/*/history"]);
+        assert!(matches_any(&patterns, Path::new(".config/fish/history")));
+        assert!(!matches_any(&patterns, Path::new("history")));
+        assert!(!matches_any(&patterns, Path::new(".local/fish/history")));
+    }
+
+    #[test]
+    fn ignore_file_skips_comments_and_slashes() {
+        let dir = TempDir::new("ignore");
+        dir.write(IGNORE_FILE, "# caches\n\n/.cache/\n*.log\n");
+        let ignore = Ignore::load(dir.path());
+
+        assert!(ignore.is_ignored(Path::new(".cache")));
+        assert!(ignore.is_ignored(Path::new(".config/app/debug.log")));
+        assert!(!ignore.is_ignored(Path::new(".bashrc")));
+    }
+
+    #[test]
+    fn flake_files_are_always_ignored() {
+        let dir = TempDir::new("ignore-builtin");
+        let ignore = Ignore::load(dir.path());
+
+        for name in &[IGNORE_FILE, KEEP_FILE, MANIFEST_FILE, LAYOUT_FILE, BOOTSTRAP_SCRIPT] {
+            assert!(ignore.is_ignored(Path::new(name)), "{} isn't ignored", name);
+        }
+        assert!(!ignore.is_ignored(Path::new(".flake/other")));
+    }
+
+    #[test]
+    fn keep_list_matches_like_the_ignore_list() {
+        let dir = TempDir::new("keep");
+        dir.write(KEEP_FILE, ".ssh/config\n*.local\n");
+        let keep = Keep::load(dir.path());
+
+        assert!(keep.is_kept(Path::new(".ssh/config")));
+        assert!(keep.is_kept(Path::new(".config/git/config.local")));
+        assert!(!keep.is_kept(Path::new(".ssh/known_hosts")));
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use git2::{Oid, Repository};
+
+const JOURNAL_FILE: &str = "flake-apply-journal";
+
+// The files an `apply` already wrote, next to the state file. The first line is the commit being
+// applied and every file is appended once it's written, so a process that gets killed only loses
+// the file it was writing. A successful apply removes it.
+pub struct Journal {
+    path: PathBuf,
+    commit: Option<String>,
+    applied: BTreeSet<PathBuf>,
+}
+
+impl Journal {
+    pub fn load(repo: &Repository) -> Journal {
+        let mut journal = Journal {
+            path: repo.path().join(JOURNAL_FILE),
+            commit: None,
+            applied: BTreeSet::new(),
+        };
+
+        let mut content = String::new();
+        if File::open(&journal.path).and_then(|mut f| f.read_to_string(&mut content)).is_err() {
+            return journal;
+        }
+        let mut lines = content.lines();
+        journal.commit = lines.next().map(String::from);
+        journal.applied = lines.filter(|line| !line.is_empty()).map(PathBuf::from).collect();
+        journal
+    }
+
+    // How many files an interrupted apply of the same commit already wrote. A journal of another
+    // commit is stale, those files are going to be replaced anyway.
+    pub fn interrupted(&self, commit: Oid) -> Option<usize> {
+        match self.commit {
+            Some(ref journaled) if *journaled == commit.to_string() && !self.applied.is_empty() => {
+                Some(self.applied.len())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn contains(&self, name: &Path) -> bool {
+        self.applied.contains(name)
+    }
+
+    // Keeps the files already written when resuming, otherwise starts over.
+    pub fn start(&mut self, commit: Oid, resume: bool) -> Result<(), io::Error> {
+        if resume {
+            return Ok(());
+        }
+        self.commit = Some(commit.to_string());
+        self.applied.clear();
+
+        let mut file = File::create(&self.path)?;
+        writeln!(file, "{}", commit)
+    }
+
+    pub fn record(&mut self, name: &Path) -> Result<(), io::Error> {
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        writeln!(file, "{}", name.display())?;
+        self.applied.insert(PathBuf::from(name));
+        Ok(())
+    }
+
+    pub fn clear(&self) -> Result<(), io::Error> {
+        match fs::remove_file(&self.path) {
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::{commit_all, init_repo, TempDir};
+
+    #[test]
+    fn an_interrupted_apply_can_resume() {
+        let store = TempDir::new("journal-resume");
+        store.write(".vimrc", "set number");
+        let repo = init_repo(store.path());
+        let commit = commit_all(&repo, "Add vimrc");
+
+        {
+            let mut journal = Journal::load(&repo);
+            assert_eq!(journal.interrupted(commit), None);
+            journal.start(commit, false).unwrap();
+            journal.record(Path::new(".vimrc")).unwrap();
+            journal.record(Path::new(".config/nvim/init.vim")).unwrap();
+            // Killed before the apply finished, nothing cleared the journal.
+        }
+
+        let mut journal = Journal::load(&repo);
+        assert_eq!(journal.interrupted(commit), Some(2));
+        assert!(journal.contains(Path::new(".vimrc")));
+        assert!(journal.contains(Path::new(".config/nvim/init.vim")));
+        assert!(!journal.contains(Path::new(".bashrc")));
+
+        journal.start(commit, true).unwrap();
+        journal.record(Path::new(".bashrc")).unwrap();
+        assert_eq!(Journal::load(&repo).interrupted(commit), Some(3));
+
+        journal.clear().unwrap();
+        assert_eq!(Journal::load(&repo).interrupted(commit), None);
+        journal.clear().unwrap();
+    }
+
+    #[test]
+    fn a_journal_of_another_commit_is_stale() {
+        let store = TempDir::new("journal-stale");
+        store.write(".vimrc", "set number");
+        let repo = init_repo(store.path());
+        let old = commit_all(&repo, "Add vimrc");
+        store.write(".vimrc", "set nonumber");
+        let new = commit_all(&repo, "Update vimrc");
+
+        let mut journal = Journal::load(&repo);
+        journal.start(old, false).unwrap();
+        journal.record(Path::new(".vimrc")).unwrap();
+
+        let mut journal = Journal::load(&repo);
+        assert_eq!(journal.interrupted(new), None);
+        journal.start(new, false).unwrap();
+        assert!(!journal.contains(Path::new(".vimrc")));
+        assert_eq!(Journal::load(&repo).interrupted(new), None);
+    }
+}
@@ -0,0 +1,142 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+pub const LAYOUT_FILE: &str = ".flake-layout";
+
+pub const MIRROR: &str = "mirror";
+pub const SUBDIR: &str = "subdir";
+
+// How the files in the repository map to the home directory. The default layout mirrors it,
+// `.vimrc` is `.vimrc` in the repository. The subdir layout groups each file in a package
+// directory, stow-like, so `.vimrc` is `vim/.vimrc` and `.config/nvim/init.vim` is
+// `nvim/.config/nvim/init.vim`. The layout is recorded at the root of the repository, so every
+// machine reads it the same way.
+pub struct Layout {
+    home: PathBuf,
+    workdir: PathBuf,
+    subdir: bool,
+}
+
+impl Layout {
+    pub fn load(workdir: &Path) -> Layout {
+        Layout::with_home(workdir, &env::home_dir().unwrap())
+    }
+
+    // The layout of the repository for another home directory than the current user's.
+    pub fn with_home(workdir: &Path, home: &Path) -> Layout {
+        let mut format = String::new();
+        let path = workdir.join(LAYOUT_FILE);
+        let _ = File::open(path).and_then(|mut f| f.read_to_string(&mut format));
+
+        Layout {
+            home: PathBuf::from(home),
+            workdir: PathBuf::from(workdir),
+            subdir: format.trim() == SUBDIR,
+        }
+    }
+
+    // Files at the root of a subdir repository, like its README, belong to no package.
+    pub fn is_home_file(&self, name: &Path) -> bool {
+        !self.subdir || name.components().count() > 1
+    }
+
+    // The path in the home directory, relative to it, of a file in the repository.
+    pub fn home_name(&self, name: &Path) -> PathBuf {
+        if !self.subdir {
+            return PathBuf::from(name);
+        }
+        name.components().skip(1).collect()
+    }
+
+    pub fn home_path(&self, name: &Path) -> PathBuf {
+        self.home.join(self.home_name(name))
+    }
+
+    // The path in the repository of a file in the home directory. Files that are already in a
+    // package stay there, new ones go to the package named after them.
+    pub fn store_name(&self, name: &Path) -> PathBuf {
+        if !self.subdir {
+            return PathBuf::from(name);
+        }
+
+        if let Ok(entries) = fs::read_dir(&self.workdir) {
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                let package = entry.file_name();
+                if package != ".git" && entry.path().join(name).exists() {
+                    return Path::new(&package).join(name);
+                }
+            }
+        }
+        Path::new(&package_name(name)).join(name)
+    }
+
+    // Everywhere a path of the home directory can be in the repository, which in the subdir
+    // layout is inside any of the packages.
+    pub fn store_names(&self, name: &Path) -> Vec<PathBuf> {
+        if !self.subdir {
+            return vec![PathBuf::from(name)];
+        }
+
+        let mut names = Vec::new();
+        if let Ok(entries) = fs::read_dir(&self.workdir) {
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                let package = entry.file_name();
+                if package != ".git" && entry.path().is_dir() {
+                    names.push(Path::new(&package).join(name));
+                }
+            }
+        }
+        names
+    }
+}
+
+// Records the layout of a new repository. Existing repositories keep theirs, moving every file
+// around would break the other machines.
+pub fn init(workdir: &Path, format: &str, empty: bool) -> Result<(), String> {
+    let path = workdir.join(LAYOUT_FILE);
+    let current = if Layout::load(workdir).subdir { SUBDIR } else { MIRROR };
+    if current == format {
+        return Ok(());
+    }
+    if !empty {
+        return Err(format!("the repository already uses the {} layout, it can only be chosen \
+                            for a new repository",
+                           current));
+    }
+
+    File::create(path)
+        .and_then(|mut file| writeln!(file, "{}", format))
+        .map_err(|error| format!("unable to save the layout: {}", error))
+}
+
+// The first directory, or `.config`'s child, without the leading dot and the usual suffixes:
+// `.vimrc` goes to `vim`, `.tmux.conf` to `tmux` and `.config/nvim/init.vim` to `nvim`.
+fn package_name(name: &Path) -> String {
+    let mut components = name.components().filter_map(|component| match component {
+        Component::Normal(part) => Some(part.to_string_lossy().into_owned()),
+        _ => None,
+    });
+    let first = components.next().unwrap_or_default();
+    let top = if first == ".config" {
+        match components.next() {
+            Some(ref second) if name.components().count() > 2 => second.clone(),
+            _ => first,
+        }
+    } else {
+        first
+    };
+
+    let stem = top.split('.').find(|part| !part.is_empty()).unwrap_or("");
+    let package = if stem.len() > 2 && stem.ends_with("rc") {
+        &stem[..stem.len() - 2]
+    } else {
+        stem
+    };
+    if package.is_empty() {
+        String::from("misc")
+    } else {
+        String::from(package)
+    }
+}
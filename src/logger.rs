@@ -0,0 +1,59 @@
+use std::io;
+use std::io::Write;
+
+use log::{Log, LogLevel, LogLevelFilter, LogMetadata, LogRecord};
+
+// The target for the messages about every file flake syncs, only shown with `--verbose-files`.
+pub const FILES: &str = "flake::files";
+
+// Errors go to stderr so they still surface when the rest of the output is silenced.
+struct Logger {
+    level: LogLevelFilter,
+    verbose: bool,
+    files: bool,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        if metadata.level() > self.level {
+            return false;
+        }
+        if metadata.target() == FILES {
+            return self.files;
+        }
+        metadata.level() <= LogLevel::Info || self.verbose
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        match record.level() {
+            LogLevel::Error => {
+                let _ = writeln!(io::stderr(), "{}", record.args());
+            }
+            LogLevel::Warn => println!("[WARNING] {}", record.args()),
+            _ => println!("{}", record.args()),
+        }
+    }
+}
+
+pub fn init(quiet: bool, verbose: bool, files: bool) {
+    let level = if quiet {
+        LogLevelFilter::Error
+    } else if verbose || files {
+        LogLevelFilter::Debug
+    } else {
+        LogLevelFilter::Info
+    };
+
+    let _ = ::log::set_logger(|max_level| {
+        max_level.set(level);
+        Box::new(Logger {
+            level,
+            verbose,
+            files,
+        })
+    });
+}
@@ -1,296 +1,5322 @@
 #[macro_use]
 extern crate clap;
 extern crate git2;
+extern crate glob;
+extern crate libc;
+#[macro_use]
+extern crate log;
+extern crate notify;
+extern crate rustc_serialize;
 extern crate secret_service;
 extern crate schedule_recv;
+extern crate time;
 extern crate walkdir;
 
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::mem;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::fs::{symlink, FileTypeExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
 use std::process;
-use std::time::Duration;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::{App, Arg, ArgMatches, Shell, SubCommand, Values};
 
-use clap::{App, Arg, ArgMatches, SubCommand};
+use git2::{BranchType, Error, FetchOptions, Index, Oid, ProxyOptions, PushOptions, Repository,
+           RemoteCallbacks, Status, StatusEntry, StatusOptions, Statuses};
+use git2::build::{CheckoutBuilder, RepoBuilder};
 
-use git2::{Error, FetchOptions, PushOptions, Repository, RemoteCallbacks};
-use git2::build::RepoBuilder;
+use glob::Pattern;
 
-use secret_service::SecretService;
-use secret_service::EncryptionType;
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+
+use rustc_serialize::json::{Json, ToJson};
 
 use walkdir::{DirEntry, WalkDir, WalkDirIterator};
 
+use credentials::CredentialStore;
+use doctor::Check;
+use duration::{humanize, parse_duration};
+use error::FlakeError;
+use hooks::Hooks;
+use ignore::{Ignore, Keep};
+use journal::Journal;
+use layout::Layout;
+use merge::Strategy;
+use permissions::Permissions;
+use reload::Reload;
+use report::Report;
+use state::{Lock, State};
+use stats::Stats;
+
+mod color;
+mod credentials;
+mod doctor;
+mod duration;
+mod error;
+mod history;
+mod hooks;
+mod ignore;
+mod journal;
+mod layout;
+mod logger;
+mod merge;
+mod permissions;
+mod reload;
+mod report;
+mod signatures;
+mod ssh;
+mod state;
+mod stats;
+mod template;
+#[cfg(test)]
+mod testing;
+mod verify;
+mod xattrs;
+
 const STORE_NAME: &'static str = ".snowflakes";
+const DEFAULT_BRANCH: &str = "master";
+const DEFAULT_MAX_FILES: usize = 100;
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const ONLINE_CHECK_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_MAX_COMMITS: usize = 50;
+const DEFAULT_JOBS: usize = 4;
+const DEFAULT_GC_AGE: &str = "30d";
+const DEFAULT_WATCH_DEBOUNCE: &str = "2s";
+// Bump it when the `sync --dump-plan` output changes in a way that breaks existing consumers.
+const PLAN_SCHEMA_VERSION: i64 = 1;
 
-fn main() {
-    let matches = App::new("flake")
+// Editor swap and backup files, written next to the files being edited.
+const WATCH_JUNK: &[&str] = &["*.swp", "*.swx", "*~", ".#*", "#*#", "4913"];
+
+#[derive(Clone)]
+struct SyncOptions {
+    max_files: usize,
+    max_bytes: u64,
+    assume_yes: bool,
+    per_file: bool,
+    trailers: Vec<String>,
+    // The remote branch that's fetched and pushed to.
+    branch: Option<String>,
+    // The branch of the store the changes are committed to, the one HEAD is on when it's not set.
+    local_branch: Option<String>,
+    timeout: Duration,
+    prune: bool,
+    strategy: Strategy,
+    mirrors: Vec<String>,
+    // How many mirrors are pushed at the same time.
+    jobs: usize,
+    proxy: Option<String>,
+    ff_only: bool,
+    mtime_dates: bool,
+    only_if_online: bool,
+    dotfiles_only: bool,
+    group_by_dir: bool,
+    include_vcs: bool,
+    max_commits: usize,
+    hooks: Hooks,
+    no_commit: bool,
+    stage: bool,
+    commit_empty: bool,
+    // Leave files whose content didn't change out of the commits, even if their mode did.
+    ignore_mode_changes: bool,
+    preserve_xattrs: bool,
+    report: Option<Report>,
+    // Only capture the files already in the repository's index, new ones come from `flake add`.
+    tracked_only: bool,
+    // The profile whose state and lock this sync uses, None for the default one.
+    profile: Option<String>,
+    // Only capture the files in these paths of the home directory, from `flake.root`.
+    roots: Vec<PathBuf>,
+    // Run the repository's bootstrap script on the first sync.
+    bootstrap: bool,
+    // Refuse the remote commits that aren't signed by one of these keys.
+    verify_signatures: bool,
+    trusted_keys: Vec<String>,
+    // Skip the files whose size and modification time say they didn't change, without reading
+    // them. Restores that reset the modification times need a full comparison.
+    trust_mtime: bool,
+}
+
+// The command line definition, shared by the parser and the completion scripts.
+fn app() -> App<'static, 'static> {
+    App::new("flake")
         .version("1.0")
         .author("David Calavera <david.calavera@gmail.com>")
         .about("Keep track of dotfiles")
+        .arg(Arg::with_name("quiet")
+            .short("q")
+            .long("quiet")
+            .global(true)
+            .help("Only print errors"))
+        .arg(Arg::with_name("verbose")
+            .short("v")
+            .long("verbose")
+            .global(true)
+            .help("Print details that help troubleshoot problems, like credential lookups"))
+        .arg(Arg::with_name("ssh-command")
+            .long("ssh-command")
+            .value_name("COMMAND")
+            .global(true)
+            .help("Connect to ssh:// repositories with this command, like GIT_SSH_COMMAND"))
+        .arg(Arg::with_name("profile")
+            .long("profile")
+            .value_name("NAME")
+            .global(true)
+            .help("Keep the sync state and lock of this profile apart from the others"))
+        .arg(Arg::with_name("color")
+            .long("color")
+            .value_name("WHEN")
+            .possible_values(&["auto", "always", "never"])
+            .global(true)
+            .help("Color the output, auto only colors terminals and honors NO_COLOR"))
+        .arg(Arg::with_name("error-format")
+            .long("error-format")
+            .value_name("FORMAT")
+            .possible_values(&["human", "json"])
+            .global(true)
+            .help("Print errors as text, the default, or as a JSON object on stderr"))
+        .arg(Arg::with_name("verbose-files")
+            .long("verbose-files")
+            .global(true)
+            .help("Print what happens to every file flake syncs, and why"))
+        .arg(Arg::with_name("home")
+            .long("home")
+            .value_name("PATH")
+            .global(true)
+            .help("Use this directory as the home directory, like another user's"))
+        .subcommand(SubCommand::with_name("add")
+            .about("Start tracking files from your home directory")
+            .args(&sync_args())
+            .arg(Arg::with_name("paths")
+                .required_unless("batch")
+                .multiple(true)
+                .help("The files to track, relative to your home directory"))
+            .arg(Arg::with_name("batch")
+                .long("batch")
+                .visible_alias("from-stdin")
+                .conflicts_with("paths")
+                .help("Read the files to track from stdin, one per line, and commit and push \
+                       them together")))
+        .subcommand(SubCommand::with_name("apply")
+            .about("Install the files in the repository into your home directory")
+            .arg(Arg::with_name("backup")
+                .short("b")
+                .long("backup")
+                .help("Keep a copy of the files it replaces with the .flake-bak extension"))
+            .arg(Arg::with_name("symlink")
+                .long("symlink")
+                .visible_alias("bare-apply")
+                .help("Link the files in your home directory to the repository instead of \
+                       copying them"))
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Show a diff of the changes to every file without writing anything"))
+            .arg(Arg::with_name("preserve-xattrs")
+                .long("preserve-xattrs")
+                .help("Set the extended attributes recorded in the repository on the files"))
+            .arg(Arg::with_name("resume")
+                .long("resume")
+                .conflicts_with("dry-run")
+                .help("Skip the files an interrupted apply of the same commit already wrote, \
+                       without asking"))
+            .arg(Arg::with_name("verify")
+                .long("verify")
+                .conflicts_with("dry-run")
+                .help("Read the files back after applying them and fail if any doesn't match the \
+                       repository"))
+            .arg(Arg::with_name("since-commit")
+                .long("since-commit")
+                .value_name("COMMIT")
+                .min_values(0)
+                .help("Only install the files that changed after this commit, by default the one \
+                       flake last applied"))
+            .arg(Arg::with_name("paths")
+                .multiple(true)
+                .help("Only install the files matching these paths or globs, like .zshrc or \
+                       .config/nvim/*")))
         .subcommand(SubCommand::with_name("auth")
             .about("Store auth token in the credentials store")
             .arg(Arg::with_name("token")
                 .required(true)
-                .help("GitHub's access token")))
+                .help("GitHub's access token"))
+            .arg(Arg::with_name("cred-backend")
+                .long("cred-backend")
+                .value_name("BACKEND")
+                .possible_values(&["secret-service", "gpg", "file"])
+                .help("Where to keep the token, secret-service by default, a gpg encrypted file \
+                       or a plaintext file"))
+            .arg(Arg::with_name("label")
+                .long("label")
+                .value_name("LABEL")
+                .help("Save the token in the secret service under this label, to keep a \
+                       separate token for each flake setup. `flake` by default"))
+            .arg(Arg::with_name("keyring-collection")
+                .long("keyring-collection")
+                .value_name("NAME")
+                .help("Save the token in this keyring collection, created if it doesn't exist, \
+                       instead of the default one, and only look for it there from now on"))
+            .arg(Arg::with_name("host")
+                .long("host")
+                .value_name("HOST")
+                .help("Only use the token for the repositories on this host, like a mirror"))
+            .arg(Arg::with_name("i-understand-the-risk")
+                .long("i-understand-the-risk")
+                .help("Allow storing the token in a plaintext file with --cred-backend file"))
+            .arg(Arg::with_name("test")
+                .long("test")
+                .help("Check that the stored token can authenticate with your repository")))
+        .subcommand(SubCommand::with_name("check")
+            .about("Exit with an error if any tracked file differs from the repository"))
+        .subcommand(SubCommand::with_name("completions")
+            .about("Print the completion script for a shell")
+            .arg(Arg::with_name("shell")
+                .required(true)
+                .possible_values(&Shell::variants())
+                .help("The shell to complete flake in")))
+        .subcommand(SubCommand::with_name("diff")
+            .about("Show the changes in the repository that aren't committed yet, like git diff")
+            .arg(Arg::with_name("staged")
+                .long("staged")
+                .help("Compare the staged changes instead of the files in the repository"))
+            .arg(Arg::with_name("against")
+                .long("against")
+                .value_name("COMMIT")
+                .help("Compare with a commit or a branch instead of the index"))
+            .arg(Arg::with_name("stat")
+                .long("stat")
+                .conflicts_with("name-only")
+                .help("Only show how many lines changed in each file"))
+            .arg(Arg::with_name("name-only")
+                .long("name-only")
+                .help("Only show the names of the changed files")))
+        .subcommand(SubCommand::with_name("doctor")
+            .about("Check that flake is ready to synchronize your dotfiles")
+            .arg(Arg::with_name("format")
+                .long("format")
+                .visible_alias("output-format")
+                .value_name("FORMAT")
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("Print a human readable or a JSON report")))
+        .subcommand(SubCommand::with_name("edit")
+            .about("Edit a tracked file in $EDITOR and commit it when the editor exits")
+            .args(&sync_args())
+            .arg(Arg::with_name("path")
+                .required(true)
+                .help("The file to edit, relative to your home directory")))
+        .subcommand(SubCommand::with_name("gc")
+            .about("Remove old .flake-bak backups")
+            .arg(Arg::with_name("older-than")
+                .long("older-than")
+                .value_name("DURATION")
+                .help("Only remove backups older than this, 30d by default")))
+        .subcommand(SubCommand::with_name("log")
+            .about("Show the history of your dotfiles")
+            .arg(Arg::with_name("since")
+                .long("since")
+                .value_name("DURATION|DATE")
+                .help("Only show changes newer than a duration, like 2d, or a date, like \
+                       2017-01-31")))
+        .subcommand(SubCommand::with_name("move")
+            .about("Rename a tracked file, keeping its history")
+            .args(&sync_args())
+            .arg(Arg::with_name("from")
+                .required(true)
+                .help("The tracked file, relative to your home directory"))
+            .arg(Arg::with_name("to")
+                .required(true)
+                .help("The new name, relative to your home directory"))
+            .arg(Arg::with_name("force")
+                .short("f")
+                .long("force")
+                .help("Replace the destination if it already exists"))
+            .arg(Arg::with_name("store-only")
+                .long("store-only")
+                .help("Only rename the file in the repository, when you already moved it in \
+                       your home directory")))
+        .subcommand(SubCommand::with_name("prune-history")
+            .about("Squash old commits to keep the repository small, rewriting its history")
+            .args(&sync_args())
+            .arg(Arg::with_name("before")
+                .long("before")
+                .value_name("DURATION|DATE")
+                .help("Only squash commits older than a duration, like 52w, or a date, like \
+                       2017-01-31. Squashes everything by default"))
+            .arg(Arg::with_name("author-from-commit")
+                .long("author-from-commit")
+                .help("Commit the squashed history as its last committer instead of as you"))
+            .arg(Arg::with_name("force")
+                .long("force")
+                .help("Rewrite the history and force push it")))
+        .subcommand(SubCommand::with_name("reauth")
+            .about("Replace the stored access token, reading the new one from FLAKE_TOKEN or stdin")
+            .arg(Arg::with_name("host")
+                .long("host")
+                .value_name("HOST")
+                .help("Replace the token saved for this host"))
+            .arg(Arg::with_name("test")
+                .long("test")
+                .help("Check that the new token can authenticate with your repository")))
+        .subcommand(SubCommand::with_name("restore")
+            .about("Restore a single file from the repository into your home directory")
+            .arg(Arg::with_name("path")
+                .required(true)
+                .help("The file to restore, relative to your home directory"))
+            .arg(Arg::with_name("backup")
+                .short("b")
+                .long("backup")
+                .help("Keep a copy of the current file with the .flake-bak extension")))
+        .subcommand(SubCommand::with_name("stats")
+            .about("Summarize the files and history of your dotfiles")
+            .arg(Arg::with_name("json")
+                .long("json")
+                .help("Print the summary as JSON")))
+        .subcommand(SubCommand::with_name("status")
+            .about("Show the files that changed since the last sync")
+            .arg(Arg::with_name("remote")
+                .long("remote")
+                .help("Also fetch the remote and show how far the local repository is from it"))
+            .arg(Arg::with_name("short")
+                .long("short")
+                .conflicts_with("remote")
+                .help("Print a one line summary for a shell prompt, without fetching")))
+        .subcommand(SubCommand::with_name("tracked")
+            .about("Print whether flake tracks a file, and exit with 1 when it doesn't")
+            .arg(Arg::with_name("path")
+                .required(true)
+                .help("The file to look for, relative to your home directory")))
+        .subcommand(SubCommand::with_name("store")
+            .about("Manage the local copy of your dotfiles repository")
+            .subcommand(SubCommand::with_name("move")
+                .about("Move the local repository to a new location")
+                .arg(Arg::with_name("path")
+                    .required(true)
+                    .help("The new location of the repository"))))
         .subcommand(SubCommand::with_name("sync")
             .about("Syncronize repository")
-            .arg(Arg::with_name("repository")
-                .short("r")
-                .long("repository")
-                .value_name("HTTP_URL")
-                .help("The repository http url"))
+            .args(&sync_args())
+            .arg(Arg::with_name("interval")
+                .short("i")
+                .long("interval")
+                .value_name("DURATION")
+                .help("The interval to sync files, like 90 (seconds), 30m or 1h"))
+            .arg(Arg::with_name("once")
+                .long("once")
+                .help("Syncronize the repository once and exit"))
+            .arg(Arg::with_name("update-remote-tracking")
+                .long("update-remote-tracking")
+                .help("Only fetch the remote and report how far behind the local repository is, \
+                       without touching any file"))
+            .arg(Arg::with_name("dump-plan")
+                .long("dump-plan")
+                .conflicts_with("update-remote-tracking")
+                .help("Print what the sync would do as JSON and exit, without touching any file")))
+        .subcommand(SubCommand::with_name("verify")
+            .about("Check that the local repository isn't damaged")
+            .arg(Arg::with_name("repair")
+                .long("repair")
+                .help("Reset the index and the files in the repository to the last commit")))
+        .subcommand(SubCommand::with_name("whoami")
+            .about("Show the settings flake resolved and where each one comes from")
+            .args(&sync_args())
+            .arg(Arg::with_name("json")
+                .long("json")
+                .help("Print the settings as JSON")))
+        .subcommand(SubCommand::with_name("watch")
+            .about("Syncronize repository every time a tracked file changes")
+            .args(&sync_args())
             .arg(Arg::with_name("interval")
                 .short("i")
                 .long("interval")
-                .value_name("SECONDS")
-                .help("The interval to sync files in seconds")))
-        .get_matches();
+                .value_name("DURATION")
+                .help("Also syncronize the repository periodically, like 90 (seconds), 30m or \
+                       1h"))
+            .arg(Arg::with_name("watch-debounce")
+                .long("watch-debounce")
+                .value_name("DURATION")
+                .help("Wait for the changes to settle this long before syncing, 2s by default"))
+            .arg(Arg::with_name("watch-ignore")
+                .long("watch-ignore")
+                .value_name("GLOB")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Never sync because of changes to files matching this glob, like \
+                       .zsh_history. Editor swap files are always ignored")))
+}
 
-    match matches.subcommand() {
+fn main() {
+    let matches = app().get_matches();
+
+    let quiet = matches.is_present("quiet") ||
+                matches.subcommand().1.map(|m| m.is_present("quiet")).unwrap_or(false);
+    let verbose = matches.is_present("verbose") ||
+                  matches.subcommand().1.map(|m| m.is_present("verbose")).unwrap_or(false);
+    let verbose_files = matches.is_present("verbose-files") ||
+                        matches.subcommand()
+                            .1
+                            .map(|m| m.is_present("verbose-files"))
+                            .unwrap_or(false);
+    logger::init(quiet, verbose, verbose_files);
+    color::init(matches.value_of("color")
+        .or_else(|| matches.subcommand().1.and_then(|m| m.value_of("color")))
+        .unwrap_or("auto"));
+
+    let home = match matches.value_of("home")
+        .or_else(|| matches.subcommand().1.and_then(|m| m.value_of("home"))) {
+        Some(path) => override_home(path),
+        None => Ok(()),
+    };
+
+    let ssh_command = matches.value_of("ssh-command")
+        .or_else(|| matches.subcommand().1.and_then(|m| m.value_of("ssh-command")))
+        .map(String::from)
+        .or_else(|| env::var("GIT_SSH_COMMAND").ok())
+        .or_else(|| {
+            git2::Config::open_default().ok().and_then(|c| c.get_string("core.sshCommand").ok())
+        });
+    if let Some(command) = ssh_command {
+        if let Err(error) = ssh::register(command) {
+            warn!("Unable to use the custom ssh command: {}", error);
+        }
+    }
+
+    let result = home.and_then(|_| match matches.subcommand() {
+        ("add", Some(add_matches)) => add(add_matches),
+        ("apply", Some(apply_matches)) => apply(apply_matches),
         ("auth", Some(auth_matches)) => auth(auth_matches),
+        ("check", Some(check_matches)) => check(check_matches),
+        ("completions", Some(completions_matches)) => completions(completions_matches),
+        ("diff", Some(diff_matches)) => diff(diff_matches),
+        ("doctor", Some(doctor_matches)) => doctor(doctor_matches),
+        ("edit", Some(edit_matches)) => edit(edit_matches),
+        ("gc", Some(gc_matches)) => gc(gc_matches),
+        ("log", Some(log_matches)) => log(log_matches),
+        ("move", Some(move_matches)) => move_file(move_matches),
+        ("prune-history", Some(prune_matches)) => prune_history(prune_matches),
+        ("reauth", Some(reauth_matches)) => reauth(reauth_matches),
+        ("restore", Some(restore_matches)) => restore(restore_matches),
+        ("stats", Some(stats_matches)) => stats(stats_matches),
+        ("status", Some(status_matches)) => status(status_matches),
+        ("store", Some(store_matches)) => store(store_matches),
         ("sync", Some(sync_matches)) => sync(sync_matches),
-        ("", None) => println!("Please, run flake command with `auth` or `sync` subcommands"),
+        ("tracked", Some(tracked_matches)) => tracked(tracked_matches),
+        ("verify", Some(verify_matches)) => verify(verify_matches),
+        ("watch", Some(watch_matches)) => watch(watch_matches),
+        ("whoami", Some(whoami_matches)) => whoami(whoami_matches),
+        ("", None) => {
+            println!("Please, run flake command with `add`, `apply`, `auth`, `check`, \
+                      `completions`, `diff`, `doctor`, `edit`, `gc`, `log`, `move`, \
+                      `prune-history`, `reauth`, `restore`, `stats`, `status`, `store`, `sync`, \
+                      `tracked`, `verify`, `watch` or `whoami` subcommands");
+            Ok(())
+        }
         _ => unreachable!(),
+    });
+
+    if let Err(error) = result {
+        let format = matches.value_of("error-format")
+            .or_else(|| matches.subcommand().1.and_then(|m| m.value_of("error-format")));
+        if format == Some("json") {
+            let _ = writeln!(io::stderr(), "{}", error.to_json());
+        } else {
+            error!("{}", error);
+        }
+        process::exit(error.exit_code());
     }
 }
 
-fn auth(matches: &ArgMatches) {
-    match SecretService::new(EncryptionType::Dh) {
-        Err(error) => {
-            println!("Unable to connect with the secret service: {}", error);
-            process::exit(1);
-        }
-        Ok(ss) => {
-            let collection = ss.get_default_collection().unwrap();
+// Every path flake reads or writes in the home directory, its store and its configuration
+// included, comes from HOME, which is replaced before libgit2 reads the global configuration.
+// The XDG directories of the current user would point outside of the new home.
+fn override_home(path: &str) -> Result<(), FlakeError> {
+    let home = fs::canonicalize(path)
+        .map_err(|error| FlakeError::Config(format!("unable to use {} as the home directory: {}",
+                                                    path,
+                                                    error)))?;
+    if !home.is_dir() {
+        return Err(FlakeError::Config(format!("unable to use {} as the home directory, it's not \
+                                               a directory",
+                                              path)));
+    }
+
+    env::set_var("HOME", &home);
+    env::remove_var("XDG_CONFIG_HOME");
+    env::remove_var("XDG_DATA_HOME");
+    debug!("Using {} as the home directory", home.display());
+    Ok(())
+}
+
+fn sync_args() -> Vec<Arg<'static, 'static>> {
+    vec![Arg::with_name("repository")
+             .short("r")
+             .long("repository")
+             .value_name("HTTP_URL")
+             .help("The repository http url"),
+         Arg::with_name("branch")
+             .long("branch")
+             .visible_alias("remote-branch")
+             .value_name("BRANCH")
+             .help("The branch to syncronize, by default the remote's default branch"),
+         Arg::with_name("local-branch")
+             .long("local-branch")
+             .value_name("BRANCH")
+             .help("Commit to this branch of the store, and push it to the synchronized branch"),
+         Arg::with_name("timeout")
+             .long("timeout")
+             .value_name("DURATION")
+             .help("Abort fetches and pushes that take longer than this, 60 seconds by default"),
+         Arg::with_name("jobs")
+             .short("j")
+             .long("jobs")
+             .value_name("N")
+             .help("How many mirrors to push to at the same time, 4 by default"),
+         Arg::with_name("prune")
+             .long("prune")
+             .help("Remove the files deleted from the repository in other machines from your home \
+                    directory, keeping a .flake-bak copy"),
+         Arg::with_name("strategy")
+             .long("strategy")
+             .value_name("STRATEGY")
+             .possible_values(&["home-wins", "repo-wins", "newest-wins"])
+             .help("How to resolve files changed in both your home directory and the \
+                    repository, newest-wins by default"),
+         Arg::with_name("shallow")
+             .long("shallow")
+             .help("Only fetch the synchronized branch, for slow machines"),
+         Arg::with_name("filter")
+             .long("filter")
+             .value_name("FILTER")
+             .help("Clone the store without the objects this filter leaves out, like blob:none"),
+         Arg::with_name("proxy")
+             .long("proxy")
+             .value_name("URL")
+             .help("The proxy to reach the repository, by default http.proxy or HTTPS_PROXY"),
+         Arg::with_name("ff-only")
+             .long("ff-only")
+             .help("Refuse to sync when the local repository has commits that are not in the \
+                    remote, the default"),
+         Arg::with_name("no-ff-only")
+             .long("no-ff-only")
+             .conflicts_with("ff-only")
+             .help("Discard local commits that are not in the remote, capturing your home \
+                    directory again"),
+         Arg::with_name("config-scope")
+             .long("config-scope")
+             .value_name("global|system|PATH")
+             .help("Read flake's settings only from the global or system git config, or from a \
+                    config file"),
+         Arg::with_name("max-files")
+             .long("max-files")
+             .value_name("COUNT")
+             .help("Ask for confirmation before committing more changed files than this"),
+         Arg::with_name("max-bytes")
+             .long("max-bytes")
+             .value_name("BYTES")
+             .help("Ask for confirmation before committing more changed bytes than this"),
+         Arg::with_name("yes")
+             .short("y")
+             .long("yes")
+             .help("Commit large changes, or run the bootstrap script, without asking for \
+                    confirmation"),
+         Arg::with_name("verify-signatures")
+             .long("verify-signatures")
+             .help("Refuse to sync remote commits that aren't signed by a key in \
+                    flake.trustedkey"),
+         Arg::with_name("bootstrap")
+             .long("bootstrap")
+             .help("Run the repository's .flake/bootstrap script on the first sync of this \
+                    machine"),
+         Arg::with_name("per-file")
+             .long("per-file")
+             .help("Create a separate commit for each changed file"),
+         Arg::with_name("group-by-dir")
+             .long("group-by-dir")
+             .conflicts_with("per-file")
+             .help("Create a separate commit for each top level directory with changes"),
+         Arg::with_name("max-commits-per-run")
+             .long("max-commits-per-run")
+             .value_name("COUNT")
+             .help("Make a single commit when --per-file or --group-by-dir would create more \
+                    commits than this, 50 by default"),
+         Arg::with_name("no-commit")
+             .long("no-commit")
+             .help("Only copy your changes into the local repository, without committing or \
+                    pushing them"),
+         Arg::with_name("store-format")
+             .long("store-format")
+             .value_name("LAYOUT")
+             .possible_values(&[layout::MIRROR, layout::SUBDIR])
+             .help("How a new repository lays out the files, mirroring your home directory or \
+                    grouped in package directories, like vim/.vimrc"),
+         Arg::with_name("preserve-xattrs")
+             .long("preserve-xattrs")
+             .help("Record the extended attributes of your files in the repository"),
+         Arg::with_name("commit-empty")
+             .long("commit-empty")
+             .conflicts_with("no-commit")
+             .help("Commit even when nothing changed, to record that the sync ran"),
+         Arg::with_name("ignore-mode-changes")
+             .long("ignore-mode-changes")
+             .help("Don't commit files whose permissions changed but their content didn't"),
+         Arg::with_name("stage")
+             .long("stage")
+             .requires("no-commit")
+             .help("Stage the changes copied with --no-commit"),
+         Arg::with_name("report")
+             .long("report")
+             .value_name("FILE")
+             .help("Append a JSON line to this file with the changes of every sync that commits"),
+         Arg::with_name("mtime-dates")
+             .long("mtime-dates")
+             .help("Date commits when the files changed instead of when flake runs"),
+         Arg::with_name("only-if-online")
+             .long("only-if-online")
+             .help("Skip the sync without an error when the repository can't be reached"),
+         Arg::with_name("include-dotfiles-only")
+             .long("include-dotfiles-only")
+             .help("Only capture hidden files and files in hidden directories"),
+         Arg::with_name("capture")
+             .long("capture")
+             .value_name("POLICY")
+             .possible_values(&["all", "tracked"])
+             .help("Capture every file in the repository, or only the ones committed or added \
+                    with `flake add`. all by default"),
+         Arg::with_name("trust-mtime")
+             .long("trust-mtime")
+             .value_name("BOOL")
+             .possible_values(&["true", "false"])
+             .help("Compare the content of every file when false, for modification times that \
+                    can't be trusted"),
+         Arg::with_name("include-vcs")
+             .long("include-vcs")
+             .help("Also capture the files of git repositories nested in tracked directories")]
+}
+
+// Copies the files into the repository and stages them, the next sync commits them.
+fn add(matches: &ArgMatches) -> Result<(), FlakeError> {
+    if matches.is_present("batch") {
+        return add_batch(matches);
+    }
+
+    let repo = open_storage()?;
+    let workdir = repo.workdir().unwrap();
+    let home = env::home_dir().unwrap();
+    let layout = Layout::load(workdir);
+
+    let mut index = repo.index()?;
+    for path in matches.values_of("paths").unwrap() {
+        add_path(workdir, &home, &layout, &mut index, path)?;
+    }
+    index.write()?;
+    Ok(())
+}
+
+// The batch is pushed right away, like `edit` does, because a commit that only exists in the
+// local repository would stop the next sync. A bad path doesn't stop the batch, the files that
+// could be added are still committed.
+fn add_batch(matches: &ArgMatches) -> Result<(), FlakeError> {
+    let syncer = Syncer::new(matches)?;
+    syncer.require_up_to_date()?;
+
+    let layout = Layout::load(syncer.repo.workdir().unwrap());
+    let stdin = io::stdin();
+    add_lines(&syncer, &env::home_dir().unwrap(), &layout, stdin.lock())
+}
 
-            let token = String::from(matches.value_of("token").unwrap());
-            if let Err(error) = collection.create_item("flake",
-                                                       vec![("github", "access_token")],
-                                                       token.as_bytes(),
-                                                       true,
-                                                       "text/plain") {
-                println!("Something went wrong saving the access token :/ {}", error);
-                process::exit(1);
+// Adds the file named on every line of `input`, then commits and pushes the ones it could add.
+fn add_lines<R: BufRead>(syncer: &Syncer,
+                         home: &Path,
+                         layout: &Layout,
+                         input: R)
+                         -> Result<(), FlakeError> {
+    let repo = &syncer.repo;
+    let workdir = repo.workdir().unwrap();
+
+    let mut index = repo.index()?;
+    let mut added = 0;
+    let mut failed = 0;
+    for line in input.lines() {
+        let line = line?;
+        let path = line.trim();
+        if path.is_empty() {
+            continue;
+        }
+        match add_path(workdir, home, layout, &mut index, path) {
+            Ok(_) => added += 1,
+            Err(error) => {
+                error!("Unable to add {}: {}", path, error);
+                failed += 1;
             }
         }
     }
+
+    if added > 0 {
+        let message = format!("Add {} files", added);
+        commit_index(repo,
+                     &mut index,
+                     &with_trailers(&message, &syncer.options.trailers),
+                     None)?;
+        info!("Committed {} added files", added);
+        syncer.push(false)?;
+    }
+    if failed > 0 {
+        return Err(FlakeError::Io(format!("{} of {} files couldn't be added",
+                                          failed,
+                                          added + failed)));
+    }
+    Ok(())
 }
 
-fn sync(matches: &ArgMatches) {
-    let config = git2::Config::open_default().unwrap().snapshot().unwrap();
-    let url = match matches.value_of("repository") {
-        None => {
-            match config.get_str("github.dotfiles") {
-                Err(error) => {
-                    println!("repository url not provided, use `git config --global --add \
-                              github.dotfiles URL` to set a default repository: {}",
-                             error);
-                    process::exit(1);
+fn add_path(workdir: &Path,
+            home: &Path,
+            layout: &Layout,
+            index: &mut Index,
+            path: &str)
+            -> Result<(), FlakeError> {
+    let name = relative_to_home(path);
+    let source = home.join(&name);
+    if !source.is_file() {
+        return Err(FlakeError::Config(format!("{} is not a file in your home directory",
+                                              name.display())));
+    }
+
+    let store_name = layout.store_name(&name);
+    let target = workdir.join(&store_name);
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(&source, &target)?;
+    index.add_path(&store_name)?;
+    info!("Added {}", store_name.display());
+    Ok(())
+}
+
+fn apply(matches: &ArgMatches) -> Result<(), FlakeError> {
+    let repo = open_storage()?;
+    let config = flake_config()?;
+    let backup = matches.is_present("backup");
+    let link = matches.is_present("symlink");
+
+    let vars = template::variables(&config);
+    let layout = Layout::load(repo.workdir().unwrap());
+    let mut files = selected_files(tracked_files(repo.workdir().unwrap()),
+                                   matches.values_of("paths"),
+                                   &layout)?;
+
+    let profile = profile(matches, &config)?;
+    let mut state = State::load(&repo, profile.as_ref());
+    let head = repo.refname_to_id("HEAD")?;
+    if matches.is_present("since-commit") {
+        let since = matches.value_of("since-commit")
+            .map(String::from)
+            .or_else(|| state.last_applied.clone());
+        match since {
+            Some(since) => {
+                let changed = changed_since(&repo, &since, head).map_err(|error| {
+                        FlakeError::from(error).context(&format!("failed to compare with {}",
+                                                                 since))
+                    })?;
+                files.retain(|name| changed.contains(name));
+            }
+            None => info!("No commit was applied in this machine yet, applying every file"),
+        }
+    }
+
+    if matches.is_present("dry-run") {
+        return preview_apply(&repo, &files, &vars);
+    }
+
+    let workdir = repo.workdir().unwrap();
+    let verified = if matches.is_present("verify") {
+        Some(files.clone())
+    } else {
+        None
+    };
+
+    let mut journal = Journal::load(&repo);
+    let resume = match journal.interrupted(head) {
+        Some(count) => confirm_resume(count, matches.is_present("resume")),
+        None => false,
+    };
+    if let Err(error) = journal.start(head, resume) {
+        warn!("Unable to write the apply journal: {}", error);
+    }
+
+    let mut applied = Vec::new();
+    let mut adopted = 0;
+    for name in files {
+        if template::is_template(name.as_path()) {
+            match apply_template(&repo, name.as_path(), &vars, backup) {
+                Ok(Some(target)) => applied.push(target),
+                Ok(None) => adopted += 1,
+                Err(error) => warn!("Unable to render template {}: {}", name.display(), error),
+            }
+            continue;
+        }
+
+        // They still get their permissions and go through the hooks, the interrupted apply
+        // stopped before it got to that.
+        if resume && journal.contains(&name) {
+            debug!(target: logger::FILES,
+                   "Skipped {}, the interrupted apply already wrote it",
+                   name.display());
+            applied.push(name);
+            continue;
+        }
+
+        // Files that already match don't need a backup or a copy, only links are still created.
+        if !link && is_identical(&layout.home_path(&name), &workdir.join(&name)) {
+            info!("Adopted {}, it already matches the repository", name.display());
+            adopted += 1;
+            continue;
+        }
+
+        let result = if link {
+            link_path(&repo, name.as_path())
+        } else {
+            restore_path(&repo, name.as_path(), backup)
+        };
+        match result {
+            Ok(_) => {
+                if let Err(error) = journal.record(&name) {
+                    warn!("Unable to write the apply journal: {}", error);
                 }
-                Ok(r) => Some(r),
+                applied.push(name);
+            }
+            Err(error) => warn!("Unable to apply file {}: {}", name.display(), error),
+        }
+    }
+
+    let permissions = Permissions::load(&config);
+    for name in &applied {
+        if let Err(error) = permissions.restrict(&layout.home_path(name),
+                                                 &layout.home_name(name)) {
+            warn!("Unable to restrict the permissions of {}: {}", name.display(), error);
+        }
+    }
+
+    // Links share the attributes of the repository's copy.
+    if !link &&
+       (matches.is_present("preserve-xattrs") ||
+        config.get_bool("flake.preservexattrs").unwrap_or(false)) {
+        let manifest = xattrs::Manifest::load(repo.workdir().unwrap());
+        for name in &applied {
+            manifest.restore(name, &layout.home_path(name));
+        }
+    }
+
+    if adopted > 0 {
+        info!("Applied {} files, adopted {} that already matched", applied.len(), adopted);
+    } else {
+        info!("Applied {} files", applied.len());
+    }
+    let changed = home_names(&layout, &applied);
+    Reload::run_matching(&Reload::load(&config), &changed);
+    Hooks::load(&config).run(hooks::POST_APPLY, workdir, &changed, Some(head));
+
+    state.last_applied = Some(head.to_string());
+    state.first_sync = false;
+    if let Err(error) = state.save(&repo, profile.as_ref()) {
+        warn!("Unable to save the sync state: {}", error);
+    }
+    if let Err(error) = journal.clear() {
+        warn!("Unable to remove the apply journal: {}", error);
+    }
+
+    match verified {
+        Some(files) => verify_apply(&repo, &layout, &permissions, &files, &applied, &vars, link),
+        None => Ok(()),
+    }
+}
+
+// Reads back every file `apply` installed, after the hooks ran, to catch failures it didn't
+// notice and files something else rewrote right away. The files it copied must also have the
+// mode it left on them, and none can be more open than `permissions` allow.
+fn verify_apply(repo: &Repository,
+                layout: &Layout,
+                permissions: &Permissions,
+                files: &[PathBuf],
+                applied: &[PathBuf],
+                vars: &BTreeMap<String, String>,
+                link: bool)
+                -> Result<(), FlakeError> {
+    let workdir = repo.workdir().unwrap();
+
+    let mut mismatches = 0;
+    for name in files {
+        let source = workdir.join(name);
+        let (target_name, matches) = if template::is_template(name) {
+            let mut content = String::new();
+            fs::File::open(&source)?.read_to_string(&mut content)?;
+            let rendered = template::render(&content, vars).map_err(FlakeError::Config)?;
+
+            let target_name = template::target_name(name);
+            let mut current = Vec::new();
+            let read = fs::File::open(layout.home_path(&target_name))
+                .and_then(|mut f| f.read_to_end(&mut current));
+            (target_name, read.is_ok() && current == rendered.as_bytes())
+        } else {
+            let target = layout.home_path(name);
+            (name.clone(),
+             links_to(&target, &source) || (!link && is_identical(&target, &source)))
+        };
+
+        let target = layout.home_path(&target_name);
+        let home_name = layout.home_name(&target_name);
+        if !matches {
+            warn!("{} doesn't match the repository after applying it",
+                  home_name.display());
+            mismatches += 1;
+        } else if !links_to(&target, &source) {
+            let mode = fs::metadata(&target)?.permissions().mode() & 0o7777;
+            let expected = if applied.contains(name) && !template::is_template(name) {
+                permissions.restricted(fs::metadata(&source)?.permissions().mode() & 0o7777,
+                                       &home_name)
+            } else {
+                permissions.restricted(mode, &home_name)
+            };
+            if mode != expected {
+                warn!("{} has mode {:o} after applying it, instead of {:o}",
+                      home_name.display(),
+                      mode,
+                      expected);
+                mismatches += 1;
             }
         }
-        s => s,
+    }
+
+    if mismatches > 0 {
+        return Err(FlakeError::Drift(format!("{} applied files don't match the repository",
+                                             mismatches)));
+    }
+    info!("Verified {} files", files.len());
+    Ok(())
+}
+
+// Prints what `apply` would change, a diff for the files that exist and differ.
+fn preview_apply(repo: &Repository,
+                 files: &[PathBuf],
+                 vars: &BTreeMap<String, String>)
+                 -> Result<(), FlakeError> {
+    let workdir = repo.workdir().unwrap();
+    let layout = Layout::load(workdir);
+
+    for name in files {
+        let mut content = Vec::new();
+        fs::File::open(workdir.join(name))?.read_to_end(&mut content)?;
+        let (target_name, content) = if template::is_template(name) {
+            let rendered = template::render(&String::from_utf8_lossy(&content), vars)
+                .map_err(FlakeError::Config)?;
+            (template::target_name(name), rendered.into_bytes())
+        } else {
+            (name.clone(), content)
+        };
+
+        let target = layout.home_path(&target_name);
+        let target_name = layout.home_name(&target_name);
+        let mut current = Vec::new();
+        if fs::File::open(target)
+            .and_then(|mut f| f.read_to_end(&mut current))
+            .is_err() {
+            println!("{} {}", color::change("new"), target_name.display());
+            continue;
+        }
+        if current == content {
+            continue;
+        }
+
+        let mut patch = git2::Patch::from_buffers(&current,
+                                                  Some(&target_name),
+                                                  &content,
+                                                  Some(&target_name),
+                                                  None)?;
+        print!("{}", color::diff(&String::from_utf8_lossy(&patch.to_buf()?)));
+    }
+    Ok(())
+}
+
+// The same comparisons as `git diff` in the store: the files copied into the repository, like
+// with `sync --no-commit`, against the index, or the index against HEAD with `--staged`.
+// `--against` replaces the index, or HEAD, with any commit.
+fn diff(matches: &ArgMatches) -> Result<(), FlakeError> {
+    let repo = open_storage()?;
+    let staged = matches.is_present("staged");
+    let tree = match matches.value_of("against") {
+        Some(against) => Some(against),
+        None if staged => Some("HEAD"),
+        None => None,
+    };
+    let tree = match tree {
+        Some(reference) => {
+            let commit = repo.revparse_single(reference)
+                .and_then(|object| object.peel(git2::ObjectType::Commit))
+                .map_err(|error| {
+                    FlakeError::Config(format!("unknown commit {}: {}", reference, error.message()))
+                })?;
+            Some(repo.find_commit(commit.id())?.tree()?)
+        }
+        None => None,
     };
 
-    let username = match config.get_str("github.username") {
-        Err(error) => {
-            println!("GitHub username not provided, use `git config --global --add \
-                      github.username USERNAME` to set your username: {}",
-                     error);
-            process::exit(1);
+    let mut options = git2::DiffOptions::new();
+    options.include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .show_untracked_content(true);
+    let diff = match (tree, staged) {
+        (Some(tree), true) => repo.diff_tree_to_index(Some(&tree), None, Some(&mut options))?,
+        (Some(tree), false) => {
+            repo.diff_tree_to_workdir_with_index(Some(&tree), Some(&mut options))?
         }
-        Ok(name) => name,
+        (None, _) => repo.diff_index_to_workdir(None, Some(&mut options))?,
     };
 
-    let repo = match init_storage(url.unwrap()) {
-        Err(error) => {
-            println!("failed to open repository: {}", error);
-            process::exit(1);
+    if matches.is_present("name-only") {
+        for delta in diff.deltas() {
+            if let Some(name) = delta.new_file().path().or_else(|| delta.old_file().path()) {
+                println!("{}", name.display());
+            }
         }
-        Ok(r) => r,
+        return Ok(());
+    }
+    if matches.is_present("stat") {
+        let stats = diff.stats()?.to_buf(git2::DIFF_STATS_FULL, 80)?;
+        print!("{}", String::from_utf8_lossy(&stats));
+        return Ok(());
+    }
+
+    let mut patch = Vec::new();
+    diff.print(git2::DiffFormat::Patch, |_, _, line| {
+            match line.origin() {
+                '+' | '-' | ' ' => patch.push(line.origin() as u8),
+                _ => {}
+            }
+            patch.extend_from_slice(line.content());
+            true
+        })?;
+    print!("{}", color::diff(&String::from_utf8_lossy(&patch)));
+    Ok(())
+}
+
+// The files added or modified between a commit and HEAD.
+fn changed_since(repo: &Repository, since: &str, head: Oid) -> Result<BTreeSet<PathBuf>, Error> {
+    let since = repo.revparse_single(since)?.id();
+    let old = repo.find_commit(since)?.tree()?;
+    let new = repo.find_commit(head)?.tree()?;
+
+    let diff = repo.diff_tree_to_tree(Some(&old), Some(&new), None)?;
+    Ok(diff.deltas()
+        .filter_map(|delta| match delta.status() {
+            git2::Delta::Deleted => None,
+            _ => delta.new_file().path().map(PathBuf::from),
+        })
+        .collect())
+}
+
+// Keeps the files that match any of the given path globs, or all of them without globs.
+// Templates match by the name of the file they render to.
+fn selected_files(files: Vec<PathBuf>,
+                  paths: Option<Values>,
+                  layout: &Layout)
+                  -> Result<Vec<PathBuf>, FlakeError> {
+    let paths = match paths {
+        Some(paths) => paths,
+        None => return Ok(files),
     };
 
-    if let Err(error) = init_sync(username, &repo) {
-        println!("failed the initial sync: {}", error);
-        process::exit(1);
+    let mut patterns = Vec::new();
+    for path in paths {
+        let pattern = Pattern::new(path)
+            .map_err(|error| FlakeError::Config(format!("invalid path `{}`: {}", path, error)))?;
+        patterns.push(pattern);
     }
 
-    let interval = value_t!(matches.value_of("interval"), u64).unwrap_or(1800);
-    let tick = schedule_recv::periodic(Duration::from_secs(interval));
-    loop {
-        tick.recv().unwrap();
+    let matches = |pattern: &Pattern, name: &PathBuf| {
+        let target = if template::is_template(name) {
+            layout.home_name(&template::target_name(name))
+        } else {
+            layout.home_name(name)
+        };
+        pattern.matches_path(&target) || target.starts_with(pattern.as_str())
+    };
 
-        let state = sync_repo(username, &repo);
-        if state.is_err() {
-            println!("failed the sync repository: {}",
-                     state.err().unwrap().message());
-            process::exit(1);
+    for pattern in &patterns {
+        if !files.iter().any(|name| matches(pattern, name)) {
+            return Err(FlakeError::Config(format!("`{}` doesn't match any file in the repository",
+                                                  pattern.as_str())));
         }
     }
+
+    Ok(files.into_iter().filter(|name| patterns.iter().any(|p| matches(p, name))).collect())
 }
 
-fn init_storage(url: &str) -> Result<Repository, Error> {
-    let home = env::home_dir().unwrap();
-    let storage = home.join(STORE_NAME);
+fn auth(matches: &ArgMatches) -> Result<(), FlakeError> {
+    let config = flake_config()?;
+    let store = match matches.value_of("cred-backend") {
+        Some(name) => CredentialStore::from_name(name, &config, config_dir()),
+        None => CredentialStore::from_config(&config, config_dir()),
+    };
+    let mut store = store.map_err(FlakeError::Config)?;
 
-    if storage.exists() {
-        if storage.is_file() {
-            println!("{} is a file!", storage.to_string_lossy());
-            process::exit(1);
+    if let Some(label) = matches.value_of("label") {
+        match store {
+            CredentialStore::SecretService { label: ref mut current, .. } => {
+                *current = String::from(label)
+            }
+            _ => {
+                return Err(FlakeError::Config(String::from("--label only works with the \
+                                                            secret-service backend")));
+            }
+        }
+    }
+
+    if let Some(name) = matches.value_of("keyring-collection") {
+        match store {
+            CredentialStore::SecretService { ref mut collection, .. } => {
+                *collection = Some(String::from(name))
+            }
+            _ => {
+                return Err(FlakeError::Config(String::from("--keyring-collection only works \
+                                                            with the secret-service backend")));
+            }
+        }
+    }
+
+    match store {
+        CredentialStore::SecretService { .. } => {}
+        CredentialStore::File { .. } if !matches.is_present("i-understand-the-risk") => {
+            return Err(FlakeError::Config(String::from("the file backend stores your token \
+                                                        in plaintext, pass \
+                                                        --i-understand-the-risk to use it")));
+        }
+        CredentialStore::Gpg { ref path, .. } |
+        CredentialStore::File { ref path } => {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
         }
+    }
+
+    if let CredentialStore::File { ref path } = store {
+        warn!("Your access token will be stored UNENCRYPTED in {}. Anyone who can read that file \
+               can push to your repositories, use the secret-service or gpg backends if you can",
+              path.display());
+    }
+
+    let token = matches.value_of("token").unwrap();
+    store.save(token, matches.value_of("host")).map_err(FlakeError::Auth)?;
 
-        return Repository::open(storage.as_path());
+    if matches.is_present("cred-backend") {
+        let mut global = git2::Config::open_default()?;
+        global.set_str("flake.credentials", store.name())?;
     }
-    RepoBuilder::new().bare(false).clone(url, storage.as_path())
+    if let Some(name) = matches.value_of("keyring-collection") {
+        let mut global = git2::Config::open_default()?;
+        global.set_str("flake.keyringcollection", name)?;
+    }
+
+    if matches.is_present("test") {
+        return test_credentials();
+    }
+    Ok(())
 }
 
-fn init_sync(username: &str, repo: &Repository) -> Result<(), Error> {
-    reset_master(username, repo)?;
-    sync_repo(username, repo)
+// Rotates the token in the configured backend, under the same label, without going through
+// `flake auth` again. The token never goes through the command line, where it would end up in
+// the shell history.
+fn reauth(matches: &ArgMatches) -> Result<(), FlakeError> {
+    let config = flake_config()?;
+    let store = CredentialStore::from_config(&config, config_dir()).map_err(FlakeError::Config)?;
+
+    let token = match env::var("FLAKE_TOKEN") {
+        Ok(token) => token,
+        Err(_) => read_token()?,
+    };
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(FlakeError::Config(String::from("the new access token is empty")));
+    }
+
+    store.replace(token, matches.value_of("host")).map_err(FlakeError::Auth)?;
+    info!("Replaced the access token in the {} backend", store.name());
+
+    if matches.is_present("test") {
+        return test_credentials();
+    }
+    Ok(())
 }
 
-fn sync_repo(username: &str, repo: &Repository) -> Result<(), Error> {
-    sync_files(repo.workdir().unwrap());
+// Prompts without echoing the token when stdin is a terminal.
+fn read_token() -> Result<String, FlakeError> {
+    let mut token = String::new();
+    if !is_interactive() {
+        io::stdin().read_line(&mut token)?;
+        return Ok(token);
+    }
+
+    print!("New access token: ");
+    io::stdout().flush()?;
 
-    let statuses = repo.statuses(None)?;
-    if statuses.len() > 0 {
-        return commit_updates(&repo);
+    let mut termios: libc::termios = unsafe { mem::zeroed() };
+    let echo = unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } == 0;
+    if echo {
+        let mut hidden = termios;
+        hidden.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &hidden) };
+    }
+    let read = io::stdin().read_line(&mut token);
+    if echo {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) };
     }
+    println!();
 
-    push_master(username, repo)
+    read?;
+    Ok(token)
 }
 
-fn commit_updates(repo: &Repository) -> Result<(), Error> {
-    let head_commit = repo.find_commit(repo.refname_to_id("HEAD")?)?;
+fn test_credentials() -> Result<(), FlakeError> {
+    let config = flake_config()?;
+    let username = github_username(&config)?;
+    let credentials = CredentialStore::from_config(&config, config_dir())
+        .map_err(FlakeError::Config)?;
 
-    let mut index = repo.index()?;
-    index.add_all(&["**/*"], git2::ADD_DEFAULT, None)?;
-    index.write()?;
+    let test_path = env::temp_dir().join(format!("flake-auth-{}", process::id()));
+    let (repo, url) = match Repository::open(store_path().as_path()) {
+        Ok(repo) => {
+            let url = String::from(repo.find_remote("origin")?.url().unwrap_or(""));
+            (repo, url)
+        }
+        Err(_) => {
+            let url = remote_url(dotfiles_url(&config)?)?;
+            (Repository::init_bare(test_path.as_path())?, url)
+        }
+    };
 
-    let oid = index.write_tree()?;
-    let tree = repo.find_tree(oid)?;
+    let asked = Cell::new(false);
+    let timeout = Duration::from_secs(DEFAULT_TIMEOUT_SECS);
+    let deadline = Instant::now() + timeout;
+    let result = {
+        let mut remote = repo.remote_anonymous(&url)?;
+        let mut cb = RemoteCallbacks::new();
+        cb.credentials(|url, _, _| {
+            asked.set(true);
+            git_credentials(&username, &credentials, url)
+        });
+        cb.transfer_progress(|_| Instant::now() < deadline);
+        cb.sideband_progress(|_| Instant::now() < deadline);
 
-    let author = repo.signature()?;
-    repo.commit(Some("HEAD"),
-                &author,
-                &author,
-                "Update files",
-                &tree,
-                &[&head_commit])?;
+        let mut fo = FetchOptions::new();
+        fo.remote_callbacks(cb);
+        fo.proxy_options(proxy_options(configured_proxy(None, &config).as_ref()));
+        remote.fetch(&["refs/flake/auth-test:refs/flake/auth-test"], Some(&mut fo), None)
+            .map_err(|error| timed_out(error, deadline, timeout))
+    };
 
+    if test_path.exists() {
+        let _ = fs::remove_dir_all(test_path.as_path());
+    }
+
+    if let Err(error) = result {
+        return Err(FlakeError::Auth(format!("failed to authenticate with {}: {}", url, error)));
+    }
+
+    if asked.get() {
+        info!("Credentials for {} are working", url);
+    } else {
+        info!("{} didn't ask for credentials to fetch, they will be checked on the first push",
+              url);
+    }
     Ok(())
 }
 
+// The file in the home directory is the one edited, then it's captured and committed alone.
+fn edit(matches: &ArgMatches) -> Result<(), FlakeError> {
+    let editor = env::var("EDITOR")
+        .map_err(|_| FlakeError::Config(String::from("$EDITOR is not set")))?;
 
-fn reset_master(username: &str, repo: &Repository) -> Result<(), Error> {
-    let mut remote = repo.find_remote("origin")?;
-    let mut cb = RemoteCallbacks::new();
-    cb.credentials(|url, _, _| git_credentials(username, url));
+    let syncer = Syncer::new(matches)?;
+    let layout = Layout::load(syncer.repo.workdir().unwrap());
+    let name = layout.store_name(&relative_to_home(matches.value_of("path").unwrap()));
+    if !is_tracked(&syncer.repo, name.as_path())? {
+        return Err(FlakeError::Config(format!("{} is not tracked by flake", name.display())));
+    }
 
-    let mut fo = FetchOptions::new();
-    fo.remote_callbacks(cb);
-    remote.fetch(&[], Some(&mut fo), None)?;
+    syncer.require_up_to_date()?;
 
-    let reference = "refs/remotes/origin/master";
-    let oid = repo.refname_to_id(reference)?;
-    let object = repo.find_object(oid, None)?;
-    repo.reset(&object, git2::ResetType::Hard, None)
-}
+    let target = layout.home_path(&name);
+    let status = process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg("flake-edit")
+        .arg(&target)
+        .status()
+        .map_err(|error| FlakeError::Config(format!("Unable to run `{}`: {}", editor, error)))?;
+    if !status.success() {
+        return Err(FlakeError::Config(format!("`{}` failed with {}, not committing {}",
+                                              editor,
+                                              status,
+                                              name.display())));
+    }
 
-fn push_master(username: &str, repo: &Repository) -> Result<(), Error> {
-    let mut remote = repo.find_remote("origin")?;
-    let mut cb = RemoteCallbacks::new();
-    cb.credentials(|url, _, _| git_credentials(username, url));
+    let repo = &syncer.repo;
+    let workdir = repo.workdir().unwrap();
+    let full_path = workdir.join(&name);
+    let metadata = fs::metadata(&full_path)?;
+    sync_path(&full_path,
+              name.as_path(),
+              &metadata,
+              &Keep::load(workdir),
+              &layout,
+              syncer.options.trust_mtime)?;
 
-    let mut po = PushOptions::new();
-    po.remote_callbacks(cb);
+    let status = repo.status_file(&name)?;
+    if status.is_empty() {
+        info!("{} didn't change", name.display());
+        return Ok(());
+    }
 
-    remote.push(&["refs/remotes/origin/master"], Some(&mut po))
+    let mut index = repo.index()?;
+    let action = stage_change(&mut index, name.as_path(), status)?;
+    let message = format!("{} {}", action, name.display());
+    let modified = if syncer.options.mtime_dates {
+        merge::modified_time(&target)
+    } else {
+        None
+    };
+    commit_index(repo,
+                 &mut index,
+                 &with_trailers(&message, &syncer.options.trailers),
+                 modified)?;
+    syncer.push(false)
+        .map_err(|error| FlakeError::from(error).context("failed to push the repository"))?;
+    info!("Committed {}", name.display());
+    Ok(())
 }
 
-fn sync_files(workdir: &std::path::Path) {
-    let walker = WalkDir::new(workdir)
-        .into_iter()
-        .filter_entry(|e| !is_git_object(e));
+// The rename is committed on its own, with nothing else changing the file, so git detects it and
+// `git log --follow` keeps the history. It's pushed right away, like `edit` does.
+fn move_file(matches: &ArgMatches) -> Result<(), FlakeError> {
+    let syncer = Syncer::new(matches)?;
+    let repo = &syncer.repo;
+    let workdir = repo.workdir().unwrap();
+    let layout = Layout::load(workdir);
+    let force = matches.is_present("force");
+    let store_only = matches.is_present("store-only");
 
-    for entry in walker {
-        let entry = entry.unwrap();
-        if entry.file_type().is_file() {
-            let p = PathBuf::from(entry.path());
-            let name = p.strip_prefix(workdir).unwrap();
+    let from = layout.store_name(&relative_to_home(matches.value_of("from").unwrap()));
+    let to = layout.store_name(&relative_to_home(matches.value_of("to").unwrap()));
+    if !is_tracked(repo, from.as_path())? {
+        return Err(FlakeError::Config(format!("{} is not tracked by flake", from.display())));
+    }
+    if from == to {
+        return Err(FlakeError::Config(format!("{} already has that name", from.display())));
+    }
 
-            if let Err(error) = sync_path(entry.path(), name) {
-                println!("[WARNING] Unable to sync file {}: {}",
-                         name.display(),
-                         error);
-            }
+    let source = workdir.join(&from);
+    let target = workdir.join(&to);
+    let home_source = layout.home_path(&from);
+    let home_target = layout.home_path(&to);
+    let move_home = !store_only && fs::symlink_metadata(&home_source).is_ok();
+    let mut destinations = vec![&target];
+    if move_home {
+        destinations.push(&home_target);
+    }
+    if !force {
+        if let Some(path) = destinations.iter().find(|path| fs::symlink_metadata(path).is_ok()) {
+            return Err(FlakeError::Conflict(format!("{} already exists, use --force to replace \
+                                                     it",
+                                                    path.display())));
         }
     }
-}
 
-fn sync_path(full_path: &std::path::Path,
-             base_path: &std::path::Path)
-             -> Result<(), std::io::Error> {
-    let home = env::home_dir().unwrap();
-    let sync_path = home.join(base_path);
+    syncer.require_up_to_date()?;
 
-    if sync_path.exists() {
-        match fs::copy(home.join(sync_path).as_path(), full_path) {
-            Ok(_) => Ok(()),
-            Err(error) => Err(error),
+    let linked = links_to(&home_source, &source);
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(&source, &target)?;
+    if move_home {
+        if let Some(parent) = home_target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Only reachable with --force, the file it replaces is kept aside like restore does.
+        if fs::symlink_metadata(&home_target).is_ok() {
+            fs::rename(&home_target, backup_path(&home_target))?;
+        }
+        // A link applied with `--symlink` would point to the old name in the repository.
+        if linked {
+            fs::remove_file(&home_source)?;
+            link_path(repo, &to)?;
+        } else {
+            fs::rename(&home_source, &home_target)?;
         }
-    } else {
-        fs::remove_file(full_path)
     }
-}
 
-fn is_git_object(entry: &DirEntry) -> bool {
-    entry.file_name()
-        .to_str()
-        .map(|s| s.starts_with(".git"))
-        .unwrap_or(false)
+    let mut index = repo.index()?;
+    index.remove_path(&from)?;
+    index.add_path(&to)?;
+    let message = format!("Move {} to {}", from.display(), to.display());
+    commit_index(repo,
+                 &mut index,
+                 &with_trailers(&message, &syncer.options.trailers),
+                 None)?;
+    info!("Moved {} to {}", from.display(), to.display());
+    syncer.push(false).map_err(FlakeError::from)
 }
 
-fn git_credentials(username: &str, url: &str) -> Result<git2::Cred, Error> {
-    if url.starts_with("https://") {
-        match SecretService::new(EncryptionType::Dh) {
-            Err(error) => {
-                Err(Error::from_str(format!("Unable to connect with the secret service: {}",
-                                            error)
-                    .as_str()))
-            }
-            Ok(ss) => {
-                match ss.search_items(vec![("github", "access_token")]) {
-                    Err(_) => {
-                        Err(Error::from_str("GitHub credentials are not in the store, use `flake \
-                                         auth` to set them up"))
-                    }
-                    Ok(items) => {
-                        let item = items.get(0).unwrap();
-                        match item.get_secret() {
-                            Err(_) => {
-                                Err(Error::from_str("Missing access token, use `flake auth` to \
-                                                     set it up"))
-                            }
-                            Ok(bytes) => {
-                                let token = String::from_utf8(bytes).unwrap();
-                                git2::Cred::userpass_plaintext(username, token.as_str())
-                            }
-                        }
-                    }
+// Backups sit next to the files they replaced, so only the directories with tracked files in
+// the home directory and the store are searched. Renaming a file keeps its modification time,
+// the age of a backup is the time it was renamed, its ctime.
+fn gc(matches: &ArgMatches) -> Result<(), FlakeError> {
+    use std::os::unix::fs::MetadataExt;
+
+    let repo = open_storage()?;
+    let config = flake_config()?;
+    let age = matches.value_of("older-than")
+        .map(String::from)
+        .or_else(|| config.get_string("flake.gcage").ok())
+        .unwrap_or_else(|| String::from(DEFAULT_GC_AGE));
+    let cutoff = time::get_time().sec -
+                 parse_duration(&age).map_err(FlakeError::Config)?.as_secs() as i64;
+
+    let workdir = repo.workdir().unwrap();
+    let layout = Layout::load(workdir);
+    let mut directories = BTreeSet::new();
+    for name in tracked_files(workdir) {
+        let name = template::target_name(&name);
+        for path in &[layout.home_path(&name), workdir.join(&name)] {
+            if let Some(parent) = path.parent() {
+                directories.insert(PathBuf::from(parent));
+            }
+        }
+    }
+
+    let (mut removed, mut bytes) = (0, 0);
+    for directory in directories {
+        let entries = match fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            if !path.to_string_lossy().ends_with(".flake-bak") {
+                continue;
+            }
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            if !metadata.is_file() || metadata.ctime() >= cutoff {
+                continue;
+            }
+
+            match fs::remove_file(&path) {
+                Ok(_) => {
+                    debug!(target: logger::FILES, "Removed {}", path.display());
+                    removed += 1;
+                    bytes += metadata.len();
                 }
+                Err(error) => warn!("Unable to remove {}: {}", path.display(), error),
             }
         }
-    } else {
-        let home = env::home_dir().unwrap();
-        let private_key = home.join(".ssh/id_rsa");
-        let public_key = home.join(".ssh/id_rsa.pub");
+    }
 
-        git2::Cred::ssh_key("git",
-                            Some(public_key.as_path()),
-                            private_key.as_path(),
-                            None)
+    info!("Removed {} backups older than {}, reclaiming {} bytes", removed, age, bytes);
+    Ok(())
+}
+
+fn log(matches: &ArgMatches) -> Result<(), FlakeError> {
+    let repo = open_storage()?;
+    let since = match matches.value_of("since") {
+        Some(value) => Some(parse_since(value)?),
+        None => None,
+    };
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push_head()?;
+    revwalk.set_sorting(git2::SORT_TIME);
+
+    for oid in revwalk {
+        let mut commit = repo.find_commit(oid?)?;
+        let seconds = commit.time().seconds();
+        if since.map(|since| seconds < since).unwrap_or(false) {
+            break;
+        }
+
+        let date = time::at(time::Timespec::new(seconds, 0));
+        println!("{:.7} {} {}",
+                 commit.id().to_string(),
+                 date.strftime("%Y-%m-%d %H:%M").unwrap(),
+                 commit.summary().unwrap_or(""));
+    }
+
+    Ok(())
+}
+
+fn prune_history(matches: &ArgMatches) -> Result<(), FlakeError> {
+    if !matches.is_present("force") {
+        return Err(FlakeError::Config(String::from("prune-history rewrites the history of your \
+                                                    repository and force pushes it, pass \
+                                                    --force to do it")));
+    }
+    let cutoff = match matches.value_of("before") {
+        Some(value) => Some(parse_since(value)?),
+        None => None,
+    };
+
+    let syncer = Syncer::new(matches)?;
+    syncer.require_up_to_date()?;
+
+    let repo = &syncer.repo;
+    let old_head = repo.refname_to_id("HEAD")?;
+    let keep_identity = matches.is_present("author-from-commit");
+    let (new_head, squashed) = match history::squash(repo, cutoff, keep_identity)? {
+        Some(result) => result,
+        None => {
+            info!("There are no old commits to squash");
+            return Ok(());
+        }
+    };
+
+    warn!("Rewriting the history of your dotfiles, every other machine will need to clone the \
+           repository again");
+    let backup = format!("refs/flake/backup/{}", time::get_time().sec);
+    repo.reference(&backup, old_head, false, "flake: backup before prune-history")?;
+
+    let head = repo.head()?;
+    let name = head.name().unwrap_or("HEAD");
+    repo.reference(name, new_head, true, "flake: prune-history")?;
+
+    // The remote keeps the old history, so the store goes back to it until the next try.
+    if let Err(error) = syncer.push(true) {
+        repo.reference(name, old_head, true, "flake: undo prune-history")?;
+        return Err(FlakeError::from(error).context("failed to push the new history"));
+    }
+    info!("Squashed {} commits. The old history is saved in {}", squashed, backup);
+    Ok(())
+}
+
+fn parse_since(value: &str) -> Result<i64, FlakeError> {
+    if let Ok(duration) = parse_duration(value) {
+        return Ok(time::get_time().sec - duration.as_secs() as i64);
+    }
+
+    time::strptime(value, "%Y-%m-%d")
+        .map(|date| date.to_timespec().sec)
+        .map_err(|_| {
+            FlakeError::Config(format!("invalid --since value `{}`, use a duration like 2d or a \
+                                        date like 2017-01-31",
+                                       value))
+        })
+}
+
+fn stats(matches: &ArgMatches) -> Result<(), FlakeError> {
+    let repo = open_storage()?;
+    let profile = profile(matches, &flake_config()?)?;
+    let files = tracked_files(repo.workdir().unwrap());
+    let last_sync = State::load(&repo, profile.as_ref()).last_sync;
+    let stats = Stats::collect(&repo, files, last_sync)?;
+
+    if matches.is_present("json") {
+        println!("{}", stats.to_json().pretty());
+        return Ok(());
+    }
+
+    println!("Files:     {} ({} bytes)", stats.files, stats.bytes);
+    println!("Commits:   {}", stats.commits);
+    match stats.last_sync {
+        Some(last_sync) => {
+            let elapsed = (time::get_time().sec - last_sync).max(0) as u64;
+            println!("Last sync: {} ago", humanize(elapsed));
+        }
+        None => println!("Last sync: never"),
+    }
+
+    println!("\nLargest files:");
+    for &(ref name, size) in &stats.largest {
+        println!("{:>12} {}", size, name.display());
+    }
+
+    println!("\nDirectories:");
+    for (name, &(files, bytes)) in &stats.directories {
+        println!("{:>12} {} ({} files)", bytes, name, files);
+    }
+    Ok(())
+}
+
+fn status(matches: &ArgMatches) -> Result<(), FlakeError> {
+    let repo = open_storage()?;
+    let profile = profile(matches, &flake_config()?)?;
+    if matches.is_present("short") {
+        println!("{}", short_status(&repo, profile.as_ref())?);
+        return Ok(());
+    }
+
+    match State::load(&repo, profile.as_ref()).last_sync {
+        Some(last_sync) => {
+            let elapsed = (time::get_time().sec - last_sync).max(0) as u64;
+            println!("Last sync: {} ago", humanize(elapsed));
+        }
+        None => println!("Last sync: never"),
+    }
+
+    if matches.is_present("remote") {
+        remote_status(matches)?;
+    }
+
+    let changes = drift(&repo)?;
+    for &(ref name, change) in &changes {
+        println!("{} {}", color::change(change), name.display());
+    }
+
+    if changes.is_empty() {
+        println!("Everything is up to date");
+    }
+    Ok(())
+}
+
+// Fast enough for a prompt: the distance to the remote is the one of the last fetch.
+fn short_status(repo: &Repository, profile: Option<&String>) -> Result<String, FlakeError> {
+    if !drift(repo)?.is_empty() {
+        return Ok(String::from("✗ dirty"));
+    }
+    if State::load(repo, profile).offline {
+        return Ok(String::from("⚠ offline"));
+    }
+
+    // The same branches a sync uses: the working branch is pushed to flake.branch, or to the
+    // remote's default branch, whatever their names.
+    let config = flake_config()?;
+    let branch = config.get_string("flake.branch")
+        .ok()
+        .or_else(|| {
+            repo.find_remote("origin").ok().and_then(|remote| remote_head_branch(repo, &remote))
+        })
+        .unwrap_or_else(|| String::from(DEFAULT_BRANCH));
+    let local = match config.get_string("flake.localbranch") {
+        Ok(local) => {
+            repo.refname_to_id(&format!("refs/heads/{}", local))
+                .or_else(|_| repo.refname_to_id("HEAD"))?
+        }
+        Err(_) => repo.refname_to_id("HEAD")?,
+    };
+    let (ahead, behind) = match repo.refname_to_id(&format!("refs/remotes/origin/{}", branch)) {
+        Ok(remote) => repo.graph_ahead_behind(local, remote)?,
+        // The branch was never pushed, every commit is waiting for it.
+        Err(_) => {
+            let mut revwalk = repo.revwalk()?;
+            revwalk.push(local)?;
+            (revwalk.count(), 0)
+        }
+    };
+    let mut distance = Vec::new();
+    if ahead > 0 {
+        distance.push(format!("↑{}", ahead));
+    }
+    if behind > 0 {
+        distance.push(format!("↓{}", behind));
+    }
+
+    if distance.is_empty() {
+        Ok(String::from("✓ synced"))
+    } else {
+        Ok(distance.join(" "))
+    }
+}
+
+// Only fetches, the files and the local branch are left as they are.
+fn remote_status(matches: &ArgMatches) -> Result<(), FlakeError> {
+    let syncer = Syncer::new(matches)?;
+    let behind = syncer.fetch_only()
+        .map_err(|error| FlakeError::from(error).context("failed to fetch the repository"))?;
+
+    let repo = &syncer.repo;
+    let branch = syncer.branch();
+    let local = repo.refname_to_id("HEAD")?;
+    let remote = repo.refname_to_id(&format!("refs/remotes/origin/{}", branch))?;
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(local)?;
+    revwalk.hide(remote)?;
+    let ahead = revwalk.count();
+    println!("Remote:    {} commits behind and {} ahead of origin/{}",
+             behind,
+             ahead,
+             branch);
+
+    let local_tree = repo.find_commit(local)?.tree()?;
+    let remote_tree = repo.find_commit(remote)?.tree()?;
+    let diff = repo.diff_tree_to_tree(Some(&local_tree), Some(&remote_tree), None)?;
+    for delta in diff.deltas() {
+        let change = match delta.status() {
+            git2::Delta::Added => "added",
+            git2::Delta::Deleted => "deleted",
+            _ => "modified",
+        };
+        if let Some(name) = delta.new_file().path().or_else(|| delta.old_file().path()) {
+            println!("{} {} (remote)", color::change(change), name.display());
+        }
+    }
+    Ok(())
+}
+
+fn completions(matches: &ArgMatches) -> Result<(), FlakeError> {
+    let shell = matches.value_of("shell").unwrap().parse::<Shell>().map_err(FlakeError::Config)?;
+    app().gen_completions_to("flake", shell, &mut io::stdout());
+    Ok(())
+}
+
+fn check(_: &ArgMatches) -> Result<(), FlakeError> {
+    let repo = open_storage()?;
+
+    let changes = drift(&repo)?;
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    for &(ref name, change) in &changes {
+        println!("{} {}", color::change(change), name.display());
+    }
+    Err(FlakeError::Drift(format!("{} files differ from the repository", changes.len())))
+}
+
+// Compares the files on disk with the repository without capturing anything.
+fn drift(repo: &Repository) -> Result<Vec<(PathBuf, &'static str)>, io::Error> {
+    let workdir = repo.workdir().unwrap();
+    let layout = Layout::load(workdir);
+    let keep = Keep::load(workdir);
+
+    let mut changes = Vec::new();
+    for name in tracked_files(workdir).into_iter().filter(|n| !template::is_template(n)) {
+        let target = layout.home_path(&name);
+        if !target.exists() {
+            if !keep.is_kept(&name) {
+                changes.push((name, "deleted"));
+            }
+        } else if files_differ(workdir.join(&name).as_path(), target.as_path())? {
+            changes.push((name, "modified"));
+        }
+    }
+    Ok(changes)
+}
+
+// For scripts and editors, which only need the answer: any package of the subdir layout counts.
+fn tracked(matches: &ArgMatches) -> Result<(), FlakeError> {
+    let repo = open_storage()?;
+
+    let layout = Layout::load(repo.workdir().unwrap());
+    let name = relative_to_home(matches.value_of("path").unwrap());
+    for store_name in layout.store_names(&name) {
+        if is_tracked(&repo, &store_name)? {
+            println!("yes");
+            return Ok(());
+        }
+    }
+    println!("no");
+    Err(FlakeError::NotTracked(format!("{} is not tracked by flake", name.display())))
+}
+
+fn restore(matches: &ArgMatches) -> Result<(), FlakeError> {
+    let repo = open_storage()?;
+
+    let layout = Layout::load(repo.workdir().unwrap());
+    let name = layout.store_name(&relative_to_home(matches.value_of("path").unwrap()));
+    if !is_tracked(&repo, name.as_path())? {
+        return Err(FlakeError::Config(format!("{} is not tracked by flake", name.display())));
+    }
+
+    restore_path(&repo, name.as_path(), matches.is_present("backup")).map_err(|error| {
+        FlakeError::from(error).context(&format!("failed to restore {}", name.display()))
+    })
+}
+
+fn store(matches: &ArgMatches) -> Result<(), FlakeError> {
+    match matches.subcommand() {
+        ("move", Some(move_matches)) => move_store(move_matches),
+        _ => {
+            println!("Please, run flake store with the `move` subcommand");
+            Ok(())
+        }
+    }
+}
+
+fn move_store(matches: &ArgMatches) -> Result<(), FlakeError> {
+    let old_path = store_path();
+    if !old_path.is_dir() {
+        return Err(FlakeError::Config(format!("there is no repository at {}",
+                                              old_path.display())));
+    }
+
+    let new_path = env::current_dir()?.join(matches.value_of("path").unwrap());
+    if new_path.exists() {
+        return Err(FlakeError::Config(format!("{} already exists", new_path.display())));
+    }
+    if let Some(parent) = new_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if let Err(error) = fs::rename(old_path.as_path(), new_path.as_path()) {
+        if error.raw_os_error() != Some(libc::EXDEV) {
+            return Err(FlakeError::from(error).context("failed to move the repository"));
+        }
+
+        copy_dir(old_path.as_path(), new_path.as_path())
+            .map_err(|error| FlakeError::from(error).context("failed to copy the repository"))?;
+        fs::remove_dir_all(old_path.as_path())?;
+    }
+
+    open_repository(new_path.as_path())?;
+
+    let mut config = git2::Config::open_default()?;
+    config.set_str("flake.store", new_path.to_str().unwrap())?;
+    if env::var_os("FLAKE_STORE").is_some() {
+        warn!("FLAKE_STORE is set in your environment, update it to point to {}",
+              new_path.display());
+    }
+
+    info!("Moved repository from {} to {}", old_path.display(), new_path.display());
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<(), io::Error> {
+    for entry in WalkDir::new(from) {
+        let entry = entry?;
+        let target = to.join(entry.path().strip_prefix(from).unwrap());
+
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(target.as_path())?;
+        } else {
+            fs::copy(entry.path(), target.as_path())?;
+        }
+    }
+    Ok(())
+}
+
+fn sync(matches: &ArgMatches) -> Result<(), FlakeError> {
+    let syncer = Syncer::new(matches)?;
+    if matches.is_present("update-remote-tracking") {
+        let behind = syncer.fetch_only()
+            .map_err(|error| FlakeError::from(error).context("failed to fetch the repository"))?;
+        match behind {
+            0 => info!("The local repository is up to date"),
+            1 => info!("The local repository is 1 commit behind the remote"),
+            behind => info!("The local repository is {} commits behind the remote", behind),
+        }
+        return Ok(());
+    }
+    if matches.is_present("dump-plan") {
+        println!("{}", syncer.plan()?.pretty());
+        return Ok(());
+    }
+
+    syncer.sync_repo().map_err(|error| error.context("failed the initial sync"))?;
+
+    if matches.is_present("once") {
+        return Ok(());
+    }
+
+    let interval = match matches.value_of("interval") {
+        Some(value) => parse_duration(value).map_err(FlakeError::Config)?,
+        None => Duration::from_secs(1800),
+    };
+    let tick = schedule_recv::periodic(interval);
+    loop {
+        tick.recv().unwrap();
+
+        syncer.sync_repo().map_err(|error| error.context("failed the sync repository"))?;
+    }
+}
+
+fn watch(matches: &ArgMatches) -> Result<(), FlakeError> {
+    let syncer = Syncer::new(matches)?;
+    syncer.sync_repo().map_err(|error| error.context("failed the initial sync"))?;
+
+    let config = flake_config()?;
+    let debounce = matches.value_of("watch-debounce")
+        .map(String::from)
+        .or_else(|| config.get_string("flake.watchdebounce").ok())
+        .unwrap_or_else(|| String::from(DEFAULT_WATCH_DEBOUNCE));
+    let debounce = parse_duration(&debounce).map_err(FlakeError::Config)?;
+
+    let mut globs = WATCH_JUNK.to_vec();
+    if let Some(values) = matches.values_of("watch-ignore") {
+        globs.extend(values);
+    }
+    let mut ignored = Vec::new();
+    for glob in globs {
+        let pattern = Pattern::new(glob)
+            .map_err(|error| FlakeError::Config(format!("invalid glob `{}`: {}", glob, error)))?;
+        ignored.push(pattern);
+    }
+
+    let (tx, rx) = channel();
+    let mut watcher = notify::watcher(tx, debounce).map_err(|error| {
+            FlakeError::Io(format!("failed to start the file watcher: {}", error))
+        })?;
+
+    let mut watched = BTreeSet::new();
+    let mut tracked = watch_tracked_files(&mut watcher, &syncer.repo, &mut watched);
+
+    let interval = match matches.value_of("interval") {
+        Some(value) => Some(parse_duration(value).map_err(FlakeError::Config)?),
+        None => None,
+    };
+    let home = env::home_dir().unwrap();
+    let mut last_sync = Instant::now();
+    loop {
+        let event = match interval {
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            Some(interval) => {
+                let elapsed = last_sync.elapsed();
+                let timeout = if elapsed < interval {
+                    interval - elapsed
+                } else {
+                    Duration::from_secs(0)
+                };
+                rx.recv_timeout(timeout)
+            }
+        };
+
+        let changed = match event {
+            Ok(event) => is_watched_change(&event, &tracked, &ignored, &home),
+            Err(RecvTimeoutError::Timeout) => true,
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(FlakeError::Io(String::from("the file watcher stopped unexpectedly")));
+            }
+        };
+
+        if !changed {
+            continue;
+        }
+
+        syncer.sync_repo().map_err(|error| error.context("failed the sync repository"))?;
+        last_sync = Instant::now();
+        tracked = watch_tracked_files(&mut watcher, &syncer.repo, &mut watched);
+    }
+}
+
+fn is_watched_change(event: &DebouncedEvent,
+                     tracked: &BTreeSet<PathBuf>,
+                     ignored: &[Pattern],
+                     home: &Path)
+                     -> bool {
+    match *event {
+        DebouncedEvent::Create(ref path) |
+        DebouncedEvent::Write(ref path) |
+        DebouncedEvent::Chmod(ref path) |
+        DebouncedEvent::Remove(ref path) |
+        DebouncedEvent::Rename(_, ref path) => {
+            tracked.contains(path) && !is_watch_ignored(ignored, home, path)
+        }
+        DebouncedEvent::Rescan => true,
+        _ => false,
+    }
+}
+
+// Patterns without a slash match the file name, the others the path relative to home.
+fn is_watch_ignored(patterns: &[Pattern], home: &Path, path: &Path) -> bool {
+    let name = path.strip_prefix(home).unwrap_or(path);
+    patterns.iter().any(|pattern| {
+        pattern.matches_path(name) ||
+        (!pattern.as_str().contains('/') &&
+         path.file_name().map(|file| pattern.matches_path(Path::new(file))).unwrap_or(false))
+    })
+}
+
+fn watch_tracked_files(watcher: &mut RecommendedWatcher,
+                       repo: &Repository,
+                       watched: &mut BTreeSet<PathBuf>)
+                       -> BTreeSet<PathBuf> {
+    let layout = Layout::load(repo.workdir().unwrap());
+    let tracked: BTreeSet<PathBuf> = tracked_files(repo.workdir().unwrap())
+        .iter()
+        .map(|name| layout.home_path(name))
+        .collect();
+
+    for path in &tracked {
+        if let Some(parent) = path.parent() {
+            if !watched.contains(parent) && parent.exists() {
+                match watcher.watch(parent, RecursiveMode::NonRecursive) {
+                    Err(error) => {
+                        warn!("Unable to watch directory {}: {}",
+                              parent.display(),
+                              error);
+                    }
+                    Ok(_) => {
+                        watched.insert(PathBuf::from(parent));
+                    }
+                }
+            }
+        }
+    }
+
+    tracked
+}
+
+fn doctor(matches: &ArgMatches) -> Result<(), FlakeError> {
+    use doctor::Status;
+
+    let config = flake_config()?;
+    let mut checks = Vec::new();
+
+    checks.push(match github_username(&config) {
+        Ok(username) => Check::ok("username", format!("GitHub username is {}", username)),
+        Err(_) => {
+            Check::failed("username",
+                          Status::Error,
+                          String::from("GitHub username is not set"),
+                          "Run `git config --global github.username USERNAME`")
+        }
+    });
+
+    let url = dotfiles_url(&config).ok().map(String::from);
+    checks.push(match url {
+        Some(ref url) => Check::ok("repository", format!("Dotfiles repository is {}", url)),
+        None => {
+            Check::failed("repository",
+                          Status::Error,
+                          String::from("Dotfiles repository is not set"),
+                          "Run `git config --global github.dotfiles URL`")
+        }
+    });
+
+    let store = store_path();
+    checks.push(match open_storage() {
+        Ok(_) => Check::ok("store", format!("Local repository at {}", store.display())),
+        Err(ref error) if store.exists() => {
+            Check::failed("store",
+                          Status::Error,
+                          format!("{}", error),
+                          "Move it away and run `flake sync` to clone it again")
+        }
+        Err(_) => {
+            Check::failed("store",
+                          Status::Warning,
+                          format!("There is no local repository at {}", store.display()),
+                          "Run `flake sync` to clone it")
+        }
+    });
+
+    checks.push(match (config.get_string("user.name"), config.get_string("user.email")) {
+        (Ok(name), Ok(email)) => {
+            Check::ok("identity", format!("Committing as {} <{}>", name, email))
+        }
+        _ => {
+            Check::failed("identity",
+                          Status::Error,
+                          String::from("Git doesn't know who to commit as"),
+                          "Run `git config --global user.name NAME` and `git config --global \
+                           user.email EMAIL`")
+        }
+    });
+
+    checks.push(credentials_check(&config, url.as_ref()));
+
+    if matches.value_of("format") == Some("json") {
+        doctor::print_json(&checks);
+    } else {
+        doctor::print_text(&checks);
+    }
+
+    let failed = checks.iter().filter(|check| check.status == Status::Error).count();
+    if failed > 0 {
+        return Err(FlakeError::Config(format!("{} checks failed", failed)));
+    }
+    Ok(())
+}
+
+fn verify(matches: &ArgMatches) -> Result<(), FlakeError> {
+    use doctor::Status;
+
+    let repo = open_storage()?;
+    if matches.is_present("repair") {
+        verify::repair(&repo).map_err(|error| FlakeError::from(error).context("Unable to repair"))?;
+        info!("Reset the local repository to the last commit");
+    }
+
+    let checks = verify::verify(&repo);
+    doctor::print_text(&checks);
+
+    let failed = checks.iter().filter(|check| check.status != Status::Ok).count();
+    if failed > 0 {
+        return Err(FlakeError::Git(format!("{} checks found problems", failed)));
+    }
+    Ok(())
+}
+
+// Resolves the settings like `sync` does, without touching anything.
+fn whoami(matches: &ArgMatches) -> Result<(), FlakeError> {
+    let scope = matches.value_of("config-scope");
+    let config = scoped_config(scope)?;
+
+    let mut settings = Vec::new();
+    settings.push(("username", config_setting(&config, "github.username")));
+    settings.push(("repository",
+                   match matches.value_of("repository") {
+                       Some(url) => Some((String::from(url), String::from("flag"))),
+                       None => config_setting(&config, "github.dotfiles"),
+                   }));
+    settings.push(("branch",
+                   match matches.value_of("branch") {
+                       Some(branch) => Some((String::from(branch), String::from("flag"))),
+                       None => {
+                           config_setting(&config, "flake.branch").or_else(|| {
+                               Some((String::from("the remote's default branch"),
+                                     String::from("default")))
+                           })
+                       }
+                   }));
+    settings.push(("local branch",
+                   match matches.value_of("local-branch") {
+                       Some(branch) => Some((String::from(branch), String::from("flag"))),
+                       None => {
+                           config_setting(&config, "flake.localbranch").or_else(|| {
+                               Some((String::from("the store's current branch"),
+                                     String::from("default")))
+                           })
+                       }
+                   }));
+
+    let store = configured_store_path(Some(&config)).to_string_lossy().into_owned();
+    let store_source = if env::var_os("FLAKE_STORE").is_some() {
+        String::from("env FLAKE_STORE")
+    } else {
+        config_setting(&config, "flake.store")
+            .map(|(_, source)| source)
+            .unwrap_or_else(|| String::from("default"))
+    };
+    settings.push(("store", Some((store, store_source))));
+
+    let credentials = CredentialStore::from_config(&config, config_dir())
+        .map_err(FlakeError::Config)?;
+    let backend = match credentials {
+        CredentialStore::SecretService { ref label, collection: Some(ref collection) } => {
+            format!("secret-service ({} in {})", label, collection)
+        }
+        CredentialStore::SecretService { ref label, .. } => format!("secret-service ({})", label),
+        CredentialStore::Gpg { ref path, .. } |
+        CredentialStore::File { ref path } => {
+            format!("{} ({})", credentials.name(), path.display())
+        }
+    };
+    let backend_source = config_setting(&config, "flake.credentials")
+        .map(|(_, source)| source)
+        .unwrap_or_else(|| String::from("default"));
+    settings.push(("credentials", Some((backend, backend_source))));
+
+    if matches.is_present("json") {
+        let mut object = BTreeMap::new();
+        for &(name, ref setting) in &settings {
+            let mut entry = BTreeMap::new();
+            if let Some((ref value, ref source)) = *setting {
+                entry.insert(String::from("value"), value.to_json());
+                entry.insert(String::from("source"), source.to_json());
+            }
+            object.insert(String::from(name), Json::Object(entry));
+        }
+        println!("{}", Json::Object(object).pretty());
+        return Ok(());
+    }
+
+    for &(name, ref setting) in &settings {
+        match *setting {
+            Some((ref value, ref source)) => println!("{:12} {} ({})", name, value, source),
+            None => println!("{:12} not set", name),
+        }
+    }
+    Ok(())
+}
+
+// A setting's value and the configuration it comes from.
+fn config_setting(config: &git2::Config, name: &str) -> Option<(String, String)> {
+    let entry = config.get_entry(name).ok()?;
+    let value = entry.value()?;
+    let source = match entry.level() {
+        git2::ConfigLevel::System | git2::ConfigLevel::ProgramData => "system config",
+        git2::ConfigLevel::XDG | git2::ConfigLevel::Global => "global config",
+        git2::ConfigLevel::Local => "repository config",
+        git2::ConfigLevel::App => "flake config",
+        git2::ConfigLevel::Highest => "config",
+    };
+    Some((String::from(value), format!("{} {}", source, name)))
+}
+
+fn credentials_check(config: &git2::Config, url: Option<&String>) -> Check {
+    use doctor::Status;
+
+    let url = match url {
+        Some(url) => url,
+        None => {
+            return Check::failed("credentials",
+                                 Status::Warning,
+                                 String::from("Skipped, there is no repository to authenticate \
+                                               with"),
+                                 "Set the dotfiles repository first")
+        }
+    };
+
+    if is_local_url(url) {
+        return Check::ok("credentials",
+                         String::from("Local repositories don't need credentials"));
+    }
+
+    if !url.starts_with("https://") {
+        let key = env::home_dir().unwrap().join(".ssh/id_rsa");
+        if key.exists() {
+            return Check::ok("credentials", format!("Using the SSH key {}", key.display()));
+        }
+        return Check::failed("credentials",
+                             Status::Error,
+                             format!("{} doesn't exist", key.display()),
+                             "Create an SSH key with `ssh-keygen` and add it to GitHub");
+    }
+
+    let store = match CredentialStore::from_config(config, config_dir()) {
+        Ok(store) => store,
+        Err(error) => {
+            return Check::failed("credentials",
+                                 Status::Error,
+                                 error,
+                                 "Run `flake auth --cred-backend BACKEND TOKEN` with a valid \
+                                  backend");
+        }
+    };
+
+    match store.token(None) {
+        Ok(_) => {
+            Check::ok("credentials",
+                      format!("Access token available in the {} backend", store.name()))
+        }
+        Err(error) => {
+            Check::failed("credentials",
+                          Status::Error,
+                          error,
+                          "Run `flake auth TOKEN` to store a GitHub access token")
+        }
+    }
+}
+
+fn dotfiles_url(config: &git2::Config) -> Result<&str, FlakeError> {
+    config.get_str("github.dotfiles").map_err(|error| {
+        FlakeError::Config(format!("repository url not provided, use `git config --global --add \
+                                    github.dotfiles URL` to set a default repository: {}",
+                                   error))
+    })
+}
+
+fn github_username(config: &git2::Config) -> Result<String, FlakeError> {
+    config.get_str("github.username").map(String::from).map_err(|error| {
+        FlakeError::Config(format!("GitHub username not provided, use `git config --global --add \
+                                    github.username USERNAME` to set your username: {}",
+                                   error))
+    })
+}
+
+fn store_path() -> PathBuf {
+    match flake_config() {
+        Ok(config) => configured_store_path(Some(&config)),
+        Err(_) => configured_store_path(None),
+    }
+}
+
+fn configured_store_path(config: Option<&git2::Config>) -> PathBuf {
+    if let Some(store) = env::var_os("FLAKE_STORE") {
+        return PathBuf::from(store);
+    }
+    if let Some(store) = config.and_then(|config| config.get_path("flake.store").ok()) {
+        return store;
+    }
+
+    let legacy = env::home_dir().unwrap().join(STORE_NAME);
+    match env::var_os("XDG_DATA_HOME") {
+        Some(ref data_home) if !legacy.exists() && !data_home.is_empty() => {
+            Path::new(data_home).join("flake").join("store")
+        }
+        _ => legacy,
+    }
+}
+
+fn config_dir() -> PathBuf {
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(ref config_home) if !config_home.is_empty() => Path::new(config_home).join("flake"),
+        _ => env::home_dir().unwrap().join(".config").join("flake"),
+    }
+}
+
+fn scoped_config(scope: Option<&str>) -> Result<git2::Config, Error> {
+    let path = match scope {
+        None => return flake_config(),
+        Some("global") => git2::Config::find_global()?,
+        Some("system") => git2::Config::find_system()?,
+        Some(path) => PathBuf::from(path),
+    };
+    git2::Config::open(path.as_path())?.snapshot()
+}
+
+// The default git configuration, plus the local one of the repository flake runs from, if any,
+// which wins over the global one like in git.
+// The profile from --profile or the flake.profile setting, None for the default one.
+fn profile(matches: &ArgMatches, config: &git2::Config) -> Result<Option<String>, FlakeError> {
+    let profile = matches.value_of("profile")
+        .map(String::from)
+        .or_else(|| config.get_string("flake.profile").ok());
+    if let Some(ref name) = profile {
+        state::check_profile(name).map_err(FlakeError::Config)?;
+    }
+    Ok(profile)
+}
+
+fn flake_config() -> Result<git2::Config, Error> {
+    let mut config = git2::Config::open_default()?;
+
+    if let Ok(repo) = Repository::discover(".") {
+        let local = repo.path().join("config");
+        if local.is_file() {
+            config.add_file(local.as_path(), git2::ConfigLevel::Local, false)?;
+        }
+    }
+
+    let path = config_dir().join("config");
+    if path.is_file() {
+        config.add_file(path.as_path(), git2::ConfigLevel::App, false)?;
+    }
+    config.snapshot()
+}
+
+fn init_storage(url: &str,
+                config: &git2::Config,
+                username: &str,
+                credentials: &CredentialStore,
+                proxy: Option<&String>)
+                -> Result<(Repository, bool), FlakeError> {
+    let storage = configured_store_path(Some(config));
+
+    if storage.exists() {
+        if storage.is_file() {
+            return Err(FlakeError::Config(format!("{} is a file!", storage.to_string_lossy())));
+        }
+
+        return Ok((non_bare(Repository::open(storage.as_path())?)?, false));
+    }
+    if let Some(parent) = storage.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // No deadline, the first clone can take a while on a slow connection. libgit2 asks for
+    // credentials again every time the remote rejects them, so the stored ones get one try.
+    let mut asked = false;
+    let mut cb = RemoteCallbacks::new();
+    cb.credentials(move |url, _, _| {
+        if asked {
+            return Err(Error::from_str("the remote rejected the stored credentials, run `flake \
+                                        auth` to save new ones"));
+        }
+        asked = true;
+        git_credentials(username, credentials, url)
+    });
+
+    let mut fo = FetchOptions::new();
+    fo.remote_callbacks(cb);
+    fo.proxy_options(proxy_options(proxy));
+    let repo = RepoBuilder::new()
+        .bare(false)
+        .fetch_options(fo)
+        .clone(&remote_url(url)?, storage.as_path())?;
+    Ok((repo, true))
+}
+
+// Local repositories can be given as plain paths, relative to the current directory or to
+// the home directory with `~/`. They're saved as absolute paths so the store can find them.
+fn remote_url(url: &str) -> Result<String, FlakeError> {
+    if url.contains("://") || is_scp_url(url) {
+        return Ok(String::from(url));
+    }
+
+    let path = if url.starts_with("~/") {
+        env::home_dir().unwrap().join(url.replacen("~/", "", 1))
+    } else {
+        PathBuf::from(url)
+    };
+    let path = fs::canonicalize(&path).map_err(|error| {
+            FlakeError::Config(format!("{} is not a valid repository url or path: {}", url, error))
+        })?;
+    Ok(path.to_string_lossy().into_owned())
+}
+
+// Git's scp-like syntax, `user@host:path`. A colon after a slash is part of a path.
+fn is_scp_url(url: &str) -> bool {
+    match url.find(':') {
+        Some(colon) => !url[..colon].contains('/'),
+        None => false,
+    }
+}
+
+// The host and port to reach a remote url, like `https://host/path` or `user@host:path`.
+fn remote_address(url: &str) -> Option<(String, u16)> {
+    if is_scp_url(url) && !url.contains("://") {
+        let host = url[..url.find(':').unwrap()].rsplit('@').next().unwrap();
+        return Some((String::from(host), 22));
+    }
+
+    let scheme = url.find("://")?;
+    let default_port = match &url[..scheme] {
+        "https" => 443,
+        "http" => 80,
+        "ssh" => 22,
+        "git" => 9418,
+        _ => return None,
+    };
+    let rest = &url[scheme + 3..];
+    let authority = rest.split('/').next().unwrap().rsplit('@').next().unwrap();
+    match authority.rfind(':') {
+        Some(colon) if !authority.ends_with(']') => {
+            let port = authority[colon + 1..].parse().ok()?;
+            Some((String::from(&authority[..colon]), port))
+        }
+        _ => Some((String::from(authority), default_port)),
+    }
+}
+
+// Urls of an unknown kind are assumed to be reachable, the fetch reports the real problem.
+fn is_reachable(url: &str, timeout: Duration) -> bool {
+    if is_local_url(url) {
+        return true;
+    }
+
+    let (host, port) = match remote_address(url) {
+        Some(address) => address,
+        None => return true,
+    };
+    match (host.as_str(), port).to_socket_addrs() {
+        Ok(addresses) => {
+            addresses.into_iter()
+                .any(|address| TcpStream::connect_timeout(&address, timeout).is_ok())
+        }
+        Err(error) => {
+            debug!("Unable to resolve {}: {}", host, error);
+            false
+        }
+    }
+}
+
+fn is_local_url(url: &str) -> bool {
+    url.starts_with("file://") || !(url.contains("://") || is_scp_url(url))
+}
+
+fn open_storage() -> Result<Repository, FlakeError> {
+    open_repository(store_path().as_path())
+}
+
+fn open_repository(path: &Path) -> Result<Repository, FlakeError> {
+    let repo = Repository::open(path).map_err(|error| {
+            FlakeError::Config(format!("failed to open repository, run `flake sync` first: {}",
+                                       error))
+        })?;
+    non_bare(repo)
+}
+
+fn non_bare(repo: Repository) -> Result<Repository, FlakeError> {
+    if repo.is_bare() || repo.workdir().is_none() {
+        return Err(FlakeError::Config(format!("flake requires a non-bare repository at {}",
+                                              repo.path().display())));
+    }
+    Ok(repo)
+}
+
+struct Syncer {
+    username: String,
+    credentials: CredentialStore,
+    repo: Repository,
+    options: SyncOptions,
+    // The branch detected from the remote's HEAD, cached for the whole session.
+    branch: RefCell<Option<String>>,
+    // Whether the store was cloned and never synced or applied since.
+    first_sync: Cell<bool>,
+}
+
+impl Syncer {
+    fn new(matches: &ArgMatches) -> Result<Syncer, FlakeError> {
+        let scope = matches.value_of("config-scope");
+        let config = scoped_config(scope).map_err(|error| {
+                FlakeError::Config(format!("failed to read the {} configuration: {}",
+                                           scope.unwrap_or("default"),
+                                           error))
+            })?;
+        let url = match matches.value_of("repository") {
+            None => dotfiles_url(&config)?,
+            Some(r) => r,
+        };
+        let username = github_username(&config)?;
+        let credentials = CredentialStore::from_config(&config, config_dir())
+            .map_err(FlakeError::Config)?;
+
+        let profile = profile(matches, &config)?;
+        let filter = match matches.value_of("filter")
+            .map(String::from)
+            .or_else(|| config.get_string("flake.filter").ok()) {
+            Some(filter) => {
+                check_filter(&filter).map_err(FlakeError::Config)?;
+                Some(filter)
+            }
+            None => None,
+        };
+        let proxy = configured_proxy(matches.value_of("proxy"), &config);
+        let (repo, cloned) = init_storage(url, &config, &username, &credentials, proxy.as_ref())
+            .map_err(|error| error.context("failed to open repository"))?;
+        // Remembered until the first sync succeeds, a sync that can't ask right after cloning
+        // mustn't let the next one capture the home directory without asking.
+        if let (true, Some(filter)) = (cloned, filter) {
+            warn!("Cloned the whole repository, libgit2 can't make partial clones with --filter {}",
+                  filter);
+        }
+        if cloned {
+            let mut state = State::load(&repo, profile.as_ref());
+            state.first_sync = true;
+            if let Err(error) = state.save(&repo, profile.as_ref()) {
+                warn!("Unable to save the sync state: {}", error);
+            }
+        }
+
+        if matches.is_present("shallow") || config.get_bool("flake.shallow").unwrap_or(false) {
+            let branch = matches.value_of("branch")
+                .map(String::from)
+                .or_else(|| config.get_string("flake.branch").ok())
+                .or_else(|| repo.head().ok().and_then(|head| head.shorthand().map(String::from)))
+                .unwrap_or_else(|| String::from(DEFAULT_BRANCH));
+            single_branch(&repo, &branch)?;
+        }
+
+        // A repository with only a README or a license has no layout to keep yet.
+        if let Some(format) = matches.value_of("store-format") {
+            let workdir = repo.workdir().unwrap();
+            let empty = tracked_files(workdir)
+                .iter()
+                .all(|name| name.components().count() == 1 && !is_dotfile(name));
+            layout::init(workdir, format, empty).map_err(FlakeError::Config)?;
+        }
+
+        let timeout = match matches.value_of("timeout")
+            .map(String::from)
+            .or_else(|| config.get_string("flake.timeout").ok()) {
+            Some(value) => parse_duration(&value).map_err(FlakeError::Config)?,
+            None => Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+        };
+
+        let verify_signatures = matches.is_present("verify-signatures") ||
+                                config.get_bool("flake.verifysignatures").unwrap_or(false);
+        let trusted_keys = signatures::trusted_keys(&config).map_err(FlakeError::Config)?;
+        if verify_signatures && trusted_keys.is_empty() {
+            return Err(FlakeError::Config(String::from("verifying signatures needs a trusted key, \
+                                                        add one with `git config --global --add \
+                                                        flake.trustedkey FINGERPRINT`")));
+        }
+
+        let strategy = matches.value_of("strategy")
+            .map(String::from)
+            .or_else(|| config.get_string("flake.strategy").ok())
+            .unwrap_or_else(|| String::from("newest-wins"));
+        let strategy = Strategy::from_name(&strategy).map_err(FlakeError::Config)?;
+
+        let capture = matches.value_of("capture")
+            .map(String::from)
+            .or_else(|| config.get_string("flake.capture").ok())
+            .unwrap_or_else(|| String::from("all"));
+        if capture != "all" && capture != "tracked" {
+            return Err(FlakeError::Config(format!("unknown capture policy `{}`, use all or \
+                                                   tracked",
+                                                  capture)));
+        }
+
+        let options = SyncOptions {
+            max_files: value_t!(matches.value_of("max-files"), usize).unwrap_or_else(|_| {
+                config.get_i64("flake.maxfiles").map(|n| n as usize).unwrap_or(DEFAULT_MAX_FILES)
+            }),
+            max_bytes: value_t!(matches.value_of("max-bytes"), u64).unwrap_or_else(|_| {
+                config.get_i64("flake.maxbytes").map(|n| n as u64).unwrap_or(DEFAULT_MAX_BYTES)
+            }),
+            assume_yes: matches.is_present("yes"),
+            per_file: matches.is_present("per-file") ||
+                      config.get_bool("flake.perfile").unwrap_or(false),
+            trailers: commit_trailers(&config),
+            branch: matches.value_of("branch")
+                .map(String::from)
+                .or_else(|| config.get_string("flake.branch").ok()),
+            local_branch: matches.value_of("local-branch")
+                .map(String::from)
+                .or_else(|| config.get_string("flake.localbranch").ok()),
+            timeout,
+            prune: matches.is_present("prune"),
+            strategy,
+            mirrors: mirror_urls(&config),
+            jobs: value_t!(matches.value_of("jobs"), usize)
+                .unwrap_or_else(|_| {
+                    config.get_i64("flake.jobs").map(|n| n as usize).unwrap_or(DEFAULT_JOBS)
+                })
+                .max(1),
+            proxy,
+            ff_only: matches.is_present("ff-only") ||
+                     (!matches.is_present("no-ff-only") &&
+                      config.get_bool("flake.ffonly").unwrap_or(true)),
+            mtime_dates: matches.is_present("mtime-dates") ||
+                         config.get_bool("flake.mtimedates").unwrap_or(false),
+            only_if_online: matches.is_present("only-if-online"),
+            dotfiles_only: matches.is_present("include-dotfiles-only") ||
+                           config.get_bool("flake.dotfilesonly").unwrap_or(false),
+            group_by_dir: matches.is_present("group-by-dir") ||
+                          config.get_bool("flake.groupbydir").unwrap_or(false),
+            include_vcs: matches.is_present("include-vcs") ||
+                         config.get_bool("flake.includevcs").unwrap_or(false),
+            max_commits: value_t!(matches.value_of("max-commits-per-run"), usize)
+                .unwrap_or_else(|_| {
+                    config.get_i64("flake.maxcommits")
+                        .map(|n| n as usize)
+                        .unwrap_or(DEFAULT_MAX_COMMITS)
+                }),
+            hooks: Hooks::load(&config),
+            no_commit: matches.is_present("no-commit"),
+            stage: matches.is_present("stage"),
+            commit_empty: matches.is_present("commit-empty") ||
+                          config.get_bool("flake.commitempty").unwrap_or(false),
+            ignore_mode_changes: matches.is_present("ignore-mode-changes") ||
+                                 config.get_bool("flake.ignoremodechanges").unwrap_or(false),
+            preserve_xattrs: matches.is_present("preserve-xattrs") ||
+                             config.get_bool("flake.preservexattrs").unwrap_or(false),
+            report: matches.value_of("report")
+                .map(PathBuf::from)
+                .or_else(|| config.get_path("flake.report").ok())
+                .map(|path| {
+                    Report {
+                        path,
+                        max_bytes: config.get_i64("flake.reportmaxbytes")
+                            .map(|n| n as u64)
+                            .unwrap_or(report::DEFAULT_MAX_BYTES),
+                    }
+                }),
+            tracked_only: capture == "tracked",
+            profile,
+            roots: capture_roots(&config),
+            bootstrap: matches.is_present("bootstrap"),
+            verify_signatures,
+            trusted_keys,
+            trust_mtime: matches.value_of("trust-mtime")
+                .map(|value| value == "true")
+                .unwrap_or_else(|| config.get_bool("flake.trustmtime").unwrap_or(true)),
+        };
+
+        if let Some(ref branch) = options.local_branch {
+            switch_branch(&repo, branch)?;
+        }
+
+        let first_sync = State::load(&repo, options.profile.as_ref())
+            .first_sync;
+        Ok(Syncer {
+            username,
+            credentials,
+            repo,
+            options,
+            branch: RefCell::new(None),
+            first_sync: Cell::new(first_sync),
+        })
+    }
+
+    fn branch(&self) -> String {
+        self.branch
+            .borrow()
+            .clone()
+            .or_else(|| self.options.branch.clone())
+            .unwrap_or_else(|| String::from(DEFAULT_BRANCH))
+    }
+
+    // Updates the remote tracking branch and counts the commits the store hasn't seen yet.
+    fn fetch_only(&self) -> Result<usize, Error> {
+        let known = self.branch.borrow().clone().or_else(|| self.options.branch.clone());
+        let branch = fetch_remote(&self.username,
+                                  &self.credentials,
+                                  &self.repo,
+                                  &self.options,
+                                  known.as_ref())?;
+        *self.branch.borrow_mut() = Some(branch.clone());
+
+        let remote = self.repo.refname_to_id(&format!("refs/remotes/origin/{}", branch))?;
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.push(remote)?;
+        revwalk.hide(self.repo.refname_to_id("HEAD")?)?;
+        Ok(revwalk.count())
+    }
+
+    // Commands that change the store outside a sync refuse to run behind the remote.
+    fn require_up_to_date(&self) -> Result<(), FlakeError> {
+        let behind = self.fetch_only()
+            .map_err(|error| FlakeError::from(error).context("failed to fetch the repository"))?;
+        if behind > 0 {
+            return Err(FlakeError::Conflict(format!("the remote has {} commits the local \
+                                                     repository doesn't, run `flake sync` first",
+                                                    behind)));
+        }
+        Ok(())
+    }
+
+    // Each sync runs in phases: fetch the remote, compare it with the home directory,
+    // install the remote changes in home, capture the local changes, commit and push.
+    fn sync_repo(&self) -> Result<(), FlakeError> {
+        if self.options.only_if_online && !self.is_online() {
+            debug!("The repository can't be reached, skipping the sync");
+            self.record_sync(false);
+            return Ok(());
+        }
+        let _lock = Lock::acquire(&self.repo, self.options.profile.as_ref())
+            .map_err(FlakeError::Conflict)?;
+
+        let workdir = self.repo.workdir().unwrap();
+        let ignore = Ignore::load(workdir);
+
+        let known = self.branch.borrow().clone().or_else(|| self.options.branch.clone());
+        let branch = match fetch_remote(&self.username,
+                                        &self.credentials,
+                                        &self.repo,
+                                        &self.options,
+                                        known.as_ref()) {
+            Ok(branch) => branch,
+            Err(error) => {
+                let error = FlakeError::from(error);
+                if let FlakeError::Network(_) = error {
+                    self.record_sync(false);
+                }
+                return Err(error);
+            }
+        };
+        *self.branch.borrow_mut() = Some(branch.clone());
+
+        let local = self.repo.refname_to_id("HEAD")?;
+        let remote = self.repo.refname_to_id(&format!("refs/remotes/origin/{}", branch))?;
+        let base = self.repo.merge_base(local, remote)?;
+        if self.options.verify_signatures {
+            self.verify_signatures(local, remote)?;
+        }
+
+        // Commits that never reached the remote would be lost in the reset.
+        if self.options.ff_only && base != local {
+            let mut revwalk = self.repo.revwalk()?;
+            revwalk.push(local)?;
+            revwalk.hide(remote)?;
+            return Err(FlakeError::Conflict(format!("the local repository has {} commits that \
+                                                     are not in the remote. Push them with \
+                                                     `git -C {} push`, or run `flake sync \
+                                                     --no-ff-only` to discard them and capture \
+                                                     your home directory again",
+                                                    revwalk.count(),
+                                                    workdir.display())));
+        }
+
+        let base_tree = self.repo.find_commit(base)?.tree()?;
+        let remote_tree = self.repo.find_commit(remote)?.tree()?;
+        let layout = Layout::load(workdir);
+        let keep = Keep::load(workdir);
+        let plan = merge::plan(&self.repo, &base_tree, &remote_tree, &layout, &ignore, &keep)?;
+
+        // The store always moves to the remote, local changes are captured again from home.
+        let object = self.repo.find_object(remote, None)?;
+        self.repo.reset(&object, git2::ResetType::Hard, None)?;
+
+        for name in &plan.conflicts {
+            if self.options.strategy.keeps_home(&self.repo, base, remote, &layout, name)? {
+                warn!("{} changed in both your home directory and the repository, keeping your \
+                       version",
+                      name.display());
+                if let Err(error) = keep_home_version(workdir, name) {
+                    warn!("Unable to keep your version of {}: {}", name.display(), error);
+                }
+                continue;
+            }
+
+            warn!("{} changed in both your home directory and the repository, using the \
+                   repository version",
+                  name.display());
+            if !workdir.join(name).exists() {
+                prune_path(workdir, name);
+            } else if let Err(error) = restore_path(&self.repo, name, true) {
+                warn!("Unable to update {}: {}", name.display(), error);
+            }
+        }
+
+        for name in &plan.to_home {
+            match restore_path(&self.repo, name, false) {
+                Ok(_) => info!("Updated {} from the repository", name.display()),
+                Err(error) => warn!("Unable to update {}: {}", name.display(), error),
+            }
+        }
+
+        // Prune before capturing, otherwise the files deleted remotely would be copied back.
+        if self.options.prune {
+            for name in &plan.deleted {
+                prune_path(workdir, name);
+            }
+        }
+
+        if self.first_sync.get() {
+            self.bootstrap()?;
+            if self.options.bootstrap {
+                self.run_bootstrap_script()?;
+            }
+            self.first_sync.set(false);
+        }
+
+        self.sync_changes()?;
+        self.record_sync(true);
+
+        self.options.hooks.run(hooks::POST_SYNC,
+                               workdir,
+                               &home_names(&layout, &plan.to_home),
+                               self.repo.refname_to_id("HEAD").ok());
+        Ok(())
+    }
+
+    // Every remote commit the reset would bring in, before any file is touched.
+    fn verify_signatures(&self, local: Oid, remote: Oid) -> Result<(), FlakeError> {
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.push(remote)?;
+        revwalk.hide(local)?;
+
+        for oid in revwalk {
+            let commit = self.repo.find_commit(oid?)?;
+            signatures::verify(&commit, &self.options.trusted_keys).map_err(|error| {
+                    FlakeError::Auth(format!("refusing to sync, the remote commit {:.7} {}",
+                                             commit.id().to_string(),
+                                             error))
+                })?;
+        }
+        Ok(())
+    }
+
+    // Only the syncs that reached the remote count as the last sync.
+    fn record_sync(&self, online: bool) {
+        let profile = self.options.profile.as_ref();
+        let mut state = State::load(&self.repo, profile);
+        if online {
+            state.last_sync = Some(time::get_time().sec);
+            state.first_sync = false;
+        }
+        state.offline = !online;
+        if let Err(error) = state.save(&self.repo, profile) {
+            warn!("Unable to save the sync state: {}", error);
+        }
+    }
+
+    // A cheap TCP connection to the host of the repository, or of the proxy when there's one.
+    // Local repositories are always online.
+    fn is_online(&self) -> bool {
+        let url = match self.options.proxy {
+            Some(ref proxy) => proxy.clone(),
+            None => {
+                match self.repo.find_remote("origin").ok().and_then(|r| r.url().map(String::from)) {
+                    Some(url) => url,
+                    None => return true,
+                }
+            }
+        };
+        is_reachable(&url, Duration::from_secs(ONLINE_CHECK_TIMEOUT_SECS))
+    }
+
+    // What `sync_repo` would do next, without doing it. Only the remote tracking branch is
+    // updated, by the fetch. The files to capture are compared with the local repository as it
+    // is, before the remote changes are installed.
+    fn plan(&self) -> Result<Json, FlakeError> {
+        self.plan_for(&Layout::load(self.repo.workdir().unwrap()))
+    }
+
+    fn plan_for(&self, layout: &Layout) -> Result<Json, FlakeError> {
+        let workdir = self.repo.workdir().unwrap();
+        let branch = self.fetch_only()
+            .map(|_| self.branch())
+            .map_err(|error| FlakeError::from(error).context("failed to fetch the repository"))?;
+
+        let local = self.repo.refname_to_id("HEAD")?;
+        let remote = self.repo.refname_to_id(&format!("refs/remotes/origin/{}", branch))?;
+        let base = self.repo.merge_base(local, remote)?;
+        let (ahead, behind) = self.repo.graph_ahead_behind(local, remote)?;
+
+        let keep = Keep::load(workdir);
+        let plan = merge::plan(&self.repo,
+                               &self.repo.find_commit(base)?.tree()?,
+                               &self.repo.find_commit(remote)?.tree()?,
+                               layout,
+                               &Ignore::load(workdir),
+                               &keep)?;
+
+        let mut installed = plan.to_home.clone();
+        let mut conflicts = Vec::new();
+        for name in &plan.conflicts {
+            let keeps_home =
+                self.options.strategy.keeps_home(&self.repo, base, remote, layout, name)?;
+            if !keeps_home {
+                installed.push(name.clone());
+            }
+            let mut conflict = BTreeMap::new();
+            conflict.insert(String::from("path"), name.to_string_lossy().to_json());
+            conflict.insert(String::from("keep"),
+                            if keeps_home { "home" } else { "repository" }.to_json());
+            conflicts.push(Json::Object(conflict));
+        }
+
+        let mut changes = Vec::new();
+        for (name, _) in capturable_entries(workdir, &self.options) {
+            let target = layout.home_path(&name);
+            let source = workdir.join(&name);
+            if installed.contains(&name) || links_to(&target, &source) {
+                continue;
+            }
+            if !target.exists() {
+                if !keep.is_kept(&name) {
+                    changes.push((name, git2::STATUS_WT_DELETED));
+                }
+            } else if files_differ(&source, &target)? {
+                changes.push((name, git2::STATUS_WT_MODIFIED));
+            }
+        }
+
+        let captured = changes.iter()
+            .map(|change| {
+                let (ref name, status) = *change;
+                let mut change = BTreeMap::new();
+                change.insert(String::from("path"), name.to_string_lossy().to_json());
+                change.insert(String::from("action"), change_action(status).to_json());
+                Json::Object(change)
+            })
+            .collect();
+
+        let mut commits = Vec::new();
+        if self.options.no_commit {
+            // Nothing is committed or pushed.
+        } else if changes.is_empty() {
+            if self.options.commit_empty {
+                commits.push((heartbeat_message(), Vec::new()));
+            }
+        } else {
+            commits = group_commits(changes, &self.options);
+        }
+        let commits = commits.into_iter()
+            .map(|(message, changes)| {
+                let mut commit = BTreeMap::new();
+                commit.insert(String::from("message"),
+                              with_trailers(&message, &self.options.trailers).to_json());
+                commit.insert(String::from("files"),
+                              changes.iter()
+                                  .map(|change| change.0.to_string_lossy().to_json())
+                                  .collect::<Vec<_>>()
+                                  .to_json());
+                Json::Object(commit)
+            })
+            .collect::<Vec<_>>();
+
+        let mut push = BTreeMap::new();
+        let origin = self.repo.find_remote("origin")?;
+        push.insert(String::from("remote"), origin.url().unwrap_or("").to_json());
+        push.insert(String::from("branch"), branch.to_json());
+        push.insert(String::from("mirrors"), self.options.mirrors.to_json());
+        push.insert(String::from("credentials"), self.credentials.name().to_json());
+
+        let paths = |names: &[PathBuf]| {
+            Json::Array(names.iter().map(|name| name.to_string_lossy().to_json()).collect())
+        };
+        let mut object = BTreeMap::new();
+        object.insert(String::from("version"), PLAN_SCHEMA_VERSION.to_json());
+        object.insert(String::from("ahead"), ahead.to_json());
+        object.insert(String::from("behind"), behind.to_json());
+        object.insert(String::from("install"), paths(&plan.to_home));
+        object.insert(String::from("conflicts"), Json::Array(conflicts));
+        object.insert(String::from("prune"),
+                      paths(if self.options.prune { &plan.deleted } else { &[] }));
+        object.insert(String::from("capture"), Json::Array(captured));
+        if !commits.is_empty() {
+            object.insert(String::from("push"), Json::Object(push));
+        }
+        object.insert(String::from("commits"), Json::Array(commits));
+        Ok(Json::Object(object))
+    }
+
+    // Capturing replaces the files in the repository with the ones in the home directory, and
+    // deletes the ones missing from it. On a new machine that's rarely what people want, so
+    // the first sync asks which way to go before touching anything.
+    fn bootstrap(&self) -> Result<(), FlakeError> {
+        if self.options.assume_yes {
+            return Ok(());
+        }
+
+        if !is_interactive() {
+            return Err(FlakeError::Config(String::from("this is the first sync on this machine. \
+                                                        Run `flake apply` to install the \
+                                                        repository's files, or `flake sync \
+                                                        --yes` to capture your home directory \
+                                                        into the repository")));
+        }
+
+        println!("This is the first sync on this machine.");
+        print!("[a]pply the repository to your home directory, [c]apture your home directory \
+                into the repository, or [q]uit? [a/c/Q] ");
+        io::stdout().flush().unwrap();
+
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        match answer.trim().to_lowercase().as_str() {
+            "a" | "apply" => {
+                let workdir = self.repo.workdir().unwrap();
+                let layout = Layout::load(workdir);
+                for name in tracked_files(workdir) {
+                    if template::is_template(&name) ||
+                       is_identical(&layout.home_path(&name), &workdir.join(&name)) {
+                        continue;
+                    }
+                    if let Err(error) = restore_path(&self.repo, &name, true) {
+                        warn!("Unable to apply file {}: {}", name.display(), error);
+                    }
+                }
+                info!("Applied the repository, replaced files have a .flake-bak copy");
+                Ok(())
+            }
+            "c" | "capture" => Ok(()),
+            _ => Err(FlakeError::Config(String::from("the first sync was cancelled"))),
+        }
+    }
+
+    // The script can run anything on this machine, so it's only run when asked for, and after a
+    // confirmation unless the sync also has `--yes`.
+    fn run_bootstrap_script(&self) -> Result<(), FlakeError> {
+        let workdir = self.repo.workdir().unwrap();
+        let script = workdir.join(hooks::BOOTSTRAP_SCRIPT);
+        if !script.is_file() {
+            info!("The repository has no {}, nothing to bootstrap", hooks::BOOTSTRAP_SCRIPT);
+            return Ok(());
+        }
+
+        if !self.options.assume_yes {
+            if !is_interactive() {
+                return Err(FlakeError::Config(format!("{} needs a confirmation, run `flake \
+                                                       sync --bootstrap --yes` to run it \
+                                                       without one",
+                                                      hooks::BOOTSTRAP_SCRIPT)));
+            }
+            let question = format!("Run the repository's {}? It can run any command on this \
+                                    machine.",
+                                   hooks::BOOTSTRAP_SCRIPT);
+            if !ask(&question, false) {
+                info!("Skipped {}", hooks::BOOTSTRAP_SCRIPT);
+                return Ok(());
+            }
+        }
+
+        let status = process::Command::new(&script)
+            .current_dir(workdir)
+            .env("FLAKE_STORE", workdir)
+            .status()
+            .map_err(|error| {
+                FlakeError::Config(format!("Unable to run {}: {}", hooks::BOOTSTRAP_SCRIPT, error))
+            })?;
+        if !status.success() {
+            return Err(FlakeError::Config(format!("{} failed with {}",
+                                                  hooks::BOOTSTRAP_SCRIPT,
+                                                  status)));
+        }
+        info!("Ran {}", hooks::BOOTSTRAP_SCRIPT);
+        Ok(())
+    }
+
+    fn sync_changes(&self) -> Result<(), Error> {
+        sync_files(self.repo.workdir().unwrap(), &self.options);
+
+        let statuses = changed_files(&self.repo, &self.options)?;
+        let changed = statuses.iter()
+            .filter(|entry| is_committable(&self.repo, entry, &self.options))
+            .filter_map(|entry| entry.path().map(PathBuf::from))
+            .collect::<Vec<_>>();
+        if changed.is_empty() {
+            if self.options.commit_empty {
+                return self.commit_heartbeat();
+            }
+            return Ok(());
+        }
+        if self.options.no_commit {
+            return self.report_changes(&statuses);
+        }
+        if !confirm_changes(&self.repo, &statuses, &self.options) {
+            return Ok(());
+        }
+        commit_updates(&self.repo, &self.options)?;
+        info!("Committed {} changed files", changed.len());
+        self.publish(&changed)
+    }
+
+    // An empty commit that only records that this machine synced.
+    fn commit_heartbeat(&self) -> Result<(), Error> {
+        let message = heartbeat_message();
+        commit_index(&self.repo,
+                     &mut self.repo.index()?,
+                     &with_trailers(&message, &self.options.trailers),
+                     None)?;
+        info!("Committed a sync without changes");
+        self.publish(&[])
+    }
+
+    // Everything that follows a commit: the hooks, the push and the report.
+    fn publish(&self, changed: &[PathBuf]) -> Result<(), Error> {
+        let commit = self.repo.refname_to_id("HEAD").ok();
+        let workdir = self.repo.workdir().unwrap();
+        self.options.hooks.run(hooks::POST_COMMIT,
+                               workdir,
+                               &home_names(&Layout::load(workdir), changed),
+                               commit);
+
+        let pushed = self.push(false);
+        if let Some(ref report) = self.options.report {
+            if let Err(error) = report.append(changed, commit, &pushed) {
+                warn!("Unable to write the report {}: {}", report.path.display(), error);
+            }
+        }
+        pushed
+    }
+
+    // Lists the captured changes for a review before committing them by hand.
+    fn report_changes(&self, statuses: &Statuses) -> Result<(), Error> {
+        let mut index = self.repo.index()?;
+        for entry in statuses.iter() {
+            let path = match entry.path() {
+                Some(path) => PathBuf::from(path),
+                None => continue,
+            };
+            if self.options.stage {
+                stage_change(&mut index, path.as_path(), entry.status())?;
+            }
+
+            let change = if entry.status().intersects(git2::STATUS_WT_DELETED) {
+                "deleted"
+            } else if entry.status().intersects(git2::STATUS_WT_NEW) {
+                "added"
+            } else {
+                "modified"
+            };
+            println!("{} {}", color::change(change), path.display());
+        }
+        if self.options.stage {
+            index.write()?;
+        }
+
+        info!("Captured {} changed files without committing them, review them with `git -C {} \
+               status`",
+              statuses.len(),
+              self.repo.workdir().unwrap().display());
+        Ok(())
+    }
+
+    // Mirrors are best effort, only a failure to push to origin fails the sync.
+    fn push(&self, force: bool) -> Result<(), Error> {
+        let branch = self.branch();
+        push_master(&self.username,
+                    &self.credentials,
+                    &self.repo,
+                    &self.options,
+                    None,
+                    &branch,
+                    force)?;
+
+        for (url, result) in self.push_mirrors(&branch, force) {
+            match result {
+                Ok(_) => info!("Pushed to mirror {}", url),
+                Err(error) => warn!("Unable to push to mirror {}: {}", url, error),
+            }
+        }
+        Ok(())
+    }
+
+    // The mirrors are independent, so up to `jobs` of them are pushed at the same time. Every
+    // thread opens the repository again, a Repository can't be shared between threads.
+    fn push_mirrors(&self, branch: &str, force: bool) -> Vec<(String, Result<(), Error>)> {
+        let shared = Arc::new((self.username.clone(),
+                               self.credentials.clone(),
+                               self.options.clone(),
+                               PathBuf::from(self.repo.path())));
+
+        let mut results = Vec::new();
+        for urls in self.options.mirrors.chunks(self.options.jobs) {
+            let pushes = urls.iter()
+                .map(|url| {
+                    let shared = shared.clone();
+                    let url = url.clone();
+                    let branch = String::from(branch);
+                    thread::spawn(move || {
+                        let (ref username, ref credentials, ref options, ref path) = *shared;
+                        Repository::open(path).and_then(|repo| {
+                            push_master(username,
+                                        credentials,
+                                        &repo,
+                                        options,
+                                        Some(&url),
+                                        &branch,
+                                        force)
+                        })
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for (url, push) in urls.iter().zip(pushes) {
+                let result = push.join()
+                    .unwrap_or_else(|_| Err(Error::from_str("the push thread panicked")));
+                results.push((url.clone(), result));
+            }
+        }
+        results
+    }
+}
+
+// The files to commit. The store's .gitignore decides what's committed, files it ignores are
+// still copied into the store unless .flakeignore excludes them, but they never leave it.
+// Ignored files that are already tracked keep being committed, like in git.
+fn changed_files<'a>(repo: &'a Repository, options: &SyncOptions) -> Result<Statuses<'a>, Error> {
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(!options.tracked_only)
+        .recurse_untracked_dirs(!options.tracked_only)
+        .include_ignored(false);
+    repo.statuses(Some(&mut opts))
+}
+
+fn commit_updates(repo: &Repository, options: &SyncOptions) -> Result<(), Error> {
+    let statuses = changed_files(repo, options)?;
+    let layout = Layout::load(repo.workdir().unwrap());
+    let changes = statuses.iter()
+        .filter(|entry| is_committable(repo, entry, options))
+        .filter_map(|entry| entry.path().map(|path| (PathBuf::from(path), entry.status())))
+        .collect();
+
+    let mut index = repo.index()?;
+    for (message, changes) in group_commits(changes, options) {
+        let mut newest = None;
+        for (path, status) in changes {
+            stage_change(&mut index, path.as_path(), status)?;
+            if options.mtime_dates {
+                newest = newest.max(merge::modified_time(&layout.home_path(&path)));
+            }
+        }
+        commit_index(repo,
+                     &mut index,
+                     &with_trailers(&message, &options.trailers),
+                     newest)?;
+    }
+    Ok(())
+}
+
+fn is_committable(repo: &Repository, entry: &StatusEntry, options: &SyncOptions) -> bool {
+    !options.ignore_mode_changes || !is_mode_change(repo, entry)
+}
+
+// A modified file with the same content as the index, which only leaves its mode. Files copied
+// from a mount with different default permissions show up like this.
+fn is_mode_change(repo: &Repository, entry: &StatusEntry) -> bool {
+    if entry.status() != git2::STATUS_WT_MODIFIED {
+        return false;
+    }
+    let (delta, path) = match (entry.index_to_workdir(), entry.path()) {
+        (Some(delta), Some(path)) => (delta, path),
+        _ => return false,
+    };
+    let blob = match repo.find_blob(delta.old_file().id()) {
+        Ok(blob) => blob,
+        Err(_) => return false,
+    };
+
+    let mut content = Vec::new();
+    fs::File::open(repo.workdir().unwrap().join(path))
+        .and_then(|mut file| file.read_to_end(&mut content))
+        .map(|_| content == blob.content())
+        .unwrap_or(false)
+}
+
+// The message and the changes of every commit `commit_updates` makes.
+fn group_commits(changes: Vec<(PathBuf, Status)>,
+                 options: &SyncOptions)
+                 -> Vec<(String, Vec<(PathBuf, Status)>)> {
+    // Files directly in the home directory are a group of their own.
+    let mut groups = BTreeMap::new();
+    let count = changes.len();
+    for (path, status) in changes {
+        let group = if options.group_by_dir {
+            match path.components().next() {
+                Some(Component::Normal(first)) if path.components().count() > 1 => {
+                    Some(first.to_string_lossy().into_owned())
+                }
+                _ => Some(String::from("~")),
+            }
+        } else {
+            None
+        };
+        groups.entry(group).or_insert_with(Vec::new).push((path, status));
+    }
+
+    let commits = if options.per_file { count } else { groups.len() };
+    let mut per_file = options.per_file;
+    if commits > 1 && commits > options.max_commits {
+        warn!("Committing the changes at once, they would take {} commits, more than the limit \
+               of {}",
+              commits,
+              options.max_commits);
+        per_file = false;
+        let changes = groups.values_mut().flat_map(|changes| changes.drain(..)).collect();
+        groups.clear();
+        groups.insert(None, changes);
+    }
+
+    let mut commits = Vec::new();
+    for (group, changes) in groups {
+        if per_file {
+            for (path, status) in changes {
+                let message = format!("{} {}", change_action(status), path.display());
+                commits.push((message, vec![(path, status)]));
+            }
+            continue;
+        }
+
+        let message = match group {
+            Some(group) => format!("Update files in {}", group),
+            None => String::from("Update files"),
+        };
+        commits.push((message, changes));
+    }
+    commits
+}
+
+fn heartbeat_message() -> String {
+    let mut message = format!("Sync with no changes on {}",
+                              time::now().strftime("%Y-%m-%d %H:%M").unwrap());
+    if let Some(host) = template::hostname() {
+        message.push_str(&format!(" from {}", host));
+    }
+    message
+}
+
+fn with_trailers(message: &str, trailers: &[String]) -> String {
+    if trailers.is_empty() {
+        return String::from(message);
+    }
+    format!("{}\n\n{}", message, trailers.join("\n"))
+}
+
+fn mirror_urls(config: &git2::Config) -> Vec<String> {
+    let mut mirrors = Vec::new();
+
+    if let Ok(entries) = config.entries(Some("github.dotfiles-mirror")) {
+        for entry in &entries {
+            let value = match entry.ok().and_then(|e| e.value().map(String::from)) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            match remote_url(&value) {
+                Ok(url) => mirrors.push(url),
+                Err(error) => warn!("Ignoring mirror {}: {}", value, error),
+            }
+        }
+    }
+
+    mirrors
+}
+
+fn capture_roots(config: &git2::Config) -> Vec<PathBuf> {
+    let mut roots = Vec::new();
+
+    if let Ok(entries) = config.entries(Some("flake.root")) {
+        for entry in &entries {
+            if let Some(value) = entry.ok().and_then(|e| e.value().map(String::from)) {
+                roots.push(relative_to_home(value.replacen("~/", "", 1).as_str()));
+            }
+        }
+    }
+
+    roots
+}
+
+fn commit_trailers(config: &git2::Config) -> Vec<String> {
+    let mut trailers = Vec::new();
+
+    if let Ok(entries) = config.entries(Some("flake.trailer")) {
+        for entry in &entries {
+            let value = match entry.ok().and_then(|e| e.value().map(String::from)) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            if is_valid_trailer(&value) {
+                trailers.push(value);
+            } else {
+                warn!("Ignoring invalid commit trailer `{}`, use the form `Key: value`", value);
+            }
+        }
+    }
+
+    trailers
+}
+
+fn is_valid_trailer(trailer: &str) -> bool {
+    match trailer.find(": ") {
+        Some(split) => {
+            let (key, value) = (&trailer[..split], &trailer[split + 2..]);
+            !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || c == '-') &&
+            !value.trim().is_empty()
+        }
+        None => false,
+    }
+}
+
+// Only touches the paths that changed, so staging scales with the diff instead of the repository.
+fn stage_change(index: &mut Index, path: &Path, status: Status) -> Result<&'static str, Error> {
+    if status.intersects(git2::STATUS_WT_DELETED | git2::STATUS_INDEX_DELETED) {
+        index.remove_path(path)?;
+    } else {
+        index.add_path(path)?;
+    }
+    Ok(change_action(status))
+}
+
+fn change_action(status: Status) -> &'static str {
+    if status.intersects(git2::STATUS_WT_DELETED | git2::STATUS_INDEX_DELETED) {
+        "Remove"
+    } else if status.intersects(git2::STATUS_WT_NEW | git2::STATUS_INDEX_NEW) {
+        "Add"
+    } else {
+        "Update"
+    }
+}
+
+// The author date is the given time, like the modification time of the files, or now.
+fn commit_index(repo: &Repository,
+                index: &mut Index,
+                message: &str,
+                author_time: Option<i64>)
+                -> Result<(), Error> {
+    let head_commit = repo.find_commit(repo.refname_to_id("HEAD")?)?;
+
+    index.write()?;
+    let oid = index.write_tree()?;
+    let tree = repo.find_tree(oid)?;
+
+    let committer = repo.signature()?;
+    let author = match author_time {
+        Some(seconds) => {
+            let when = git2::Time::new(seconds, committer.when().offset_minutes());
+            git2::Signature::new(committer.name().unwrap_or(""),
+                                 committer.email().unwrap_or(""),
+                                 &when)?
+        }
+        None => committer.to_owned(),
+    };
+    repo.commit(Some("HEAD"),
+                &author,
+                &committer,
+                message,
+                &tree,
+                &[&head_commit])?;
+
+    Ok(())
+}
+
+// Starting over is always safe, the files already written are adopted because they match.
+fn confirm_resume(count: usize, assume_yes: bool) -> bool {
+    if assume_yes {
+        info!("Resuming the interrupted apply, {} files were already written", count);
+        return true;
+    }
+
+    let summary = format!("A previous apply of this commit was interrupted after writing {} files",
+                          count);
+    if !is_interactive() {
+        info!("{}, applying every file again. Run `flake apply --resume` to skip them", summary);
+        return false;
+    }
+    ask(&format!("{}. Resume it?", summary), true)
+}
+
+fn confirm_changes(repo: &Repository, statuses: &Statuses, options: &SyncOptions) -> bool {
+    let workdir = repo.workdir().unwrap();
+    let files = statuses.len();
+    let bytes = statuses.iter()
+        .filter_map(|entry| entry.path().map(|path| workdir.join(path)))
+        .filter_map(|path| fs::metadata(path).ok())
+        .fold(0, |total, metadata| total + metadata.len());
+
+    if options.assume_yes || (files <= options.max_files && bytes <= options.max_bytes) {
+        return true;
+    }
+
+    let summary = format!("{} files and {} bytes changed, more than the configured limits of {} \
+                           files and {} bytes",
+                          files,
+                          bytes,
+                          options.max_files,
+                          options.max_bytes);
+
+    if !is_interactive() {
+        warn!("Skipping commit, {}. Run `flake sync --yes` to commit them anyway",
+              summary);
+        return false;
+    }
+
+    ask(&format!("{}. Commit them anyway?", summary), false)
+}
+
+// Whether someone can answer questions, flake never waits for an answer from cron or a pipe.
+fn is_interactive() -> bool {
+    let tty = unsafe { libc::isatty(libc::STDIN_FILENO) };
+    tty != 0
+}
+
+// Asks a yes or no question, an empty or unreadable answer picks `default`.
+fn ask(question: &str, default: bool) -> bool {
+    print!("{} {} ", question, if default { "[Y/n]" } else { "[y/N]" });
+    io::stdout().flush().unwrap();
+
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return default;
+    }
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => true,
+        "n" | "no" => false,
+        _ => default,
+    }
+}
+
+fn fetch_remote(username: &str,
+                credentials: &CredentialStore,
+                repo: &Repository,
+                options: &SyncOptions,
+                branch: Option<&String>)
+                -> Result<String, Error> {
+    let mut remote = repo.find_remote("origin")?;
+    let timeout = options.timeout;
+    let deadline = Instant::now() + timeout;
+
+    let mut fo = FetchOptions::new();
+    fo.remote_callbacks(remote_callbacks(username, credentials, deadline));
+    fo.proxy_options(proxy_options(options.proxy.as_ref()));
+    remote.fetch(&[], Some(&mut fo), None)
+        .map_err(|error| timed_out(error, deadline, timeout))?;
+
+    Ok(match branch {
+        Some(branch) => branch.clone(),
+        None => {
+            remote_head_branch(repo, &remote).unwrap_or_else(|| String::from(DEFAULT_BRANCH))
+        }
+    })
+}
+
+// The filters `git clone --filter` accepts. libgit2 can't fetch with any of them, so they only
+// decide whether a clone warns that it downloaded everything.
+fn check_filter(filter: &str) -> Result<(), String> {
+    let valid = match filter.find(':') {
+        Some(colon) => {
+            match (&filter[..colon], &filter[colon + 1..]) {
+                ("blob", "none") => true,
+                ("blob", limit) if limit.starts_with("limit=") => {
+                    let size = &limit["limit=".len()..];
+                    let size = match size.chars().last() {
+                        Some(unit) if "kmg".contains(unit.to_ascii_lowercase()) => {
+                            &size[..size.len() - 1]
+                        }
+                        _ => size,
+                    };
+                    !size.is_empty() && size.chars().all(|c| c.is_ascii_digit())
+                }
+                ("tree", depth) => !depth.is_empty() && depth.chars().all(|c| c.is_ascii_digit()),
+                _ => false,
+            }
+        }
+        None => false,
+    };
+    if !valid {
+        return Err(format!("invalid --filter `{}`, use blob:none, blob:limit=SIZE or tree:DEPTH",
+                           filter));
+    }
+    Ok(())
+}
+
+// The closest to a shallow store libgit2 gets: it can't fetch with a depth, but it can fetch a
+// single branch. The refspec is saved in the store, so every later fetch stays on the branch.
+fn single_branch(repo: &Repository, branch: &str) -> Result<(), Error> {
+    let refspec = format!("+refs/heads/{0}:refs/remotes/origin/{0}", branch);
+    let mut config = repo.config()?;
+    if config.get_string("remote.origin.fetch").ok().as_ref() == Some(&refspec) {
+        return Ok(());
+    }
+
+    config.set_str("remote.origin.fetch", &refspec)?;
+    warn!("Only fetching the {} branch from now on. libgit2 can't limit the depth of the \
+           history, so its whole history is still downloaded",
+          branch);
+    Ok(())
+}
+
+// Reads the default branch from the HEAD the remote advertised on the last fetch,
+// falling back to the origin/HEAD reference that clone creates.
+fn remote_head_branch(repo: &Repository, remote: &git2::Remote) -> Option<String> {
+    let advertised = remote.list().ok().and_then(|heads| {
+        heads.iter()
+            .find(|head| head.name() == "HEAD")
+            .and_then(|head| head.symref_target())
+            .map(|target| target.replacen("refs/heads/", "", 1))
+    });
+    if advertised.is_some() {
+        return advertised;
+    }
+
+    repo.find_reference("refs/remotes/origin/HEAD")
+        .ok()
+        .and_then(|reference| {
+            reference.symbolic_target()
+                .map(|target| target.replacen("refs/remotes/origin/", "", 1))
+        })
+}
+
+// Moves the store to the working branch, which starts where the store is when it doesn't exist
+// yet. Every commit lands there from then on, and the reset of every sync moves it to the remote.
+fn switch_branch(repo: &Repository, branch: &str) -> Result<(), Error> {
+    let refname = format!("refs/heads/{}", branch);
+    if repo.head().ok().as_ref().and_then(|head| head.name()) == Some(refname.as_str()) {
+        return Ok(());
+    }
+
+    if repo.find_branch(branch, BranchType::Local).is_err() {
+        let head = repo.find_commit(repo.refname_to_id("HEAD")?)?;
+        repo.branch(branch, &head, false)?;
+    }
+    repo.set_head(&refname)?;
+    repo.checkout_head(Some(CheckoutBuilder::new().force()))?;
+    info!("Committing to the {} branch of the store", branch);
+    Ok(())
+}
+
+// The local side of the push, the working branch pushes to the remote branch whatever its name.
+fn local_ref(repo: &Repository, options: &SyncOptions) -> Result<String, Error> {
+    match options.local_branch {
+        Some(ref branch) => Ok(format!("refs/heads/{}", branch)),
+        None => Ok(repo.head()?.name().map(String::from).unwrap_or_else(|| String::from("HEAD"))),
+    }
+}
+
+// Pushes to origin, or to the given mirror url.
+fn push_master(username: &str,
+               credentials: &CredentialStore,
+               repo: &Repository,
+               options: &SyncOptions,
+               mirror: Option<&str>,
+               branch: &str,
+               force: bool)
+               -> Result<(), Error> {
+    if mirror.is_none() && !force &&
+       !fast_forwardable(username, credentials, repo, options, branch)? {
+        return Ok(());
+    }
+    if mirror.is_none() && force {
+        check_remote_unchanged(username, credentials, repo, options, branch)?;
+    }
+
+    let mut remote = match mirror {
+        Some(url) => repo.remote_anonymous(url)?,
+        None => repo.find_remote("origin")?,
+    };
+    let timeout = options.timeout;
+    let deadline = Instant::now() + timeout;
+
+    let mut po = PushOptions::new();
+    po.remote_callbacks(remote_callbacks(username, credentials, deadline));
+    po.proxy_options(proxy_options(options.proxy.as_ref()));
+
+    let refspec = format!("{}{}:refs/heads/{}",
+                          if force { "+" } else { "" },
+                          local_ref(repo, options)?,
+                          branch);
+    remote.push(&[refspec.as_str()], Some(&mut po))
+        .map_err(|error| timed_out(error, deadline, timeout))
+}
+
+// Force pushes only replace the remote branch if it's still where the last fetch left it, which
+// is the history being replaced. Unlike `git push --force-with-lease`, libgit2 can't make the
+// push itself conditional, so the branch is fetched again and compared right before pushing, and
+// a push that lands between the two is still overwritten. Mirrors follow origin, they're only
+// force pushed once origin took the new history.
+fn check_remote_unchanged(username: &str,
+                          credentials: &CredentialStore,
+                          repo: &Repository,
+                          options: &SyncOptions,
+                          branch: &str)
+                          -> Result<(), Error> {
+    let tracking = format!("refs/remotes/origin/{}", branch);
+    let expected = repo.refname_to_id(&tracking).ok();
+    fetch_remote(username, credentials, repo, options, Some(&String::from(branch)))?;
+
+    if repo.refname_to_id(&tracking).ok() == expected {
+        return Ok(());
+    }
+    let message = format!("origin/{} moved since it was fetched, another machine pushed to it. \
+                           Refusing to overwrite its commits, sync them and try again",
+                          branch);
+    Err(Error::from_str(message.as_str()))
+}
+
+// Fetches again right before pushing, the remote may have moved since the sync started. A push
+// that isn't a fast-forward would only be rejected, so it's skipped: the next sync picks up the
+// remote commits and captures the local changes on top of them. It's an error unless ff_only is
+// off, because that sync would refuse to discard the local commits.
+fn fast_forwardable(username: &str,
+                    credentials: &CredentialStore,
+                    repo: &Repository,
+                    options: &SyncOptions,
+                    branch: &str)
+                    -> Result<bool, Error> {
+    fetch_remote(username, credentials, repo, options, Some(&String::from(branch)))?;
+
+    let local = repo.refname_to_id(&local_ref(repo, options)?)?;
+    let remote = match repo.refname_to_id(&format!("refs/remotes/origin/{}", branch)) {
+        Ok(remote) => remote,
+        // The branch doesn't exist in the remote yet.
+        Err(_) => return Ok(true),
+    };
+
+    let (ahead, behind) = repo.graph_ahead_behind(local, remote)?;
+    if ahead == 0 {
+        debug!("origin/{} already has every local commit, nothing to push", branch);
+        return Ok(false);
+    }
+    if behind == 0 {
+        return Ok(true);
+    }
+
+    if options.ff_only {
+        let message = format!("origin/{} has {} new commits, the {} local commits can't be \
+                               pushed without discarding them",
+                              branch,
+                              behind,
+                              ahead);
+        return Err(Error::from_str(message.as_str()));
+    }
+    warn!("origin/{} moved while syncing, pushing on the next sync", branch);
+    Ok(false)
+}
+
+// libgit2 doesn't time out on its own, so every progress callback
+// cancels the transfer once the deadline has passed.
+fn remote_callbacks<'a>(username: &'a str,
+                        credentials: &'a CredentialStore,
+                        deadline: Instant)
+                        -> RemoteCallbacks<'a> {
+    let mut cb = RemoteCallbacks::new();
+    cb.credentials(move |url, _, _| git_credentials(username, credentials, url));
+    cb.transfer_progress(move |_| Instant::now() < deadline);
+    cb.sideband_progress(move |_| Instant::now() < deadline);
+    cb
+}
+
+// Without an explicit proxy, libgit2 looks for one in the git configuration.
+fn proxy_options(proxy: Option<&String>) -> ProxyOptions<'static> {
+    let mut po = ProxyOptions::new();
+    match proxy {
+        Some(url) => po.url(url),
+        None => po.auto(),
+    };
+    po
+}
+
+fn configured_proxy(flag: Option<&str>, config: &git2::Config) -> Option<String> {
+    flag.map(String::from)
+        .or_else(|| config.get_string("http.proxy").ok())
+        .or_else(|| env::var("HTTPS_PROXY").ok())
+        .or_else(|| env::var("https_proxy").ok())
+        .filter(|proxy| !proxy.is_empty())
+}
+
+fn timed_out(error: Error, deadline: Instant, timeout: Duration) -> Error {
+    if Instant::now() < deadline {
+        return error;
+    }
+    let message = format!("network operation timed out after {} seconds", timeout.as_secs());
+    Error::from_str(message.as_str())
+}
+
+// Moves aside a file in the home directory that was deleted from the repository.
+fn prune_path(workdir: &Path, name: &Path) {
+    let target = Layout::load(workdir).home_path(name);
+    let backup = backup_path(target.as_path());
+    match fs::rename(target.as_path(), backup.as_path()) {
+        Ok(_) => info!("Removed {}, deleted from the repository. Saved a copy in {}",
+                       target.display(),
+                       backup.display()),
+        Err(error) => warn!("Unable to remove {}: {}", target.display(), error),
+    }
+}
+
+// Copies a file from the home directory into the repository, even if the repository doesn't
+// have it anymore, so the home version is the one captured.
+fn keep_home_version(workdir: &Path, name: &Path) -> Result<(), io::Error> {
+    let source = Layout::load(workdir).home_path(name);
+    if !source.exists() {
+        return Ok(());
+    }
+
+    let target = workdir.join(name);
+    if links_to(&source, &target) {
+        return Ok(());
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(source.as_path(), target.as_path()).map(|_| ())
+}
+
+fn sync_files(workdir: &std::path::Path, options: &SyncOptions) {
+    let keep = Keep::load(workdir);
+    let layout = Layout::load(workdir);
+    let mut manifest = if options.preserve_xattrs {
+        Some(xattrs::Manifest::load(workdir))
+    } else {
+        None
+    };
+
+    // The walk already has the metadata of every file, so each one is only stat'd once more,
+    // in the home directory.
+    for (name, metadata) in capturable_entries(workdir, options) {
+        if let Err(error) = sync_path(workdir.join(&name).as_path(),
+                                      name.as_path(),
+                                      &metadata,
+                                      &keep,
+                                      &layout,
+                                      options.trust_mtime) {
+            warn!("Unable to sync file {}: {}",
+                  name.display(),
+                  error);
+            continue;
+        }
+        if let Some(ref mut manifest) = manifest {
+            if let Err(error) = manifest.capture(&name, &layout.home_path(&name)) {
+                warn!("Unable to read the attributes of {}: {}", name.display(), error);
+            }
+        }
+    }
+
+    if let Some(ref manifest) = manifest {
+        if let Err(error) = manifest.save(workdir) {
+            warn!("Unable to save {}: {}", xattrs::MANIFEST_FILE, error);
+        }
+    }
+}
+
+// The files in the repository a sync captures from the home directory.
+fn capturable_entries(workdir: &Path, options: &SyncOptions) -> Vec<(PathBuf, fs::Metadata)> {
+    let index = if options.tracked_only {
+        Repository::open(workdir).and_then(|repo| repo.index()).ok()
+    } else {
+        None
+    };
+
+    let mut entries = tracked_entries(workdir, &options.roots, options.include_vcs);
+    entries.retain(|entry| {
+        let name = &entry.0;
+        if let Some(ref index) = index {
+            if index.get_path(name, 0).is_none() {
+                debug!(target: logger::FILES,
+                       "Skipped {}, it was never added to the repository",
+                       name.display());
+                return false;
+            }
+        }
+        // Templates are the source of truth, their rendered output is never captured back.
+        if template::is_template(name) {
+            debug!(target: logger::FILES, "Skipped {}, it's a template", name.display());
+            return false;
+        }
+        if options.dotfiles_only && !is_dotfile(name) {
+            debug!(target: logger::FILES, "Skipped {}, it's not a dotfile", name.display());
+            return false;
+        }
+        true
+    });
+    entries
+}
+
+// Hidden files, or any file inside a hidden directory, like .config/nvim/init.vim.
+fn is_dotfile(name: &Path) -> bool {
+    name.components().any(|component| component.as_os_str().to_string_lossy().starts_with('.'))
+}
+
+fn tracked_files(workdir: &std::path::Path) -> Vec<PathBuf> {
+    tracked_entries(workdir, &[], true).into_iter().map(|(name, _)| name).collect()
+}
+
+// Without `include_vcs`, directories that are git repositories of their own, in the store or
+// in the home directory, are skipped whole, they're usually project checkouts.
+// Without roots the whole repository is walked, otherwise only the paths of the home directory
+// they list.
+fn tracked_entries(workdir: &std::path::Path,
+                   roots: &[PathBuf],
+                   include_vcs: bool)
+                   -> Vec<(PathBuf, fs::Metadata)> {
+    let ignore = Ignore::load(workdir);
+    let layout = Layout::load(workdir);
+    let home = env::home_dir().unwrap();
+    // A store inside the home directory would find itself in the repository, and capture
+    // its own files into it again and again.
+    let store_in_home = workdir.strip_prefix(&home).ok().map(PathBuf::from);
+    let starts = if roots.is_empty() {
+        vec![PathBuf::from(workdir)]
+    } else {
+        roots.iter()
+            .flat_map(|root| layout.store_names(root))
+            .map(|name| workdir.join(name))
+            .filter(|path| fs::symlink_metadata(path).is_ok())
+            .collect()
+    };
+    let walker = starts.into_iter().flat_map(|start| {
+        WalkDir::new(start)
+            .into_iter()
+            .filter_entry(|e| {
+                !is_git_object(e) && !is_ignored(e, workdir, &ignore) &&
+                !is_store(e, workdir, store_in_home.as_ref(), &layout) &&
+                (include_vcs || !is_nested_repository(e, workdir, &layout))
+            })
+    });
+
+    let mut files = Vec::new();
+    for entry in walker {
+        let entry = entry.unwrap();
+        if !entry.file_type().is_file() {
+            if let Some(kind) = special_kind(&entry.file_type()) {
+                debug!(target: logger::FILES,
+                       "Skipped {}, it's {}",
+                       entry.path().strip_prefix(workdir).unwrap().display(),
+                       kind);
+            }
+            continue;
+        }
+        let name = entry.path().strip_prefix(workdir).unwrap();
+        if !layout.is_home_file(name) {
+            continue;
+        }
+        match entry.metadata() {
+            Ok(metadata) => files.push((PathBuf::from(name), metadata)),
+            Err(error) => warn!("Unable to read {}: {}", name.display(), error),
+        }
+    }
+    // Roots inside other roots, like .config and .config/nvim, find the same files.
+    if roots.len() > 1 {
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        files.dedup_by(|a, b| a.0 == b.0);
+    }
+    files
+}
+
+// Sockets, FIFOs and device nodes, which can't be copied like a file.
+fn special_kind(file_type: &fs::FileType) -> Option<&'static str> {
+    if file_type.is_fifo() {
+        Some("a FIFO")
+    } else if file_type.is_socket() {
+        Some("a socket")
+    } else if file_type.is_block_device() || file_type.is_char_device() {
+        Some("a device")
+    } else {
+        None
+    }
+}
+
+// `metadata` belongs to the copy in the repository, `full_path`.
+fn sync_path(full_path: &std::path::Path,
+             base_path: &std::path::Path,
+             metadata: &fs::Metadata,
+             keep: &Keep,
+             layout: &Layout,
+             trust_mtime: bool)
+             -> Result<(), std::io::Error> {
+    let sync_path = layout.home_path(base_path);
+
+    // Files applied with `--symlink` already are the repository's copy.
+    if links_to(&sync_path, full_path) {
+        debug!(target: logger::FILES,
+               "Skipped {}, it's a link to the repository",
+               base_path.display());
+        return Ok(());
+    }
+
+    let home_metadata = match fs::metadata(&sync_path) {
+        Ok(home_metadata) => Some(home_metadata),
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => None,
+        Err(error) => return Err(error),
+    };
+
+    match home_metadata {
+        // Copying or reading a FIFO would block until something writes to it.
+        Some(ref home_metadata) if special_kind(&home_metadata.file_type()).is_some() => {
+            debug!(target: logger::FILES,
+                   "Skipped {}, it's {} in your home directory",
+                   base_path.display(),
+                   special_kind(&home_metadata.file_type()).unwrap());
+            Ok(())
+        }
+        Some(ref home_metadata) if trust_mtime && is_unchanged(home_metadata, metadata) => {
+            debug!(target: logger::FILES,
+                   "Skipped {}, it didn't change since it was copied",
+                   base_path.display());
+            Ok(())
+        }
+        // Only files of the same size can be unchanged, and the walk already knows the size.
+        Some(ref home_metadata) if home_metadata.len() == metadata.len() &&
+                                   !content_differs(&sync_path, full_path)? => {
+            debug!(target: logger::FILES,
+                   "Skipped {}, its content didn't change",
+                   base_path.display());
+            Ok(())
+        }
+        Some(_) => {
+            fs::copy(sync_path.as_path(), full_path)?;
+            debug!(target: logger::FILES,
+                   "Copied {} from your home directory",
+                   base_path.display());
+            Ok(())
+        }
+        None if keep.is_kept(base_path) => {
+            debug!(target: logger::FILES,
+                   "Kept {}, it's missing from your home directory but listed in {}",
+                   base_path.display(),
+                   ignore::KEEP_FILE);
+            Ok(())
+        }
+        None => {
+            fs::remove_file(full_path)?;
+            debug!(target: logger::FILES,
+                   "Deleted {}, it's missing from your home directory",
+                   base_path.display());
+            Ok(())
+        }
+    }
+}
+
+// A home file of the same size that's older than the repository's copy hasn't changed since
+// it was copied or installed.
+fn is_unchanged(home: &fs::Metadata, repo: &fs::Metadata) -> bool {
+    match (home.modified(), repo.modified()) {
+        (Ok(home_modified), Ok(repo_modified)) => {
+            home.len() == repo.len() && home_modified < repo_modified
+        }
+        _ => false,
+    }
+}
+
+fn apply_template(repo: &Repository,
+                  name: &Path,
+                  vars: &BTreeMap<String, String>,
+                  backup: bool)
+                  -> Result<Option<PathBuf>, FlakeError> {
+    let mut content = String::new();
+    fs::File::open(repo.workdir().unwrap().join(name))?.read_to_string(&mut content)?;
+    let rendered = template::render(&content, vars).map_err(FlakeError::Config)?;
+
+    let target_name = template::target_name(name);
+    let target = Layout::load(repo.workdir().unwrap()).home_path(&target_name);
+
+    let mut current = Vec::new();
+    let read = fs::File::open(target.as_path()).and_then(|mut f| f.read_to_end(&mut current));
+    if read.is_ok() && current == rendered.as_bytes() {
+        info!("Adopted {}, it already matches the template", target_name.display());
+        return Ok(None);
+    }
+
+    if backup && target.exists() {
+        fs::rename(target.as_path(), backup_path(target.as_path()))?;
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::File::create(target.as_path())?.write_all(rendered.as_bytes())?;
+    Ok(Some(target_name))
+}
+
+fn is_identical(a: &Path, b: &Path) -> bool {
+    a.is_file() && !links_to(a, b) && !files_differ(a, b).unwrap_or(true)
+}
+
+fn files_differ(a: &Path, b: &Path) -> Result<bool, io::Error> {
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() {
+        return Ok(true);
+    }
+    content_differs(a, b)
+}
+
+fn content_differs(a: &Path, b: &Path) -> Result<bool, io::Error> {
+    let mut content_a = Vec::new();
+    let mut content_b = Vec::new();
+    fs::File::open(a)?.read_to_end(&mut content_a)?;
+    fs::File::open(b)?.read_to_end(&mut content_b)?;
+    Ok(content_a != content_b)
+}
+
+fn restore_path(repo: &Repository, name: &Path, backup: bool) -> Result<(), std::io::Error> {
+    let source = repo.workdir().unwrap().join(name);
+    let target = Layout::load(repo.workdir().unwrap()).home_path(name);
+
+    // Copying over a link into the repository would truncate the file it points to.
+    if links_to(&target, &source) {
+        return Ok(());
+    }
+    if backup && target.exists() {
+        fs::rename(target.as_path(), backup_path(target.as_path()))?;
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::copy(source.as_path(), target.as_path()).map(|_| ())
+}
+
+// Replaces a file in the home directory with a link to the repository, Stow style.
+// Real files are always backed up, since the link would otherwise hide them for good.
+fn link_path(repo: &Repository, name: &Path) -> Result<(), std::io::Error> {
+    let source = repo.workdir().unwrap().join(name);
+    let target = Layout::load(repo.workdir().unwrap()).home_path(name);
+
+    if links_to(&target, &source) {
+        return Ok(());
+    }
+    match fs::symlink_metadata(target.as_path()) {
+        Ok(ref metadata) if metadata.file_type().is_symlink() => fs::remove_file(&target)?,
+        Ok(_) if is_identical(&target, &source) => fs::remove_file(&target)?,
+        Ok(_) => fs::rename(target.as_path(), backup_path(target.as_path()))?,
+        Err(_) => {}
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    symlink(source.as_path(), target.as_path())
+}
+
+fn links_to(link: &Path, target: &Path) -> bool {
+    let is_link = fs::symlink_metadata(link).map(|m| m.file_type().is_symlink()).unwrap_or(false);
+    if !is_link {
+        return false;
+    }
+    match (fs::canonicalize(link), fs::canonicalize(target)) {
+        (Ok(link), Ok(target)) => link == target,
+        _ => false,
+    }
+}
+
+fn is_tracked(repo: &Repository, name: &Path) -> Result<bool, Error> {
+    Ok(repo.index()?.get_path(name, 0).is_some())
+}
+
+// The paths of files in the repository relative to the home directory, without the package of
+// the subdir layout. Files that are never installed, like a README, are left out.
+fn home_names(layout: &Layout, names: &[PathBuf]) -> Vec<PathBuf> {
+    names.iter()
+        .filter(|name| layout.is_home_file(name))
+        .map(|name| layout.home_name(name))
+        .collect()
+}
+
+fn relative_to_home(path: &str) -> PathBuf {
+    let home = env::home_dir().unwrap();
+    let path = Path::new(path);
+
+    match path.strip_prefix(home.as_path()) {
+        Ok(name) => PathBuf::from(name),
+        Err(_) => PathBuf::from(path),
+    }
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".flake-bak");
+    PathBuf::from(name)
+}
+
+fn is_git_object(entry: &DirEntry) -> bool {
+    entry.file_name()
+        .to_str()
+        .map(|s| s.starts_with(".git"))
+        .unwrap_or(false)
+}
+
+fn is_store(entry: &DirEntry,
+            workdir: &Path,
+            store_in_home: Option<&PathBuf>,
+            layout: &Layout)
+            -> bool {
+    match (store_in_home, entry.path().strip_prefix(workdir)) {
+        (Some(store), Ok(name)) => {
+            !store.as_os_str().is_empty() && layout.is_home_file(name) &&
+            layout.home_name(name) == store.as_path()
+        }
+        _ => false,
+    }
+}
+
+fn is_nested_repository(entry: &DirEntry, workdir: &Path, layout: &Layout) -> bool {
+    if !entry.file_type().is_dir() || entry.path() == workdir {
+        return false;
+    }
+
+    let name = entry.path().strip_prefix(workdir).unwrap();
+    let nested = entry.path().join(".git").exists() ||
+                 (layout.is_home_file(name) && layout.home_path(name).join(".git").exists());
+    if nested {
+        debug!(target: logger::FILES,
+               "Skipped {}, it's a git repository of its own",
+               name.display());
+    }
+    nested
+}
+
+fn is_ignored(entry: &DirEntry, workdir: &Path, ignore: &Ignore) -> bool {
+    entry.path()
+        .strip_prefix(workdir)
+        .map(|name| ignore.is_ignored(name))
+        .unwrap_or(false)
+}
+
+fn git_credentials(username: &str,
+                   credentials: &CredentialStore,
+                   url: &str)
+                   -> Result<git2::Cred, Error> {
+    if is_local_url(url) {
+        debug!("{} is a local repository, it doesn't need credentials", url);
+        return Err(Error::from_str("local repositories don't use credentials"));
+    }
+
+    // Plain http is for servers on a trusted network, git sends them credentials too.
+    if url.starts_with("https://") || url.starts_with("http://") {
+        // Never log the token itself, only where it came from.
+        debug!("Looking up the access token for {} in the {} credential backend",
+               url,
+               credentials.name());
+        let host = remote_address(url).map(|(host, _)| host).unwrap_or_default();
+        let token = credentials.token_for_host(&host).map_err(|error| {
+                debug!("The {} credential backend didn't provide a token: {}",
+                       credentials.name(),
+                       error);
+                Error::from_str(error.as_str())
+            })?;
+        debug!("Using the access token from the {} credential backend", credentials.name());
+        git2::Cred::userpass_plaintext(username, token.as_str())
+    } else {
+        let home = env::home_dir().unwrap();
+        let private_key = home.join(".ssh/id_rsa");
+        let public_key = home.join(".ssh/id_rsa.pub");
+
+        debug!("{} is not an http url, skipping the {} credential backend and using the SSH key \
+                {}",
+               url,
+               credentials.name(),
+               private_key.display());
+
+        git2::Cred::ssh_key("git",
+                            Some(public_key.as_path()),
+                            private_key.as_path(),
+                            None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use ignore::{IGNORE_FILE, KEEP_FILE};
+    use testing::{commit_all, init_repo, make_fifo, serve_http, set_modified, summaries, TempDir};
+
+    // What a sync without any flag or setting uses.
+    fn options() -> SyncOptions {
+        SyncOptions {
+            max_files: DEFAULT_MAX_FILES,
+            max_bytes: DEFAULT_MAX_BYTES,
+            assume_yes: false,
+            per_file: false,
+            trailers: Vec::new(),
+            branch: None,
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            prune: false,
+            strategy: Strategy::from_name("newest-wins").unwrap(),
+            mirrors: Vec::new(),
+            proxy: None,
+            ff_only: true,
+            mtime_dates: false,
+            only_if_online: false,
+            dotfiles_only: false,
+            group_by_dir: false,
+            include_vcs: false,
+            max_commits: DEFAULT_MAX_COMMITS,
+            hooks: Hooks::load(&git2::Config::new().unwrap()),
+            no_commit: false,
+            stage: false,
+            report: None,
+            tracked_only: false,
+            profile: None,
+            commit_empty: false,
+            preserve_xattrs: false,
+            ignore_mode_changes: false,
+            jobs: DEFAULT_JOBS,
+            trust_mtime: true,
+            bootstrap: false,
+            trusted_keys: Vec::new(),
+            verify_signatures: false,
+            roots: Vec::new(),
+            local_branch: None,
+        }
+    }
+
+    // A store with a committed .vimrc and .bashrc, and then .vimrc changed, .bashrc removed and
+    // .zshrc added.
+    fn changed_store(dir: &TempDir) -> Repository {
+        let repo = init_repo(dir.path());
+        dir.write(".vimrc", "set number");
+        dir.write(".bashrc", "alias ll='ls -l'");
+        commit_all(&repo, "Initial files");
+
+        dir.write(".vimrc", "set relativenumber");
+        dir.write(".zshrc", "bindkey -e");
+        fs::remove_file(dir.path().join(".bashrc")).unwrap();
+        repo
+    }
+
+    // Captures `name` from `home` into the store, like a sync does for every tracked file.
+    fn sync_file(store: &TempDir, home: &TempDir, name: &str) -> io::Result<()> {
+        sync_file_with(store, home, name, true)
+    }
+
+    fn sync_file_with(store: &TempDir,
+                      home: &TempDir,
+                      name: &str,
+                      trust_mtime: bool)
+                      -> io::Result<()> {
+        let full_path = store.path().join(name);
+        let metadata = fs::metadata(&full_path)?;
+        sync_path(&full_path,
+                  Path::new(name),
+                  &metadata,
+                  &Keep::load(store.path()),
+                  &Layout::with_home(store.path(), home.path()),
+                  trust_mtime)
+    }
+
+    fn names(entries: Vec<(PathBuf, fs::Metadata)>) -> Vec<PathBuf> {
+        entries.into_iter().map(|entry| entry.0).collect()
+    }
+
+    // `.cache` can't match `.cache/big.bin` by its path or its file name, only pruning the
+    // directory leaves it out.
+    #[test]
+    fn ignored_directories_are_not_walked() {
+        let store = TempDir::new("walk-ignore");
+        store.write(IGNORE_FILE, ".cache\n");
+        store.write(".vimrc", "set number");
+        store.write(".cache/big.bin", "cached");
+        store.write(".cache/deep/nested.bin", "cached");
+
+        let files = tracked_files(store.path());
+        assert!(files.contains(&PathBuf::from(".vimrc")));
+        assert!(files.iter().all(|name| !name.starts_with(".cache")), "{:?}", files);
+        assert!(!files.contains(&PathBuf::from(IGNORE_FILE)));
+    }
+
+    #[test]
+    fn changes_are_committed_at_once_by_default() {
+        let dir = TempDir::new("commit-bulk");
+        let repo = changed_store(&dir);
+
+        commit_updates(&repo, &options()).unwrap();
+        assert_eq!(summaries(&repo), vec!["Update files", "Initial files"]);
+    }
+
+    #[test]
+    fn per_file_makes_a_commit_for_every_changed_file() {
+        let dir = TempDir::new("commit-per-file");
+        let repo = changed_store(&dir);
+
+        let options = SyncOptions { per_file: true, ..options() };
+        commit_updates(&repo, &options).unwrap();
+
+        let mut commits = summaries(&repo);
+        assert_eq!(commits.pop(), Some(String::from("Initial files")));
+        commits.sort();
+        assert_eq!(commits, vec!["Add .zshrc", "Remove .bashrc", "Update .vimrc"]);
+        assert_eq!(repo.statuses(None).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn per_file_commits_at_once_over_the_limit() {
+        let dir = TempDir::new("commit-limit");
+        let repo = changed_store(&dir);
+
+        let options = SyncOptions {
+            per_file: true,
+            max_commits: 2,
+            ..options()
+        };
+        commit_updates(&repo, &options).unwrap();
+        assert_eq!(summaries(&repo), vec!["Update files", "Initial files"]);
+    }
+
+    #[test]
+    fn bare_stores_are_rejected() {
+        let dir = TempDir::new("bare-store");
+        Repository::init_bare(dir.path()).unwrap();
+
+        match non_bare(Repository::open(dir.path()).unwrap()) {
+            Err(FlakeError::Config(message)) => {
+                assert!(message.starts_with("flake requires a non-bare repository"), "{}", message)
+            }
+            _ => panic!("a bare store was accepted"),
+        }
+        assert!(non_bare(init_repo(TempDir::new("non-bare-store").path())).is_ok());
+    }
+
+    // A store with `files` committed files, `changed` of them modified since.
+    fn large_store(dir: &TempDir, files: usize, changed: usize) -> Repository {
+        let repo = init_repo(dir.path());
+        for n in 0..files {
+            dir.write(&format!(".config/app{}/settings{}", n % 100, n), "default");
+        }
+        commit_all(&repo, "Initial files");
+        for n in 0..changed {
+            dir.write(&format!(".config/app{}/settings{}", n % 100, n), "changed");
+        }
+        repo
+    }
+
+    // cargo test --release -- --ignored --nocapture staging_only_changed_paths
+    #[test]
+    #[ignore]
+    fn staging_only_changed_paths_benchmark() {
+        let (files, changed, runs) = (20000, 10, 5);
+        let (mut changed_paths, mut whole_tree) = (Duration::new(0, 0), Duration::new(0, 0));
+
+        for _ in 0..runs {
+            let dir = TempDir::new("bench-changed");
+            let repo = large_store(&dir, files, changed);
+            let start = Instant::now();
+            commit_updates(&repo, &options()).unwrap();
+            changed_paths += start.elapsed();
+
+            let dir = TempDir::new("bench-add-all");
+            let repo = large_store(&dir, files, changed);
+            let start = Instant::now();
+            let mut index = repo.index().unwrap();
+            index.add_all(&["**/*"], git2::ADD_DEFAULT, None).unwrap();
+            commit_index(&repo, &mut index, "Update files", None).unwrap();
+            whole_tree += start.elapsed();
+        }
+
+        let millis = |total: Duration| {
+            (total.as_secs() * 1000 + u64::from(total.subsec_nanos()) / 1_000_000) / runs
+        };
+        println!("{} files, {} changed: changed paths {}ms, add_all {}ms",
+                 files,
+                 changed,
+                 millis(changed_paths),
+                 millis(whole_tree));
+    }
+
+    // A bare repository with a committed .vimrc, like a remote on a USB drive or a NAS.
+    fn bare_remote(dir: &TempDir) -> PathBuf {
+        let source = TempDir::new("bare-remote-source");
+        let repo = init_repo(source.path());
+        source.write(".vimrc", "set number");
+        commit_all(&repo, "Initial files");
+
+        let path = dir.path().join("dotfiles.git");
+        RepoBuilder::new().bare(true).clone(source.path().to_str().unwrap(), &path).unwrap();
+        path
+    }
+
+    // A configuration that keeps the store in `dir`.
+    fn store_config(dir: &TempDir) -> git2::Config {
+        let mut config = git2::Config::open(&dir.path().join("config")).unwrap();
+        config.set_str("flake.store", dir.path().join("store").to_str().unwrap()).unwrap();
+        config
+    }
+
+    // Clones `url` into a store kept in `home`, as the user flake.
+    fn clone_store(url: &str, home: &TempDir, credentials: &CredentialStore) -> Repository {
+        init_storage(url, &store_config(home), "flake", credentials, None).unwrap().0
+    }
+
+    #[test]
+    fn stores_are_cloned_from_local_bare_repositories() {
+        let remote = TempDir::new("clone-local-remote");
+        let home = TempDir::new("clone-local-home");
+        let url = bare_remote(&remote);
+
+        let repo = clone_store(url.to_str().unwrap(), &home, &no_credentials());
+        assert!(repo.workdir().unwrap().join(".vimrc").is_file());
+        assert_eq!(repo.find_remote("origin").unwrap().url(),
+                   Some(fs::canonicalize(&url).unwrap().to_str().unwrap()));
+    }
+
+    #[test]
+    fn stores_are_cloned_from_file_urls() {
+        let remote = TempDir::new("clone-file-remote");
+        let home = TempDir::new("clone-file-home");
+        let url = format!("file://{}", bare_remote(&remote).display());
+
+        let repo = clone_store(&url, &home, &no_credentials());
+        assert!(repo.workdir().unwrap().join(".vimrc").is_file());
+        assert_eq!(repo.find_remote("origin").unwrap().url(), Some(url.as_str()));
+    }
+
+    // A file backend holding `token`, with the permissions it requires.
+    fn token_file(dir: &TempDir, token: &str) -> CredentialStore {
+        let path = dir.write("credentials", token);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        CredentialStore::File { path }
+    }
+
+    #[test]
+    fn stores_are_cloned_from_remotes_that_require_credentials() {
+        let remote = TempDir::new("clone-http-remote");
+        let home = TempDir::new("clone-http-home");
+        bare_remote(&remote);
+        let url = format!("{}/dotfiles.git", serve_http(remote.path(), "flake", "s3cret"));
+
+        let repo = clone_store(&url, &home, &token_file(&home, "s3cret"));
+        assert!(repo.workdir().unwrap().join(".vimrc").is_file());
+
+        let other = TempDir::new("clone-http-wrong-token");
+        let config = store_config(&other);
+        let credentials = token_file(&other, "wrong");
+        assert!(init_storage(&url, &config, "flake", &credentials, None).is_err());
+        assert!(!other.path().join("store").exists());
+    }
+
+    #[test]
+    fn apply_adopts_the_home_files_that_already_match() {
+        let store = TempDir::new("adopt-store");
+        let home = TempDir::new("adopt-home");
+        let names = [".vimrc", ".bashrc", ".zshrc", ".gitconfig"];
+        for name in &names {
+            store.write(name, &format!("{} from the repository", name));
+        }
+        home.write(".vimrc", ".vimrc from the repository");
+        home.write(".bashrc", ".bashrc from the repository");
+        home.write(".zshrc", ".zshrc edited at home");
+
+        let adopted: Vec<&str> = names.iter()
+            .cloned()
+            .filter(|name| is_identical(&home.path().join(name), &store.path().join(name)))
+            .collect();
+        assert_eq!(adopted, vec![".vimrc", ".bashrc"]);
+    }
+
+    #[test]
+    fn files_missing_from_home_are_deleted_from_the_store() {
+        let store = TempDir::new("sync-deleted-store");
+        let home = TempDir::new("sync-deleted-home");
+        store.write(".vimrc", "set number");
+
+        sync_file(&store, &home, ".vimrc").unwrap();
+        assert!(!store.path().join(".vimrc").exists());
+    }
+
+    #[test]
+    fn kept_files_missing_from_home_stay_in_the_store() {
+        let store = TempDir::new("sync-kept-store");
+        let home = TempDir::new("sync-kept-home");
+        store.write(KEEP_FILE, ".ssh/config\n");
+        store.write(".ssh/config", "Host laptop");
+
+        sync_file(&store, &home, ".ssh/config").unwrap();
+        assert!(store.path().join(".ssh/config").exists());
+    }
+
+    // Local remotes need no credentials, the file backend is never read.
+    fn no_credentials() -> CredentialStore {
+        CredentialStore::File { path: PathBuf::from("/nonexistent/flake-credentials") }
+    }
+
+    // A store whose origin is the bare repository in `remote`.
+    fn store_with_origin(store: &TempDir, remote: &TempDir) -> Repository {
+        Repository::init_bare(remote.path()).unwrap();
+        let repo = init_repo(store.path());
+        repo.remote("origin", remote.path().to_str().unwrap()).unwrap();
+        repo
+    }
+
+    fn remote_branch(remote: &TempDir, branch: &str) -> Option<git2::Oid> {
+        let remote = Repository::open(remote.path()).unwrap();
+        remote.refname_to_id(&format!("refs/heads/{}", branch)).ok()
+    }
+
+    fn syncer(repo: Repository, credentials: CredentialStore, options: SyncOptions) -> Syncer {
+        Syncer {
+            username: String::from("flake"),
+            credentials,
+            repo,
+            options,
+            branch: RefCell::new(None),
+            first_sync: Cell::new(false),
+        }
+    }
+
+    #[test]
+    fn syncs_push_to_every_mirror_after_origin() {
+        let store = TempDir::new("mirrors-store");
+        let origin = TempDir::new("mirrors-origin");
+        let usb = TempDir::new("mirrors-usb");
+        let nas = TempDir::new("mirrors-nas");
+        let repo = store_with_origin(&store, &origin);
+        Repository::init_bare(usb.path()).unwrap();
+        Repository::init_bare(nas.path()).unwrap();
+        store.write(".vimrc", "set number");
+        let head = commit_all(&repo, "Add vimrc");
+
+        let mut config = git2::Config::open(&store.path().join("flake-config")).unwrap();
+        config.set_multivar("github.dotfiles-mirror", "^$", usb.path().to_str().unwrap()).unwrap();
+        config.set_multivar("github.dotfiles-mirror", "^$", nas.path().to_str().unwrap()).unwrap();
+        let mut options = options();
+        options.mirrors = mirror_urls(&config);
+        assert_eq!(options.mirrors.len(), 2);
+
+        syncer(repo, no_credentials(), options).push(false).unwrap();
+        assert_eq!(remote_branch(&origin, DEFAULT_BRANCH), Some(head));
+        assert_eq!(remote_branch(&usb, DEFAULT_BRANCH), Some(head));
+        assert_eq!(remote_branch(&nas, DEFAULT_BRANCH), Some(head));
+    }
+
+    fn push_origin(repo: &Repository, options: &SyncOptions) -> Result<(), Error> {
+        push_master("flake", &no_credentials(), repo, options, None, DEFAULT_BRANCH, false)
+    }
+
+    #[test]
+    fn fast_forwards_are_pushed() {
+        let store = TempDir::new("push-ff-store");
+        let remote = TempDir::new("push-ff-remote");
+        let repo = store_with_origin(&store, &remote);
+        store.write(".vimrc", "set number");
+        commit_all(&repo, "Add vimrc");
+        push_origin(&repo, &options()).unwrap();
+
+        store.write(".vimrc", "set nonumber");
+        let second = commit_all(&repo, "Update vimrc");
+        push_origin(&repo, &options()).unwrap();
+        assert_eq!(remote_branch(&remote, DEFAULT_BRANCH), Some(second));
+    }
+
+    #[test]
+    fn diverged_pushes_are_skipped() {
+        let store = TempDir::new("push-diverged-store");
+        let remote = TempDir::new("push-diverged-remote");
+        let repo = store_with_origin(&store, &remote);
+        store.write(".vimrc", "set number");
+        commit_all(&repo, "Add vimrc");
+        push_origin(&repo, &options()).unwrap();
+
+        // Another machine pushes while this one commits.
+        let laptop = TempDir::new("push-diverged-laptop");
+        RepoBuilder::new().clone(remote.path().to_str().unwrap(), laptop.path()).unwrap();
+        let other = init_repo(laptop.path());
+        laptop.write(".bashrc", "set -o vi");
+        let theirs = commit_all(&other, "Add bashrc");
+        push_origin(&other, &options()).unwrap();
+
+        store.write(".vimrc", "set nonumber");
+        let ours = commit_all(&repo, "Update vimrc");
+        assert!(push_origin(&repo, &options()).is_err());
+        assert_eq!(remote_branch(&remote, DEFAULT_BRANCH), Some(theirs));
+
+        let options = SyncOptions { ff_only: false, ..options() };
+        push_origin(&repo, &options).unwrap();
+        assert_eq!(remote_branch(&remote, DEFAULT_BRANCH), Some(theirs));
+        assert_eq!(repo.refname_to_id("HEAD").unwrap(), ours);
+    }
+
+    #[test]
+    fn unreachable_remotes_are_offline() {
+        // Nothing listens on a port once its listener is gone.
+        let port = ::std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = format!("https://127.0.0.1:{}/calavera/dotfiles.git", port);
+        assert!(!is_reachable(&url, Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn listening_remotes_are_online() {
+        let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ssh://git@127.0.0.1:{}/dotfiles.git",
+                          listener.local_addr().unwrap().port());
+        assert!(is_reachable(&url, Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn local_remotes_are_always_online() {
+        assert!(is_reachable("/srv/git/dotfiles.git", Duration::from_secs(1)));
+        assert!(is_reachable("file:///srv/git/dotfiles.git", Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn remote_addresses_have_the_default_port_of_their_scheme() {
+        assert_eq!(remote_address("https://github.com/calavera/dotfiles"),
+                   Some((String::from("github.com"), 443)));
+        assert_eq!(remote_address("git@github.com:calavera/dotfiles.git"),
+                   Some((String::from("github.com"), 22)));
+        assert_eq!(remote_address("ssh://git@example.com:2222/dotfiles.git"),
+                   Some((String::from("example.com"), 2222)));
+    }
+
+    // cargo test --release -- --ignored --nocapture single_pass_walk
+    // With 20000 files, a release build took 74ms for the single pass and 118ms to walk and stat.
+    #[test]
+    #[ignore]
+    fn single_pass_walk_benchmark() {
+        let (files, runs) = (20000, 5);
+        let dir = TempDir::new("bench-walk");
+        large_store(&dir, files, 0);
+        let (mut single_pass, mut two_passes) = (Duration::new(0, 0), Duration::new(0, 0));
+
+        for _ in 0..runs {
+            let start = Instant::now();
+            let entries = tracked_entries(dir.path(), &[], true);
+            single_pass += start.elapsed();
+            assert_eq!(entries.len(), files);
+
+            // What capturing did before, a walk for the names and a stat for every one of them.
+            let start = Instant::now();
+            let metadata: Vec<fs::Metadata> = tracked_files(dir.path())
+                .iter()
+                .map(|name| fs::metadata(dir.path().join(name)).unwrap())
+                .collect();
+            two_passes += start.elapsed();
+            assert_eq!(metadata.len(), files);
+        }
+
+        let millis = |total: Duration| {
+            (total.as_secs() * 1000 + u64::from(total.subsec_nanos()) / 1_000_000) / runs
+        };
+        println!("{} files: single pass {}ms, walk and stat {}ms",
+                 files,
+                 millis(single_pass),
+                 millis(two_passes));
+    }
+
+    #[test]
+    fn nested_repositories_are_skipped_unless_included() {
+        let store = TempDir::new("walk-nested");
+        store.write(".config/nvim/init.vim", "set number");
+        store.write(".config/flake-test-project/.git/HEAD", "ref: refs/heads/master");
+        store.write(".config/flake-test-project/src/main.rs", "fn main() {}");
+
+        let files = names(tracked_entries(store.path(), &[], false));
+        assert_eq!(files, vec![PathBuf::from(".config/nvim/init.vim")]);
+
+        let mut files = names(tracked_entries(store.path(), &[], true));
+        files.sort();
+        assert_eq!(files,
+                   vec![PathBuf::from(".config/flake-test-project/src/main.rs"),
+                        PathBuf::from(".config/nvim/init.vim")]);
+    }
+
+    // The files a walk of a store at ~/.dotfiles finds, with `store_in_home` as given.
+    fn store_walk(store: &Path, store_in_home: Option<&PathBuf>) -> Vec<PathBuf> {
+        let layout = Layout::with_home(store, store.parent().unwrap());
+        let mut files: Vec<PathBuf> = WalkDir::new(store)
+            .into_iter()
+            .filter_entry(|e| !is_git_object(e) && !is_store(e, store, store_in_home, &layout))
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .map(|e| PathBuf::from(e.path().strip_prefix(store).unwrap()))
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn the_store_is_not_walked_into_itself() {
+        let home = TempDir::new("store-in-home");
+        let store = home.path().join(".dotfiles");
+        home.write(".dotfiles/.vimrc", "set number");
+        home.write(".dotfiles/.dotfiles/.vimrc", "set number");
+        home.write(".dotfiles/.dotfilesrc", "verbose");
+
+        let in_home = PathBuf::from(".dotfiles");
+        assert_eq!(store_walk(&store, Some(&in_home)),
+                   vec![PathBuf::from(".dotfilesrc"), PathBuf::from(".vimrc")]);
+        assert_eq!(store_walk(&store, None),
+                   vec![PathBuf::from(".dotfiles/.vimrc"),
+                        PathBuf::from(".dotfilesrc"),
+                        PathBuf::from(".vimrc")]);
+    }
+
+    #[test]
+    fn verify_catches_files_changed_after_applying_them() {
+        let store = TempDir::new("verify-store");
+        let home = TempDir::new("verify-home");
+        let repo = init_repo(store.path());
+        store.write(".vimrc", "set number");
+        store.write(".netrc", "machine github.com");
+        let layout = Layout::with_home(store.path(), home.path());
+        let permissions = Permissions::load(&git2::Config::new().unwrap());
+
+        let files = vec![PathBuf::from(".vimrc"), PathBuf::from(".netrc")];
+        for name in &files {
+            fs::copy(store.path().join(name), layout.home_path(name)).unwrap();
+            permissions.restrict(&layout.home_path(name), name).unwrap();
+        }
+        let verify = || {
+            verify_apply(&repo, &layout, &permissions, &files, &files, &BTreeMap::new(), false)
+        };
+        assert!(verify().is_ok());
+
+        // Something rewrites .vimrc between apply writing it and reading it back.
+        home.write(".vimrc", "set nonumber");
+        match verify() {
+            Err(FlakeError::Drift(message)) => assert!(message.starts_with("1 applied files")),
+            _ => panic!("the rewritten file was verified"),
+        }
+
+        home.write(".vimrc", "set number");
+        fs::set_permissions(home.path().join(".netrc"), fs::Permissions::from_mode(0o644))
+            .unwrap();
+        match verify() {
+            Err(FlakeError::Drift(message)) => assert!(message.starts_with("1 applied files")),
+            _ => panic!("the reopened credentials file was verified"),
+        }
+    }
+
+    #[test]
+    fn mirrors_on_other_hosts_use_their_own_token() {
+        let store = TempDir::new("host-tokens-store");
+        let origin = TempDir::new("host-tokens-origin");
+        let mirror = TempDir::new("host-tokens-mirror");
+        Repository::init_bare(origin.path().join("dotfiles.git")).unwrap();
+        Repository::init_bare(mirror.path().join("dotfiles.git")).unwrap();
+        let origin_url = format!("{}/dotfiles.git", serve_http(origin.path(), "flake", "github"));
+        // The same server under another name is another host.
+        let mirror_url = format!("{}/dotfiles.git", serve_http(mirror.path(), "flake", "nas"))
+            .replacen("127.0.0.1", "localhost", 1);
+
+        let repo = init_repo(store.path());
+        repo.remote("origin", &origin_url).unwrap();
+        store.write(".vimrc", "set number");
+        let head = commit_all(&repo, "Add vimrc");
+
+        let credentials = token_file(&store, "github");
+        let nas = store.write("credentials@localhost", "nas");
+        fs::set_permissions(&nas, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let options = SyncOptions { mirrors: vec![mirror_url], ..options() };
+        syncer(repo, credentials, options).push(false).unwrap();
+        let pushed = |dir: &TempDir| {
+            let remote = Repository::open(dir.path().join("dotfiles.git")).unwrap();
+            remote.refname_to_id(&format!("refs/heads/{}", DEFAULT_BRANCH)).ok()
+        };
+        assert_eq!(pushed(&origin), Some(head));
+        assert_eq!(pushed(&mirror), Some(head));
+    }
+
+    fn watch_ignores(globs: &[&str]) -> Vec<Pattern> {
+        WATCH_JUNK.iter().chain(globs).map(|glob| Pattern::new(glob).unwrap()).collect()
+    }
+
+    #[test]
+    fn swap_files_and_ignored_globs_do_not_sync() {
+        let home = Path::new("/home/flake");
+        let ignored = watch_ignores(&[".zsh_history", ".config/*/history"]);
+
+        assert!(is_watch_ignored(&ignored, home, &home.join(".vimrc.swp")));
+        assert!(is_watch_ignored(&ignored, home, &home.join(".config/nvim/.init.vim.swp")));
+        assert!(is_watch_ignored(&ignored, home, &home.join(".bashrc~")));
+        assert!(is_watch_ignored(&ignored, home, &home.join(".zsh_history")));
+        assert!(is_watch_ignored(&ignored, home, &home.join(".config/fish/history")));
+        assert!(!is_watch_ignored(&ignored, home, &home.join(".config/fish/config.fish")));
+        assert!(!is_watch_ignored(&ignored, home, &home.join(".vimrc")));
+    }
+
+    #[test]
+    fn rapid_changes_sync_once() {
+        let home = TempDir::new("watch-rapid");
+        let vimrc = home.write(".vimrc", "set number");
+        let history = home.write(".zsh_history", "ls");
+        let swap = home.write(".vimrc.swp", "");
+        let tracked: BTreeSet<PathBuf> = vec![vimrc, history, swap].into_iter().collect();
+        let ignored = watch_ignores(&[".zsh_history"]);
+
+        let (tx, rx) = channel();
+        let mut watcher = notify::watcher(tx, Duration::from_millis(500)).unwrap();
+        watcher.watch(home.path(), RecursiveMode::NonRecursive).unwrap();
+        for i in 0..10 {
+            home.write(".vimrc", &format!("set tabstop={}", i));
+            home.write(".vimrc.swp", &format!("{}", i));
+            home.write(".zsh_history", &format!("ls {}", i));
+        }
+
+        let mut syncs = 0;
+        while let Ok(event) = rx.recv_timeout(Duration::from_secs(2)) {
+            if is_watched_change(&event, &tracked, &ignored, home.path()) {
+                syncs += 1;
+            }
+        }
+        assert_eq!(syncs, 1);
+    }
+
+    #[test]
+    fn plans_list_every_operation_of_the_next_sync() {
+        let store = TempDir::new("plan-store");
+        let origin = TempDir::new("plan-origin");
+        let home = TempDir::new("plan-home");
+        let repo = store_with_origin(&store, &origin);
+        store.write(".vimrc", "set number");
+        store.write(".bashrc", "alias ll='ls -l'");
+        let base = commit_all(&repo, "Add dotfiles");
+        store.write(".gitconfig", "[user]");
+        commit_all(&repo, "Add gitconfig");
+        push_origin(&repo, &options()).unwrap();
+        repo.reset(&repo.find_object(base, None).unwrap(), git2::ResetType::Hard, None).unwrap();
+
+        home.write(".vimrc", "set nonumber");
+        let layout = Layout::with_home(store.path(), home.path());
+        let plan = syncer(repo, no_credentials(), options()).plan_for(&layout).unwrap();
+
+        let expected = r#"{
+  "ahead": 0,
+  "behind": 1,
+  "capture": [
+    {
+      "action": "Remove",
+      "path": ".bashrc"
+    },
+    {
+      "action": "Update",
+      "path": ".vimrc"
+    }
+  ],
+  "commits": [
+    {
+      "files": [
+        ".bashrc",
+        ".vimrc"
+      ],
+      "message": "Update files"
+    }
+  ],
+  "conflicts": [],
+  "install": [
+    ".gitconfig"
+  ],
+  "prune": [],
+  "push": {
+    "branch": "master",
+    "credentials": "file",
+    "mirrors": [],
+    "remote": "ORIGIN"
+  },
+  "version": 1
+}"#;
+        let origin = origin.path().to_str().unwrap();
+        assert_eq!(plan.pretty().to_string().replace(origin, "ORIGIN"), expected);
+        assert!(!home.path().join(".gitconfig").exists());
+    }
+
+    #[test]
+    fn batches_commit_and_push_every_path_they_can_add() {
+        let store = TempDir::new("batch-store");
+        let origin = TempDir::new("batch-origin");
+        let home = TempDir::new("batch-home");
+        let repo = store_with_origin(&store, &origin);
+        store.write(".vimrc", "set number");
+        commit_all(&repo, "Add vimrc");
+        push_origin(&repo, &options()).unwrap();
+
+        home.write(".bashrc", "alias ll='ls -l'");
+        home.write(".config/git/config", "[user]");
+        let layout = Layout::with_home(store.path(), home.path());
+        let input = io::Cursor::new("  .bashrc\n\n.config/git/config\n.missing\n");
+        let syncer = syncer(repo, no_credentials(), options());
+        assert!(add_lines(&syncer, home.path(), &layout, input).is_err());
+
+        let head = syncer.repo.refname_to_id("HEAD").unwrap();
+        let head = syncer.repo.find_commit(head).unwrap();
+        assert_eq!(head.message(), Some("Add 2 files"));
+        let tree = head.tree().unwrap();
+        assert!(tree.get_path(Path::new(".bashrc")).is_ok());
+        assert!(tree.get_path(Path::new(".config/git/config")).is_ok());
+        assert!(tree.get_path(Path::new(".missing")).is_err());
+        assert_eq!(remote_branch(&origin, DEFAULT_BRANCH), Some(head.id()));
+    }
+
+    #[test]
+    fn fifos_are_skipped() {
+        let store = TempDir::new("walk-fifo-store");
+        let home = TempDir::new("walk-fifo-home");
+        store.write(".vimrc", "set number");
+        make_fifo(&store.path().join(".vim-server"));
+        assert_eq!(names(tracked_entries(store.path(), &[], false)),
+                   vec![PathBuf::from(".vimrc")]);
+
+        // Reading the FIFO would block the sync until something writes to it.
+        make_fifo(&home.path().join(".vimrc"));
+        sync_file(&store, &home, ".vimrc").unwrap();
+        let mut content = String::new();
+        fs::File::open(store.path().join(".vimrc"))
+            .and_then(|mut file| file.read_to_string(&mut content))
+            .unwrap();
+        assert_eq!(content, "set number");
+    }
+
+    #[test]
+    fn filters_are_the_ones_git_clone_accepts() {
+        assert!(check_filter("blob:none").is_ok());
+        assert!(check_filter("blob:limit=1m").is_ok());
+        assert!(check_filter("blob:limit=2048").is_ok());
+        assert!(check_filter("tree:0").is_ok());
+        assert!(check_filter("blob:limit=").is_err());
+        assert!(check_filter("blob:some").is_err());
+        assert!(check_filter("blob:limit=k").is_err());
+        assert!(check_filter("tree:").is_err());
+        assert!(check_filter("none").is_err());
+    }
+
+    // A server that takes a single connection and holds it until `expected` connections reached
+    // any of the servers sharing `arrived`, or a few seconds went by. Then it answers 404, so the
+    // push fails. `overlapped` counts the connections that saw all the others while they waited.
+    fn stalled_server(arrived: &Arc<AtomicUsize>,
+                      overlapped: &Arc<AtomicUsize>,
+                      expected: usize)
+                      -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/dotfiles.git", listener.local_addr().unwrap());
+        let (arrived, overlapped) = (arrived.clone(), overlapped.clone());
+        thread::spawn(move || if let Ok((mut stream, _)) = listener.accept() {
+            let mut request = Vec::new();
+            let mut byte = [0; 1];
+            while !request.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap_or(0) == 1 {
+                request.push(byte[0]);
+            }
+            arrived.fetch_add(1, Ordering::SeqCst);
+            let deadline = Instant::now() + Duration::from_secs(3);
+            while arrived.load(Ordering::SeqCst) < expected && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+            if arrived.load(Ordering::SeqCst) >= expected {
+                overlapped.fetch_add(1, Ordering::SeqCst);
+            }
+            let _ = stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\
+                                       Connection: close\r\n\r\n");
+        });
+        url
+    }
+
+    #[test]
+    fn mirrors_are_pushed_at_the_same_time() {
+        let store = TempDir::new("mirrors-jobs-store");
+        let repo = init_repo(store.path());
+        store.write(".vimrc", "set number");
+        commit_all(&repo, "Add vimrc");
+
+        let arrived = Arc::new(AtomicUsize::new(0));
+        let overlapped = Arc::new(AtomicUsize::new(0));
+        let mut options = options();
+        options.mirrors = vec![stalled_server(&arrived, &overlapped, 2),
+                               stalled_server(&arrived, &overlapped, 2)];
+        options.jobs = 2;
+
+        let results = syncer(repo, no_credentials(), options).push_mirrors(DEFAULT_BRANCH, false);
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|result| result.1.is_err()));
+        assert_eq!(overlapped.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn mirror_pushes_wait_for_a_free_job() {
+        let store = TempDir::new("mirrors-one-job-store");
+        let repo = init_repo(store.path());
+        let usb = TempDir::new("mirrors-one-job-usb");
+        let nas = TempDir::new("mirrors-one-job-nas");
+        Repository::init_bare(usb.path()).unwrap();
+        Repository::init_bare(nas.path()).unwrap();
+        store.write(".vimrc", "set number");
+        let head = commit_all(&repo, "Add vimrc");
+
+        let mut options = options();
+        options.mirrors = vec![String::from(usb.path().to_str().unwrap()),
+                               String::from(nas.path().to_str().unwrap())];
+        options.jobs = 1;
+
+        let results = syncer(repo, no_credentials(), options).push_mirrors(DEFAULT_BRANCH, false);
+        assert!(results.iter().all(|result| result.1.is_ok()));
+        assert_eq!(remote_branch(&usb, DEFAULT_BRANCH), Some(head));
+        assert_eq!(remote_branch(&nas, DEFAULT_BRANCH), Some(head));
+    }
+
+    #[test]
+    fn restored_files_are_only_captured_without_trusting_mtimes() {
+        let store = TempDir::new("trust-mtime-store");
+        let home = TempDir::new("trust-mtime-home");
+        store.write(".vimrc", "set number");
+        home.write(".vimrc", "set nowrap");
+        // Restored with an old modification time, and the same size.
+        set_modified(&home.path().join(".vimrc"), 1_000_000_000);
+
+        let content = || {
+            let mut content = String::new();
+            fs::File::open(store.path().join(".vimrc"))
+                .and_then(|mut file| file.read_to_string(&mut content))
+                .unwrap();
+            content
+        };
+        sync_file_with(&store, &home, ".vimrc", true).unwrap();
+        assert_eq!(content(), "set number");
+        sync_file_with(&store, &home, ".vimrc", false).unwrap();
+        assert_eq!(content(), "set nowrap");
+    }
+
+    #[test]
+    fn only_the_roots_are_walked() {
+        let store = TempDir::new("walk-roots");
+        store.write(".vimrc", "set number");
+        store.write(".bashrc", "set -o vi");
+        store.write(".config/nvim/init.vim", "set number");
+        store.write(".config/fish/config.fish", "set -x EDITOR vim");
+
+        let roots = vec![PathBuf::from(".config/nvim")];
+        assert_eq!(names(tracked_entries(store.path(), &roots, false)),
+                   vec![PathBuf::from(".config/nvim/init.vim")]);
+
+        let roots = vec![PathBuf::from(".config/nvim"),
+                         PathBuf::from(".vimrc"),
+                         PathBuf::from(".config"),
+                         PathBuf::from(".missing")];
+        assert_eq!(names(tracked_entries(store.path(), &roots, false)),
+                   vec![PathBuf::from(".config/fish/config.fish"),
+                        PathBuf::from(".config/nvim/init.vim"),
+                        PathBuf::from(".vimrc")]);
+    }
+
+    #[test]
+    fn roots_are_found_in_every_package() {
+        let store = TempDir::new("walk-roots-subdir");
+        store.write(layout::LAYOUT_FILE, "subdir\n");
+        store.write("vim/.vimrc", "set number");
+        store.write("nvim/.config/nvim/init.vim", "set number");
+        store.write("fish/.config/fish/config.fish", "set -x EDITOR vim");
+
+        let roots = vec![PathBuf::from(".config/nvim"), PathBuf::from(".vimrc")];
+        assert_eq!(names(tracked_entries(store.path(), &roots, false)),
+                   vec![PathBuf::from("nvim/.config/nvim/init.vim"),
+                        PathBuf::from("vim/.vimrc")]);
+    }
+
+    #[test]
+    fn roots_are_relative_to_home() {
+        let dir = TempDir::new("capture-roots");
+        let mut config = git2::Config::open(&dir.write("config", "")).unwrap();
+        config.set_multivar("flake.root", "^$", "~/.config/nvim").unwrap();
+        config.set_multivar("flake.root", "^$", ".vimrc").unwrap();
+
+        assert_eq!(capture_roots(&config),
+                   vec![PathBuf::from(".config/nvim"), PathBuf::from(".vimrc")]);
+    }
+
+    #[test]
+    fn the_local_branch_is_pushed_to_the_remote_branch() {
+        let store = TempDir::new("push-mapped-store");
+        let remote = TempDir::new("push-mapped-remote");
+        let repo = store_with_origin(&store, &remote);
+        store.write(".vimrc", "set number");
+        let first = commit_all(&repo, "Add vimrc");
+        repo.branch("laptop", &repo.find_commit(first).unwrap(), false).unwrap();
+        repo.set_head("refs/heads/laptop").unwrap();
+        store.write(".vimrc", "set nonumber");
+        let second = commit_all(&repo, "Update vimrc");
+
+        let mut options = options();
+        options.local_branch = Some(String::from("laptop"));
+        push_master("flake", &no_credentials(), &repo, &options, None, "master", false).unwrap();
+        assert_eq!(remote_branch(&remote, "master"), Some(second));
+        assert_eq!(remote_branch(&remote, "laptop"), None);
+
+        store.write(".bashrc", "set -o vi");
+        let third = commit_all(&repo, "Add bashrc");
+        push_master("flake", &no_credentials(), &repo, &options, None, "master", false).unwrap();
+        assert_eq!(remote_branch(&remote, "master"), Some(third));
+        assert_eq!(repo.refname_to_id("refs/heads/master").unwrap(), first);
+    }
+
+    #[test]
+    fn tracked_files_are_the_ones_in_the_index() {
+        let store = TempDir::new("tracked-store");
+        let repo = init_repo(store.path());
+        store.write(".vimrc", "set number");
+        commit_all(&repo, "Add vimrc");
+        store.write(".bashrc", "set -o vi");
+
+        assert!(is_tracked(&repo, Path::new(".vimrc")).unwrap());
+        assert!(!is_tracked(&repo, Path::new(".bashrc")).unwrap());
+    }
+
+    // A store with two commits pushed to origin, its history squashed into one commit that's
+    // only local, like prune-history leaves it.
+    fn squashed_store(store: &TempDir, remote: &TempDir) -> Repository {
+        let repo = store_with_origin(store, remote);
+        store.write(".vimrc", "set number");
+        commit_all(&repo, "Add vimrc");
+        store.write(".bashrc", "set -o vi");
+        commit_all(&repo, "Add bashrc");
+        push_master("flake", &no_credentials(), &repo, &options(), None, "master", false).unwrap();
+        fetch_remote("flake", &no_credentials(), &repo, &options(), None).unwrap();
+
+        let (head, _) = history::squash(&repo, None, false).unwrap().unwrap();
+        repo.reference("refs/heads/master", head, true, "squash").unwrap();
+        repo
+    }
+
+    #[test]
+    fn rewritten_history_is_force_pushed() {
+        let store = TempDir::new("force-push-store");
+        let remote = TempDir::new("force-push-remote");
+        let repo = squashed_store(&store, &remote);
+        let head = repo.refname_to_id("refs/heads/master").unwrap();
+
+        push_master("flake", &no_credentials(), &repo, &options(), None, "master", true).unwrap();
+        assert_eq!(remote_branch(&remote, "master"), Some(head));
+    }
+
+    #[test]
+    fn force_pushes_abort_when_the_remote_moved() {
+        let store = TempDir::new("force-push-moved-store");
+        let remote = TempDir::new("force-push-moved-remote");
+        let repo = squashed_store(&store, &remote);
+
+        // Another machine pushes after the store last fetched.
+        let other_store = TempDir::new("force-push-moved-other");
+        let other = Repository::clone(remote.path().to_str().unwrap(), other_store.path())
+            .unwrap();
+        other.config().unwrap().set_str("user.name", "Flake Test").unwrap();
+        other.config().unwrap().set_str("user.email", "test@example.com").unwrap();
+        other_store.write(".zshrc", "bindkey -v");
+        let pushed = commit_all(&other, "Add zshrc");
+        push_master("flake", &no_credentials(), &other, &options(), None, "master", false)
+            .unwrap();
+        assert_eq!(remote_branch(&remote, "master"), Some(pushed));
+
+        let error = push_master("flake", &no_credentials(), &repo, &options(), None, "master", true)
+            .unwrap_err();
+        assert!(error.message().contains("moved since it was fetched"));
+        assert_eq!(remote_branch(&remote, "master"), Some(pushed));
     }
 }
@@ -0,0 +1,293 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use git2::{Delta, Error, Oid, Repository, Tree};
+
+use ignore::{Ignore, Keep};
+use layout::Layout;
+use template;
+
+// Which side wins when a file changed in both the home directory and the repository.
+#[derive(Clone)]
+pub enum Strategy {
+    Home,
+    Repo,
+    Newest,
+}
+
+impl Strategy {
+    pub fn from_name(name: &str) -> Result<Strategy, String> {
+        match name {
+            "home-wins" => Ok(Strategy::Home),
+            "repo-wins" => Ok(Strategy::Repo),
+            "newest-wins" => Ok(Strategy::Newest),
+            _ => {
+                Err(format!("unknown strategy `{}`, use home-wins, repo-wins or newest-wins",
+                            name))
+            }
+        }
+    }
+
+    // Whether the home version of a conflicting file should be kept.
+    pub fn keeps_home(&self,
+                      repo: &Repository,
+                      base: Oid,
+                      remote: Oid,
+                      layout: &Layout,
+                      name: &Path)
+                      -> Result<bool, Error> {
+        match *self {
+            Strategy::Home => Ok(true),
+            Strategy::Repo => Ok(false),
+            Strategy::Newest => {
+                // A file deleted from home has no time to compare, so the repository wins.
+                let modified = match modified_time(&layout.home_path(name)) {
+                    Some(modified) => modified,
+                    None => return Ok(false),
+                };
+                Ok(modified >= last_change(repo, base, remote, name)?)
+            }
+        }
+    }
+}
+
+// A three-way comparison between the last commit both sides agreed on (the base),
+// the remote repository, and the files in the home directory.
+//
+// Files that only changed in the repository go to the home directory, files that only
+// changed in the home directory are captured as usual, and files that changed on both
+// sides to different contents are conflicts.
+pub struct Plan {
+    // Files to install in the home directory from the remote tree.
+    pub to_home: Vec<PathBuf>,
+    // Files deleted in the remote that are unchanged in the home directory.
+    pub deleted: Vec<PathBuf>,
+    // Files changed differently in the home directory and in the remote.
+    pub conflicts: Vec<PathBuf>,
+}
+
+pub fn plan(repo: &Repository,
+            base: &Tree,
+            remote: &Tree,
+            layout: &Layout,
+            ignore: &Ignore,
+            keep: &Keep)
+            -> Result<Plan, Error> {
+    let mut plan = Plan {
+        to_home: Vec::new(),
+        deleted: Vec::new(),
+        conflicts: Vec::new(),
+    };
+
+    let diff = repo.diff_tree_to_tree(Some(base), Some(remote), None)?;
+    for delta in diff.deltas() {
+        let name = match delta.new_file().path().or_else(|| delta.old_file().path()) {
+            Some(name) => PathBuf::from(name),
+            None => continue,
+        };
+        // Templates are rendered by `apply`, and ignored files never leave the repository.
+        if template::is_template(&name) || ignore.is_ignored(&name) ||
+           !layout.is_home_file(&name) {
+            continue;
+        }
+
+        let base_content = content_at(repo, base, &name)?;
+        let remote_content = content_at(repo, remote, &name)?;
+        let home_content = read_file(&layout.home_path(&name));
+        // Kept files missing from home belong to other machines, leave them alone.
+        if home_content.is_none() && keep.is_kept(&name) {
+            continue;
+        }
+
+        if home_content == remote_content {
+            continue;
+        }
+
+        if home_content != base_content {
+            plan.conflicts.push(name);
+            continue;
+        }
+
+        match delta.status() {
+            Delta::Deleted => plan.deleted.push(name),
+            _ => plan.to_home.push(name),
+        }
+    }
+
+    Ok(plan)
+}
+
+fn content_at(repo: &Repository, tree: &Tree, name: &Path) -> Result<Option<Vec<u8>>, Error> {
+    let entry = match tree.get_path(name) {
+        Ok(entry) => entry,
+        Err(_) => return Ok(None),
+    };
+    let blob = repo.find_blob(entry.id())?;
+    Ok(Some(blob.content().to_vec()))
+}
+
+fn read_file(path: &Path) -> Option<Vec<u8>> {
+    let mut content = Vec::new();
+    File::open(path).and_then(|mut file| file.read_to_end(&mut content)).ok().map(|_| content)
+}
+
+pub fn modified_time(path: &Path) -> Option<i64> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_secs() as i64)
+}
+
+// The time of the newest remote commit that changed the file since the base.
+fn last_change(repo: &Repository, base: Oid, remote: Oid, name: &Path) -> Result<i64, Error> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(::git2::SORT_TIME);
+    revwalk.push(remote)?;
+    revwalk.hide(base)?;
+
+    for oid in revwalk {
+        let commit = repo.find_commit(oid?)?;
+        let current = entry_id(&commit.tree()?, name);
+        let changed = match commit.parents().next() {
+            Some(parent) => entry_id(&parent.tree()?, name) != current,
+            None => true,
+        };
+        if changed {
+            return Ok(commit.time().seconds());
+        }
+    }
+
+    Ok(repo.find_commit(remote)?.time().seconds())
+}
+
+fn entry_id(tree: &Tree, name: &Path) -> Option<Oid> {
+    tree.get_path(name).ok().map(|entry| entry.id())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ignore::KEEP_FILE;
+    use testing::{commit_all, init_repo, set_modified, TempDir};
+
+    // A store whose last commit changed every file but .profile since the base, the first one.
+    fn store(dir: &TempDir) -> (Repository, Oid, Oid) {
+        let repo = init_repo(dir.path());
+        for name in &[".vimrc", ".bashrc", ".zshrc", ".profile", ".inputrc"] {
+            dir.write(name, "base");
+        }
+        let base = commit_all(&repo, "Base");
+
+        dir.write(".vimrc", "remote");
+        dir.write(".bashrc", "remote");
+        dir.write(".inputrc", "remote");
+        fs::remove_file(dir.path().join(".zshrc")).unwrap();
+        let remote = commit_all(&repo, "Remote");
+        (repo, base, remote)
+    }
+
+    fn plan_for(repo: &Repository, base: Oid, remote: Oid, home: &TempDir) -> Plan {
+        let workdir = repo.workdir().unwrap();
+        let base = repo.find_commit(base).unwrap().tree().unwrap();
+        let remote = repo.find_commit(remote).unwrap().tree().unwrap();
+        plan(repo,
+             &base,
+             &remote,
+             &Layout::with_home(workdir, home.path()),
+             &Ignore::load(workdir),
+             &Keep::load(workdir))
+            .unwrap()
+    }
+
+    #[test]
+    fn files_changed_on_both_sides_are_conflicts() {
+        let dir = TempDir::new("plan-store");
+        let home = TempDir::new("plan-home");
+        let (repo, base, remote) = store(&dir);
+        home.write(".vimrc", "home");
+        home.write(".bashrc", "base");
+        home.write(".zshrc", "base");
+        home.write(".profile", "home");
+        // The same change on both sides isn't a conflict.
+        home.write(".inputrc", "remote");
+
+        let plan = plan_for(&repo, base, remote, &home);
+        assert_eq!(plan.conflicts, vec![PathBuf::from(".vimrc")]);
+        assert_eq!(plan.to_home, vec![PathBuf::from(".bashrc")]);
+        assert_eq!(plan.deleted, vec![PathBuf::from(".zshrc")]);
+    }
+
+    #[test]
+    fn files_deleted_from_home_and_changed_remotely_are_conflicts() {
+        let dir = TempDir::new("plan-deleted-store");
+        let home = TempDir::new("plan-deleted-home");
+        let (repo, base, remote) = store(&dir);
+        home.write(".bashrc", "base");
+        home.write(".zshrc", "base");
+        home.write(".inputrc", "base");
+
+        let plan = plan_for(&repo, base, remote, &home);
+        assert_eq!(plan.conflicts, vec![PathBuf::from(".vimrc")]);
+    }
+
+    #[test]
+    fn kept_files_missing_from_home_are_left_alone() {
+        let dir = TempDir::new("plan-keep-store");
+        let home = TempDir::new("plan-keep-home");
+        let (repo, base, remote) = store(&dir);
+        dir.write(KEEP_FILE, ".vimrc\n");
+        home.write(".bashrc", "base");
+        home.write(".zshrc", "base");
+        home.write(".inputrc", "base");
+
+        let plan = plan_for(&repo, base, remote, &home);
+        assert!(plan.conflicts.is_empty());
+    }
+
+    fn keeps_home(strategy: &str, home: &TempDir) -> bool {
+        let dir = TempDir::new("strategy-store");
+        let (repo, base, remote) = store(&dir);
+        let layout = Layout::with_home(repo.workdir().unwrap(), home.path());
+        Strategy::from_name(strategy)
+            .unwrap()
+            .keeps_home(&repo, base, remote, &layout, Path::new(".vimrc"))
+            .unwrap()
+    }
+
+    #[test]
+    fn home_wins_and_repo_wins_ignore_the_times() {
+        let home = TempDir::new("strategy-fixed");
+        let path = home.write(".vimrc", "home");
+        set_modified(&path, 1000);
+
+        assert!(keeps_home("home-wins", &home));
+        assert!(!keeps_home("repo-wins", &home));
+    }
+
+    #[test]
+    fn newest_wins_compares_the_time_of_the_remote_change() {
+        let home = TempDir::new("strategy-newest");
+        let path = home.write(".vimrc", "home");
+
+        set_modified(&path, 1000);
+        assert!(!keeps_home("newest-wins", &home));
+
+        let tomorrow = ::time::get_time().sec + 24 * 60 * 60;
+        set_modified(&path, tomorrow);
+        assert!(keeps_home("newest-wins", &home));
+    }
+
+    #[test]
+    fn newest_wins_picks_the_repository_for_files_deleted_from_home() {
+        let home = TempDir::new("strategy-deleted");
+        assert!(!keeps_home("newest-wins", &home));
+    }
+
+    #[test]
+    fn unknown_strategies_are_errors() {
+        assert!(Strategy::from_name("theirs").is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use git2::Config;
+use glob::Pattern;
+
+const DEFAULT_MODE: u32 = 0o644;
+const DEFAULT_SENSITIVE_MODE: u32 = 0o600;
+
+// Files that usually hold credentials. More can be added with `git config --add flake.sensitive`.
+const SENSITIVE: &[&str] = &[".netrc",
+                             ".pgpass",
+                             ".ssh/*",
+                             ".gnupg/*",
+                             ".aws/credentials",
+                             ".docker/config.json",
+                             "*.pem",
+                             "*.key"];
+
+// The most open permissions `apply` leaves on the files it writes.
+pub struct Permissions {
+    mode: u32,
+    sensitive_mode: u32,
+    sensitive: Vec<Pattern>,
+}
+
+impl Permissions {
+    pub fn load(config: &Config) -> Permissions {
+        let mut sensitive: Vec<Pattern> =
+            SENSITIVE.iter().filter_map(|pattern| Pattern::new(pattern).ok()).collect();
+
+        if let Ok(entries) = config.entries(Some("flake.sensitive")) {
+            for entry in &entries {
+                let value = match entry.ok().and_then(|e| e.value().map(String::from)) {
+                    Some(value) => value,
+                    None => continue,
+                };
+
+                match Pattern::new(value.trim()) {
+                    Ok(pattern) => sensitive.push(pattern),
+                    Err(_) => warn!("Ignoring invalid sensitive file pattern `{}`", value),
+                }
+            }
+        }
+
+        Permissions {
+            mode: mode_setting(config, "flake.filemode", DEFAULT_MODE),
+            sensitive_mode: mode_setting(config, "flake.sensitivemode", DEFAULT_SENSITIVE_MODE),
+            sensitive,
+        }
+    }
+
+    // Only removes permissions. Execute bits follow the read bits of the cap, so scripts stay
+    // executable for whoever can read them. Links are left alone, their target is the
+    // repository's copy.
+    pub fn restrict(&self, path: &Path, name: &Path) -> Result<(), io::Error> {
+        let metadata = fs::symlink_metadata(path)?;
+        if metadata.file_type().is_symlink() {
+            return Ok(());
+        }
+
+        let mut permissions = metadata.permissions();
+        let mode = permissions.mode() & 0o7777;
+        let restricted = self.restricted(mode, name);
+        if restricted == mode {
+            return Ok(());
+        }
+        permissions.set_mode(restricted);
+        fs::set_permissions(path, permissions)
+    }
+
+    // The mode `restrict` leaves on the file `name` when it has `mode`.
+    pub fn restricted(&self, mode: u32, name: &Path) -> u32 {
+        let cap = if self.sensitive.iter().any(|pattern| pattern.matches_path(name)) {
+            self.sensitive_mode
+        } else {
+            self.mode
+        };
+        mode & (cap | ((cap & 0o444) >> 2))
+    }
+}
+
+fn mode_setting(config: &Config, name: &str, default: u32) -> u32 {
+    let value = match config.get_string(name) {
+        Ok(value) => value,
+        Err(_) => return default,
+    };
+
+    match u32::from_str_radix(value.trim(), 8) {
+        Ok(mode) if mode <= 0o7777 => mode,
+        _ => {
+            warn!("Ignoring invalid {} `{}`, use an octal mode like 0644", name, value);
+            default
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::path::Path;
+
+    use git2::Config;
+
+    use super::*;
+    use testing::TempDir;
+
+    fn mode_after(permissions: &Permissions, dir: &TempDir, name: &str, mode: u32) -> u32 {
+        let path = dir.write(name, "");
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        permissions.restrict(&path, Path::new(name)).unwrap();
+        fs::metadata(&path).unwrap().permissions().mode() & 0o7777
+    }
+
+    #[test]
+    fn modes_are_capped() {
+        let dir = TempDir::new("permissions-default");
+        let permissions = Permissions::load(&Config::new().unwrap());
+
+        assert_eq!(mode_after(&permissions, &dir, ".vimrc", 0o666), 0o644);
+        assert_eq!(mode_after(&permissions, &dir, ".profile", 0o600), 0o600);
+        assert_eq!(mode_after(&permissions, &dir, "bin/backup", 0o777), 0o755);
+        assert_eq!(mode_after(&permissions, &dir, "bin/private", 0o700), 0o700);
+        assert_eq!(mode_after(&permissions, &dir, ".netrc", 0o644), 0o600);
+        assert_eq!(mode_after(&permissions, &dir, ".ssh/config", 0o664), 0o600);
+        assert_eq!(mode_after(&permissions, &dir, ".ssh/rc", 0o755), 0o700);
+    }
+
+    #[test]
+    fn modes_come_from_the_config() {
+        let dir = TempDir::new("permissions-config");
+        let mut config = Config::open(&dir.write("config", "")).unwrap();
+        config.set_str("flake.filemode", "0640").unwrap();
+        config.set_str("flake.sensitivemode", "0400").unwrap();
+        config.set_str("flake.sensitive", ".config/hub").unwrap();
+        let permissions = Permissions::load(&config);
+
+        assert_eq!(mode_after(&permissions, &dir, ".vimrc", 0o666), 0o640);
+        assert_eq!(mode_after(&permissions, &dir, "bin/backup", 0o777), 0o750);
+        assert_eq!(mode_after(&permissions, &dir, ".config/hub", 0o644), 0o400);
+        assert_eq!(mode_after(&permissions, &dir, ".netrc", 0o600), 0o400);
+    }
+
+    #[test]
+    fn invalid_modes_are_ignored() {
+        let dir = TempDir::new("permissions-invalid");
+        let mut config = Config::open(&dir.write("config", "")).unwrap();
+        config.set_str("flake.filemode", "rw-r--r--").unwrap();
+        config.set_str("flake.sensitivemode", "17777").unwrap();
+        let permissions = Permissions::load(&config);
+
+        assert_eq!(mode_after(&permissions, &dir, ".vimrc", 0o666), 0o644);
+        assert_eq!(mode_after(&permissions, &dir, ".netrc", 0o644), 0o600);
+    }
+
+    #[test]
+    fn links_are_left_alone() {
+        let dir = TempDir::new("permissions-link");
+        let target = dir.write("target", "");
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o666)).unwrap();
+        let link = dir.path().join(".vimrc");
+        symlink(&target, &link).unwrap();
+
+        let permissions = Permissions::load(&Config::new().unwrap());
+        permissions.restrict(&link, Path::new(".vimrc")).unwrap();
+        assert_eq!(fs::metadata(&target).unwrap().permissions().mode() & 0o7777, 0o666);
+    }
+}
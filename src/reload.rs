@@ -0,0 +1,79 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+use git2::Config;
+use glob::Pattern;
+
+// Reload actions users can enable by name with `git config --add flake.reload NAME`.
+const BUILTIN: &[(&str, &str, &str)] = &[("tmux", ".tmux.conf", "tmux source-file ~/.tmux.conf"),
+                                         ("i3", ".config/i3/*", "i3-msg reload"),
+                                         ("xresources", ".Xresources", "xrdb -merge ~/.Xresources")];
+
+pub struct Reload {
+    name: String,
+    pattern: Pattern,
+    command: String,
+}
+
+impl Reload {
+    // Every `flake.reload` value is either a built-in name or `PATTERN=COMMAND`.
+    pub fn load(config: &Config) -> Vec<Reload> {
+        let mut actions = Vec::new();
+
+        let entries = match config.entries(Some("flake.reload")) {
+            Ok(entries) => entries,
+            Err(_) => return actions,
+        };
+
+        for entry in &entries {
+            let value = match entry.ok().and_then(|e| e.value().map(String::from)) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            match Reload::parse(&value) {
+                Some(action) => actions.push(action),
+                None => warn!("Ignoring unknown reload action `{}`", value),
+            }
+        }
+
+        actions
+    }
+
+    fn parse(value: &str) -> Option<Reload> {
+        let (name, pattern, command) = match value.find('=') {
+            Some(split) => (value, &value[..split], &value[split + 1..]),
+            None => {
+                match BUILTIN.iter().find(|b| b.0 == value) {
+                    Some(builtin) => *builtin,
+                    None => return None,
+                }
+            }
+        };
+
+        Pattern::new(pattern.trim()).ok().map(|pattern| {
+            Reload {
+                name: String::from(name),
+                pattern,
+                command: String::from(command.trim()),
+            }
+        })
+    }
+
+    // The patterns match the changed paths relative to the home directory, like `.tmux.conf`,
+    // whatever the layout of the repository.
+    pub fn run_matching(actions: &[Reload], changed: &[PathBuf]) {
+        for action in actions {
+            if !changed.iter().any(|path| action.pattern.matches_path(path)) {
+                continue;
+            }
+
+            info!("Reloading {}", action.name);
+            match Command::new("sh").arg("-c").arg(&action.command).status() {
+                Ok(ref status) if status.success() => {}
+                Ok(status) => warn!("Reload action `{}` failed with {}", action.name, status),
+                Err(error) => warn!("Unable to run reload action `{}`: {}", action.name, error),
+            }
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+
+use git2::Oid;
+use rustc_serialize::json::{Json, ToJson};
+
+use template;
+
+pub const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+// An append-only log of the syncs that committed something, one JSON object per line.
+// Once it grows past `max_bytes` it's moved aside to `FILE.1`, replacing the previous one.
+#[derive(Clone)]
+pub struct Report {
+    pub path: PathBuf,
+    pub max_bytes: u64,
+}
+
+impl Report {
+    pub fn append(&self,
+                  changed: &[PathBuf],
+                  commit: Option<Oid>,
+                  pushed: &Result<(), ::git2::Error>)
+                  -> Result<(), io::Error> {
+        let mut record = BTreeMap::new();
+        record.insert(String::from("time"), ::time::get_time().sec.to_json());
+        record.insert(String::from("host"), template::hostname().to_json());
+        record.insert(String::from("changed"),
+                      Json::Array(changed.iter()
+                          .map(|path| path.to_string_lossy().to_json())
+                          .collect()));
+        record.insert(String::from("commit"),
+                      commit.map(|oid| oid.to_string()).to_json());
+        record.insert(String::from("pushed"), pushed.is_ok().to_json());
+        if let Err(ref error) = *pushed {
+            record.insert(String::from("error"), error.message().to_json());
+        }
+
+        if fs::metadata(&self.path).map(|m| m.len() >= self.max_bytes).unwrap_or(false) {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            fs::rename(&self.path, rotated)?;
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", Json::Object(record))
+    }
+}
@@ -0,0 +1,271 @@
+use std::env;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::process;
+use std::process::{Command, Stdio};
+
+use git2::{Commit, Config};
+
+const LONG_ID_LEN: usize = 16;
+
+// The keys `sync --verify-signatures` accepts, their fingerprints or long key ids, added with
+// `git config --global --add flake.trustedkey KEY`. Anything else is refused, a short or empty
+// value would trust the keys of strangers too.
+pub fn trusted_keys(config: &Config) -> Result<Vec<String>, String> {
+    let mut keys = Vec::new();
+
+    if let Ok(entries) = config.entries(Some("flake.trustedkey")) {
+        for entry in &entries {
+            if let Some(value) = entry.ok().and_then(|e| e.value().map(String::from)) {
+                keys.push(parse_key(&value)?);
+            }
+        }
+    }
+
+    Ok(keys)
+}
+
+fn parse_key(value: &str) -> Result<String, String> {
+    let key = value.replace(' ', "").to_uppercase().replacen("0X", "", 1);
+    let valid = (key.len() == LONG_ID_LEN || key.len() == 40 || key.len() == 64) &&
+                key.chars().all(|c| c.is_ascii_hexdigit());
+    if valid {
+        Ok(key)
+    } else {
+        Err(format!("`{}` isn't a key fingerprint or a 16 digit long key id, it can't be a \
+                     flake.trustedkey",
+                    value))
+    }
+}
+
+// Long key ids are the last 16 digits of the fingerprint, fingerprints have to be the same.
+fn is_trusted(fingerprint: &str, key: &str) -> bool {
+    if key.len() == LONG_ID_LEN {
+        fingerprint.len() > LONG_ID_LEN && fingerprint.ends_with(key)
+    } else {
+        fingerprint == key
+    }
+}
+
+// Checks the commit's gpgsig header with gpg, like `git verify-commit`. The signature covers the
+// raw commit without that header. Commits signed with a subkey are accepted when either the
+// subkey or its primary key is trusted.
+pub fn verify(commit: &Commit, trusted: &[String]) -> Result<(), String> {
+    let (payload, signature) = split_signature(commit.raw_header().unwrap_or(""));
+    let signature = match signature {
+        Some(signature) => signature,
+        None => return Err(String::from("isn't signed")),
+    };
+    let payload = format!("{}\n{}", payload, commit.message_raw().unwrap_or(""));
+
+    // gpg only reads detached signatures from a file. It goes in a new directory only this user
+    // can open, so nobody else can replace it or plant a link where it's written.
+    let directory = env::temp_dir().join(format!("flake-{}-{}", process::id(), commit.id()));
+    DirBuilder::new().mode(0o700).create(&directory).map_err(|error| {
+            format!("can't be verified, unable to create a directory for its signature: {}",
+                    error)
+        })?;
+    let path = directory.join("signature");
+    let saved = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut file| file.write_all(signature.as_bytes()));
+    if let Err(error) = saved {
+        let _ = fs::remove_dir_all(&directory);
+        return Err(format!("can't be verified, unable to save its signature: {}", error));
+    }
+
+    let output = Command::new("gpg")
+        .arg("--batch")
+        .arg("--status-fd")
+        .arg("1")
+        .arg("--verify")
+        .arg(&path)
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .and_then(|mut child| {
+            if let Some(stdin) = child.stdin.as_mut() {
+                stdin.write_all(payload.as_bytes())?;
+            }
+            child.wait_with_output()
+        });
+    let _ = fs::remove_dir_all(&directory);
+    let output =
+        output.map_err(|error| format!("can't be verified, unable to run gpg: {}", error))?;
+
+    // [GNUPG:] VALIDSIG <fingerprint> <date> ... <primary key fingerprint>
+    let status = String::from_utf8_lossy(&output.stdout);
+    let fingerprints = match status.lines().find(|line| line.starts_with("[GNUPG:] VALIDSIG ")) {
+        Some(line) => line.split_whitespace().skip(2).collect::<Vec<_>>(),
+        None => return Err(String::from("has a bad signature, or one from an unknown key")),
+    };
+    let signer = fingerprints.first().cloned().unwrap_or("");
+    let primary = fingerprints.last().cloned().unwrap_or("");
+    if trusted.iter().any(|key| is_trusted(signer, key) || is_trusted(primary, key)) {
+        Ok(())
+    } else {
+        Err(format!("is signed by {}, which isn't a trusted key", signer))
+    }
+}
+
+// The header lines without gpgsig, and the signature, whose lines are indented by a space.
+fn split_signature(header: &str) -> (String, Option<String>) {
+    let mut payload = String::new();
+    let mut signature: Option<String> = None;
+    let mut in_signature = false;
+
+    for line in header.lines() {
+        if line.starts_with("gpgsig ") {
+            signature = Some(line.replacen("gpgsig ", "", 1) + "\n");
+            in_signature = true;
+        } else if in_signature && line.starts_with(' ') {
+            if let Some(ref mut signature) = signature {
+                signature.push_str(&line.replacen(" ", "", 1));
+                signature.push('\n');
+            }
+        } else {
+            in_signature = false;
+            payload.push_str(line);
+            payload.push('\n');
+        }
+    }
+    (payload, signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use git2::{Oid, Repository};
+
+    use super::*;
+    use testing::{commit_all, init_repo, TempDir};
+
+    const SIGNATURE: &str = "-----BEGIN PGP SIGNATURE-----\n\
+                             \n\
+                             iQEzBAABCAAdFiEEq8n0cOW6MXyjGBrTRXLN3pB2CMsFAlmSpuMACgkQRXLN3pB2\n\
+                             -----END PGP SIGNATURE-----\n";
+
+    // libgit2 can't sign, the signed commit is written like `git commit -S` would.
+    fn commit_signed(repo: &Repository, parent: Oid) -> Oid {
+        let tree = repo.find_commit(parent).unwrap().tree_id();
+        let signature = SIGNATURE.lines().collect::<Vec<_>>().join("\n ");
+        let commit = format!("tree {}\nparent {}\nauthor Flake Test <test@example.com> \
+                              1500000000 +0000\ncommitter Flake Test <test@example.com> \
+                              1500000000 +0000\ngpgsig {}\n\nSigned commit\n",
+                             tree,
+                             parent,
+                             signature);
+
+        let mut child = Command::new("git")
+            .arg("hash-object")
+            .arg("-t")
+            .arg("commit")
+            .arg("-w")
+            .arg("--stdin")
+            .current_dir(repo.workdir().unwrap())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(commit.as_bytes()).unwrap();
+        let mut oid = String::new();
+        child.stdout.take().unwrap().read_to_string(&mut oid).unwrap();
+        assert!(child.wait().unwrap().success());
+        Oid::from_str(oid.trim()).unwrap()
+    }
+
+    #[test]
+    fn keys_are_fingerprints_or_long_ids() {
+        let fingerprint = "ABCDEF0123456789ABCDEF0123456789ABCDEF01";
+        assert_eq!(parse_key("abcd ef01 2345 6789 abcd  ef01 2345 6789 abcd ef01"),
+                   Ok(String::from(fingerprint)));
+        assert_eq!(parse_key("0x89ABCDEF01234567"), Ok(String::from("89ABCDEF01234567")));
+        assert!(parse_key(&"A".repeat(64)).is_ok());
+
+        assert!(parse_key("").is_err());
+        assert!(parse_key("0xABCDEF01").is_err());
+        assert!(parse_key("ABCDEF0123456789ABCDEF0123456789ABCDEF0").is_err());
+        assert!(parse_key("GHIJKL0123456789").is_err());
+        assert!(parse_key("release@example.com").is_err());
+    }
+
+    #[test]
+    fn long_ids_match_the_end_of_the_fingerprint() {
+        let fingerprint = "ABCDEF0123456789ABCDEF0123456789ABCDEF01";
+        assert!(is_trusted(fingerprint, fingerprint));
+        assert!(is_trusted(fingerprint, "23456789ABCDEF01"));
+        assert!(!is_trusted(fingerprint, "ABCDEF0123456789"));
+        assert!(!is_trusted("0123456789ABCDEF", "0123456789ABCDEF"));
+        assert!(!is_trusted(fingerprint, "BCDEF0123456789ABCDEF0123456789ABCDEF01"));
+    }
+
+    #[test]
+    fn trusted_keys_refuse_invalid_values() {
+        let dir = TempDir::new("trusted-keys");
+        let mut config = Config::open(&dir.write("config", "")).unwrap();
+        config.set_multivar("flake.trustedkey", "^$", "0x89ABCDEF01234567").unwrap();
+        assert_eq!(trusted_keys(&config), Ok(vec![String::from("89ABCDEF01234567")]));
+
+        config.set_multivar("flake.trustedkey", "^$", "DEADBEEF").unwrap();
+        assert!(trusted_keys(&config).is_err());
+    }
+
+    #[test]
+    fn the_signature_is_split_from_the_header() {
+        let header = "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+                      author Flake Test <test@example.com> 1500000000 +0000\n\
+                      committer Flake Test <test@example.com> 1500000000 +0000\n\
+                      gpgsig -----BEGIN PGP SIGNATURE-----\n \n \
+                      iQEzBAABCAAdFiEEq8n0cOW6MXyjGBrTRXLN3pB2CMsFAlmSpuMACgkQRXLN3pB2\n \
+                      -----END PGP SIGNATURE-----\n";
+
+        let (payload, signature) = split_signature(header);
+        assert_eq!(payload,
+                   "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+                    author Flake Test <test@example.com> 1500000000 +0000\n\
+                    committer Flake Test <test@example.com> 1500000000 +0000\n");
+        assert_eq!(signature, Some(String::from(SIGNATURE)));
+
+        let tree = "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n";
+        let (payload, signature) = split_signature(tree);
+        assert_eq!(payload, tree);
+        assert_eq!(signature, None);
+    }
+
+    #[test]
+    fn unsigned_commits_are_refused() {
+        let store = TempDir::new("verify-unsigned");
+        store.write(".vimrc", "set number");
+        let repo = init_repo(store.path());
+        let commit = repo.find_commit(commit_all(&repo, "Add vimrc")).unwrap();
+
+        let trusted = vec![String::from("89ABCDEF01234567")];
+        assert_eq!(verify(&commit, &trusted), Err(String::from("isn't signed")));
+    }
+
+    // The signature is made up, gpg can't accept it whether it's installed or not.
+    #[test]
+    fn signed_commits_are_checked_with_gpg() {
+        let store = TempDir::new("verify-signed");
+        store.write(".vimrc", "set number");
+        let repo = init_repo(store.path());
+        let parent = commit_all(&repo, "Add vimrc");
+        let commit = repo.find_commit(commit_signed(&repo, parent)).unwrap();
+
+        let (_, signature) = split_signature(commit.raw_header().unwrap());
+        assert_eq!(signature, Some(String::from(SIGNATURE)));
+
+        let trusted = vec![String::from("89ABCDEF01234567")];
+        let error = verify(&commit, &trusted).unwrap_err();
+        assert!(error != "isn't signed", "{}", error);
+        let directory = env::temp_dir().join(format!("flake-{}-{}", process::id(), commit.id()));
+        assert!(!directory.exists());
+    }
+}
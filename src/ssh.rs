@@ -0,0 +1,174 @@
+use std::io;
+use std::io::{Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+use git2::{Error, Remote};
+use git2::transport::{self, Service, SmartSubtransport, SmartSubtransportStream, Transport};
+
+// Routes ssh remotes through a command, like GIT_SSH_COMMAND does for git, instead of
+// libgit2's built-in libssh2 transport. libgit2 hands both `ssh://` and scp-like urls,
+// `user@host:path`, to the transport registered for ssh.
+pub fn register(command: String) -> Result<(), Error> {
+    unsafe {
+        transport::register("ssh", move |remote: &Remote| {
+            let subtransport = SshCommand {
+                command: command.clone(),
+                current: Mutex::new(None),
+            };
+            Transport::smart(remote, false, subtransport)
+        })
+    }
+}
+
+struct SshCommand {
+    command: String,
+    // SSH is stateful, the pack is negotiated over the same connection that listed the refs.
+    current: Mutex<Option<Connection>>,
+}
+
+impl SmartSubtransport for SshCommand {
+    fn action(&self, url: &str, action: Service) -> Result<Box<SmartSubtransportStream>, Error> {
+        let program = match action {
+            Service::UploadPackLs => "git-upload-pack",
+            Service::ReceivePackLs => "git-receive-pack",
+            Service::UploadPack | Service::ReceivePack => {
+                return match *self.current.lock().unwrap() {
+                    Some(ref connection) => Ok(Box::new(connection.clone())),
+                    None => Err(Error::from_str("the ssh command is not connected")),
+                };
+            }
+        };
+
+        let connection = self.connect(url, program)?;
+        *self.current.lock().unwrap() = Some(connection.clone());
+        Ok(Box::new(connection))
+    }
+
+    fn close(&self) -> Result<(), Error> {
+        if let Some(connection) = self.current.lock().unwrap().take() {
+            let mut process = connection.0.lock().unwrap();
+            let _ = process.child.kill();
+            let _ = process.child.wait();
+        }
+        Ok(())
+    }
+}
+
+impl SshCommand {
+    fn connect(&self, url: &str, program: &str) -> Result<Connection, Error> {
+        let (host, port, path) = parse_url(url)?;
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(format!("{} \"$@\"", self.command)).arg("flake-ssh");
+        if let Some(port) = port {
+            command.arg("-p").arg(port);
+        }
+        command.arg(host).arg(format!("{} '{}'", program, path.replace('\'', "'\\''")));
+
+        debug!("Connecting to {} with `{}`", url, self.command);
+        let mut child = command.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|error| {
+                Error::from_str(&format!("Unable to run `{}`: {}", self.command, error))
+            })?;
+
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        Ok(Connection(Arc::new(Mutex::new(Process {
+            child,
+            stdin,
+            stdout,
+        }))))
+    }
+}
+
+struct Process {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+#[derive(Clone)]
+struct Connection(Arc<Mutex<Process>>);
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().stdout.read(buf)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().stdin.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().stdin.flush()
+    }
+}
+
+// Splits `ssh://[user@]host[:port]/path` and git's scp-like `[user@]host:path`. Paths starting
+// with `~`, and scp-like paths without a leading slash, are relative to the home directory of
+// the remote user, like in git.
+fn parse_url(url: &str) -> Result<(&str, Option<&str>, &str), Error> {
+    let invalid = || Error::from_str(&format!("invalid ssh url {}", url));
+
+    let scheme = match url.find("://") {
+        Some(scheme) => scheme,
+        None => {
+            let colon = url.find(':').ok_or_else(&invalid)?;
+            let (host, path) = (&url[..colon], &url[colon + 1..]);
+            if host.is_empty() || host.contains('/') || path.is_empty() {
+                return Err(invalid());
+            }
+            return Ok((host, None, path));
+        }
+    };
+    let rest = &url[scheme + 3..];
+    let slash = rest.find('/').ok_or_else(&invalid)?;
+    let (authority, path) = rest.split_at(slash);
+    let path = if path.starts_with("/~") { &path[1..] } else { path };
+
+    let (host, port) = match authority.rfind(':') {
+        Some(colon) if !authority.ends_with(']') => {
+            (&authority[..colon], Some(&authority[colon + 1..]))
+        }
+        _ => (authority, None),
+    };
+    Ok((host, port, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ssh_urls_are_split_into_host_port_and_path() {
+        assert_eq!(parse_url("ssh://git@example.com/dotfiles.git").unwrap(),
+                   ("git@example.com", None, "/dotfiles.git"));
+        assert_eq!(parse_url("ssh://git@example.com:2222/srv/dotfiles.git").unwrap(),
+                   ("git@example.com", Some("2222"), "/srv/dotfiles.git"));
+        assert_eq!(parse_url("ssh://example.com/~/dotfiles.git").unwrap(),
+                   ("example.com", None, "~/dotfiles.git"));
+    }
+
+    #[test]
+    fn scp_like_urls_are_split_into_host_and_path() {
+        assert_eq!(parse_url("git@github.com:calavera/dotfiles.git").unwrap(),
+                   ("git@github.com", None, "calavera/dotfiles.git"));
+        assert_eq!(parse_url("nas:/srv/git/dotfiles.git").unwrap(),
+                   ("nas", None, "/srv/git/dotfiles.git"));
+        assert_eq!(parse_url("nas:~/dotfiles.git").unwrap(), ("nas", None, "~/dotfiles.git"));
+    }
+
+    #[test]
+    fn urls_without_a_path_are_invalid() {
+        assert!(parse_url("ssh://example.com").is_err());
+        assert!(parse_url("git@github.com:").is_err());
+        assert!(parse_url("./dotfiles:backup").is_err());
+        assert!(parse_url("example.com").is_err());
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process;
+
+use git2::Repository;
+use rustc_serialize::json::{Json, ToJson};
+
+const STATE_FILE: &str = "flake-state.json";
+const LOCK_FILE: &str = "flake.lock";
+
+// Bookkeeping that doesn't belong in the dotfiles history, kept inside the store's .git directory.
+// Every profile has its own, the default profile keeps the names from before profiles existed.
+#[derive(Default)]
+pub struct State {
+    pub last_sync: Option<i64>,
+    // The commit `apply` last installed in this machine.
+    pub last_applied: Option<String>,
+    // The last sync couldn't reach the remote.
+    pub offline: bool,
+    // The store was cloned and hasn't synced or applied since, so the next sync has to ask
+    // whether to apply the repository or capture the home directory.
+    pub first_sync: bool,
+}
+
+impl State {
+    pub fn load(repo: &Repository, profile: Option<&String>) -> State {
+        let mut content = String::new();
+        let read = File::open(state_path(repo, profile))
+            .and_then(|mut f| f.read_to_string(&mut content));
+        if read.is_err() {
+            return State::default();
+        }
+
+        match Json::from_str(&content) {
+            Ok(json) => {
+                State {
+                    last_sync: json.find("last_sync").and_then(|v| v.as_i64()),
+                    last_applied: json.find("last_applied")
+                        .and_then(|v| v.as_string())
+                        .map(String::from),
+                    offline: json.find("offline").and_then(|v| v.as_boolean()).unwrap_or(false),
+                    first_sync: json.find("first_sync")
+                        .and_then(|v| v.as_boolean())
+                        .unwrap_or(false),
+                }
+            }
+            Err(error) => {
+                warn!("Ignoring invalid state file: {}", error);
+                State::default()
+            }
+        }
+    }
+
+    pub fn save(&self, repo: &Repository, profile: Option<&String>) -> Result<(), io::Error> {
+        let mut file = File::create(state_path(repo, profile))?;
+        write!(file, "{}", self.to_json())
+    }
+}
+
+impl ToJson for State {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        if let Some(last_sync) = self.last_sync {
+            object.insert(String::from("last_sync"), last_sync.to_json());
+        }
+        if let Some(ref last_applied) = self.last_applied {
+            object.insert(String::from("last_applied"), last_applied.to_json());
+        }
+        if self.offline {
+            object.insert(String::from("offline"), true.to_json());
+        }
+        if self.first_sync {
+            object.insert(String::from("first_sync"), true.to_json());
+        }
+        Json::Object(object)
+    }
+}
+
+// Keeps two syncs of the same profile from running at once, it's released when it's dropped.
+// The file holds the pid of its owner, so a lock left behind by a crash doesn't block forever.
+pub struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    pub fn acquire(repo: &Repository, profile: Option<&String>) -> Result<Lock, String> {
+        let path = profile_path(repo, LOCK_FILE, profile);
+
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    write!(file, "{}", process::id())
+                        .map_err(|error| format!("Unable to write {}: {}", path.display(), error))?;
+                    return Ok(Lock { path });
+                }
+                Err(ref error) if error.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(error) => return Err(format!("Unable to create {}: {}", path.display(), error)),
+            }
+
+            let mut content = String::new();
+            let _ = File::open(&path).and_then(|mut f| f.read_to_string(&mut content));
+            match content.trim().parse::<i32>() {
+                Ok(pid) if is_running(pid) => {
+                    return Err(format!("another flake is already running for {} with pid {}, \
+                                        remove {} if it isn't",
+                                       profile_name(profile),
+                                       pid,
+                                       path.display()));
+                }
+                _ => {
+                    warn!("Removing the stale lock {}", path.display());
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+        Err(format!("Unable to acquire {}", path.display()))
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Profile names end up in file names, so they're limited to letters, digits, `-` and `_`.
+pub fn check_profile(name: &str) -> Result<(), String> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if name.is_empty() || !name.chars().all(valid) {
+        return Err(format!("invalid profile `{}`, use only letters, digits, - and _", name));
+    }
+    Ok(())
+}
+
+fn profile_name(profile: Option<&String>) -> String {
+    match profile {
+        Some(profile) => format!("the profile {}", profile),
+        None => String::from("the default profile"),
+    }
+}
+
+fn is_running(pid: i32) -> bool {
+    let result = unsafe { ::libc::kill(pid, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(::libc::EPERM)
+}
+
+fn state_path(repo: &Repository, profile: Option<&String>) -> PathBuf {
+    profile_path(repo, STATE_FILE, profile)
+}
+
+// `flake-state.json` is `flake-state-PROFILE.json` for a profile.
+fn profile_path(repo: &Repository, name: &str, profile: Option<&String>) -> PathBuf {
+    let name = match (profile, name.rfind('.')) {
+        (Some(profile), Some(dot)) => format!("{}-{}{}", &name[..dot], profile, &name[dot..]),
+        _ => String::from(name),
+    };
+    repo.path().join(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    use super::*;
+    use testing::{init_repo, TempDir};
+
+    #[test]
+    fn the_default_profile_keeps_unsuffixed_files() {
+        let dir = TempDir::new("state-default");
+        let repo = init_repo(dir.path());
+        let work = String::from("work");
+
+        assert_eq!(state_path(&repo, None), repo.path().join("flake-state.json"));
+        assert_eq!(profile_path(&repo, LOCK_FILE, None), repo.path().join("flake.lock"));
+        assert_eq!(state_path(&repo, Some(&work)), repo.path().join("flake-state-work.json"));
+        assert_eq!(profile_path(&repo, LOCK_FILE, Some(&work)),
+                   repo.path().join("flake-work.lock"));
+    }
+
+    #[test]
+    fn profiles_keep_their_own_state() {
+        let dir = TempDir::new("state-profiles");
+        let repo = init_repo(dir.path());
+        let (work, home) = (String::from("work"), String::from("home"));
+
+        let state = State { last_sync: Some(1), ..State::default() };
+        state.save(&repo, None).unwrap();
+        let state = State { last_sync: Some(2), ..State::default() };
+        state.save(&repo, Some(&work)).unwrap();
+
+        assert_eq!(State::load(&repo, None).last_sync, Some(1));
+        assert_eq!(State::load(&repo, Some(&work)).last_sync, Some(2));
+        assert_eq!(State::load(&repo, Some(&home)).last_sync, None);
+    }
+
+    #[test]
+    fn two_profiles_sync_at_the_same_time() {
+        let dir = TempDir::new("state-locks");
+        let repo = init_repo(dir.path());
+        let path = dir.path().to_path_buf();
+        let work = String::from("work");
+
+        let (acquired, ready) = channel();
+        let (release, released) = channel::<()>();
+        let other = thread::spawn(move || {
+            let repo = Repository::open(&path).unwrap();
+            let lock = Lock::acquire(&repo, Some(&String::from("work")));
+            acquired.send(lock.is_ok()).unwrap();
+            let _ = released.recv();
+            drop(lock);
+        });
+
+        assert!(ready.recv().unwrap());
+        let lock = Lock::acquire(&repo, None).unwrap();
+        assert!(Lock::acquire(&repo, Some(&work)).is_err());
+        assert!(Lock::acquire(&repo, None).is_err());
+
+        release.send(()).unwrap();
+        other.join().unwrap();
+        assert!(Lock::acquire(&repo, Some(&work)).is_ok());
+        drop(lock);
+        assert!(!repo.path().join("flake.lock").exists());
+    }
+
+    #[test]
+    fn stale_locks_are_taken_over() {
+        let dir = TempDir::new("state-stale-lock");
+        let repo = init_repo(dir.path());
+        // Pids wrap around long before reaching this one.
+        File::create(repo.path().join("flake.lock"))
+            .and_then(|mut file| file.write_all(b"2147483646"))
+            .unwrap();
+
+        assert!(Lock::acquire(&repo, None).is_ok());
+    }
+
+    #[test]
+    fn profile_names_must_fit_in_file_names() {
+        assert!(check_profile("work").is_ok());
+        assert!(check_profile("laptop_2-old").is_ok());
+        assert!(check_profile("").is_err());
+        assert!(check_profile("../work").is_err());
+        assert!(check_profile("work laptop").is_err());
+    }
+}
+
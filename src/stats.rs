@@ -0,0 +1,95 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, PathBuf};
+
+use git2::{Error, Repository};
+use rustc_serialize::json::{Json, ToJson};
+
+const LARGEST_FILES: usize = 5;
+
+// Files directly in the home directory are grouped under this name.
+const TOP_LEVEL: &str = "~";
+
+pub struct Stats {
+    pub files: usize,
+    pub bytes: u64,
+    pub largest: Vec<(PathBuf, u64)>,
+    pub commits: usize,
+    pub last_sync: Option<i64>,
+    // File count and size of every top level directory.
+    pub directories: BTreeMap<String, (usize, u64)>,
+}
+
+impl Stats {
+    pub fn collect(repo: &Repository,
+                   files: Vec<PathBuf>,
+                   last_sync: Option<i64>)
+                   -> Result<Stats, Error> {
+        let workdir = repo.workdir().unwrap();
+
+        let mut sizes = Vec::new();
+        let mut directories = BTreeMap::new();
+        for name in files {
+            let size = fs::metadata(workdir.join(&name)).map(|m| m.len()).unwrap_or(0);
+
+            let directory = match name.components().next() {
+                Some(Component::Normal(first)) if name.components().count() > 1 => {
+                    first.to_string_lossy().into_owned()
+                }
+                _ => String::from(TOP_LEVEL),
+            };
+            let entry = directories.entry(directory).or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 += size;
+
+            sizes.push((name, size));
+        }
+
+        let bytes = sizes.iter().fold(0, |total, &(_, size)| total + size);
+        let files = sizes.len();
+        sizes.sort_by_key(|&(_, size)| Reverse(size));
+        sizes.truncate(LARGEST_FILES);
+
+        let mut revwalk = repo.revwalk()?;
+        revwalk.push_head()?;
+
+        Ok(Stats {
+            files,
+            bytes,
+            largest: sizes,
+            commits: revwalk.count(),
+            last_sync,
+            directories,
+        })
+    }
+}
+
+impl ToJson for Stats {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert(String::from("files"), self.files.to_json());
+        object.insert(String::from("bytes"), self.bytes.to_json());
+        object.insert(String::from("commits"), self.commits.to_json());
+        object.insert(String::from("last_sync"), self.last_sync.to_json());
+
+        let largest = self.largest.iter().map(|&(ref name, size)| {
+            let mut file = BTreeMap::new();
+            file.insert(String::from("path"), name.to_string_lossy().to_json());
+            file.insert(String::from("bytes"), size.to_json());
+            Json::Object(file)
+        });
+        object.insert(String::from("largest"), Json::Array(largest.collect()));
+
+        let mut directories = BTreeMap::new();
+        for (name, &(files, bytes)) in &self.directories {
+            let mut directory = BTreeMap::new();
+            directory.insert(String::from("files"), files.to_json());
+            directory.insert(String::from("bytes"), bytes.to_json());
+            directories.insert(name.clone(), Json::Object(directory));
+        }
+        object.insert(String::from("directories"), Json::Object(directories));
+
+        Json::Object(object)
+    }
+}
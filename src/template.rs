@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::CStr;
+use std::path::{Path, PathBuf};
+
+use git2::Config;
+use libc;
+
+pub const TEMPLATE_EXTENSION: &str = ".flake-tmpl";
+
+pub fn is_template(name: &Path) -> bool {
+    name.to_str().map(|n| n.ends_with(TEMPLATE_EXTENSION)).unwrap_or(false)
+}
+
+// The file a template renders to. Other files are their own target.
+pub fn target_name(name: &Path) -> PathBuf {
+    if !is_template(name) {
+        return PathBuf::from(name);
+    }
+    let name = name.to_str().unwrap();
+    PathBuf::from(&name[..name.len() - TEMPLATE_EXTENSION.len()])
+}
+
+// Built-in variables, plus every `flake.var.NAME` setting in the configuration.
+pub fn variables(config: &Config) -> BTreeMap<String, String> {
+    let mut vars = BTreeMap::new();
+    vars.insert(String::from("os"), String::from(env::consts::OS));
+    if let Some(hostname) = hostname() {
+        vars.insert(String::from("hostname"), hostname);
+    }
+    if let Ok(username) = env::var("USER") {
+        vars.insert(String::from("username"), username);
+    }
+
+    if let Ok(entries) = config.entries(Some("flake\\.var\\..*")) {
+        for entry in entries.into_iter().filter_map(|e| e.ok()) {
+            if let (Some(name), Some(value)) = (entry.name(), entry.value()) {
+                vars.insert(String::from(&name["flake.var.".len()..]), String::from(value));
+            }
+        }
+    }
+
+    vars
+}
+
+pub fn render(content: &str, vars: &BTreeMap<String, String>) -> Result<String, String> {
+    let mut output = String::with_capacity(content.len());
+    let mut rest = content;
+
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => return Err(String::from("unclosed `{{` in template")),
+        };
+
+        let name = rest[start + 2..end].trim();
+        match vars.get(name) {
+            Some(value) => output.push_str(value),
+            None => return Err(format!("undefined template variable `{}`", name)),
+        }
+        rest = &rest[end + 2..];
+    }
+
+    output.push_str(rest);
+    Ok(output)
+}
+
+pub fn hostname() -> Option<String> {
+    let mut buf = [0 as libc::c_char; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len()) } != 0 {
+        return None;
+    }
+    buf[buf.len() - 1] = 0;
+    let name = unsafe { CStr::from_ptr(buf.as_ptr()) };
+    name.to_str().ok().map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+
+    fn vars() -> BTreeMap<String, String> {
+        let mut vars = BTreeMap::new();
+        vars.insert(String::from("hostname"), String::from("laptop"));
+        vars.insert(String::from("email"), String::from("me@example.com"));
+        vars
+    }
+
+    #[test]
+    fn substitutes_variables() {
+        let rendered = render("[user]\n  email = {{ email }}\n# {{hostname}}\n", &vars());
+        assert_eq!(rendered, Ok(String::from("[user]\n  email = me@example.com\n# laptop\n")));
+    }
+
+    #[test]
+    fn leaves_text_without_variables_alone() {
+        assert_eq!(render("set number\n{ not a variable }", &vars()),
+                   Ok(String::from("set number\n{ not a variable }")));
+    }
+
+    #[test]
+    fn missing_variables_are_errors() {
+        assert_eq!(render("{{ os_version }}", &vars()),
+                   Err(String::from("undefined template variable `os_version`")));
+    }
+
+    #[test]
+    fn unclosed_variables_are_errors() {
+        assert_eq!(render("email = {{ email", &vars()),
+                   Err(String::from("unclosed `{{` in template")));
+    }
+
+    #[test]
+    fn target_name_strips_the_template_extension() {
+        assert!(is_template(Path::new(".gitconfig.flake-tmpl")));
+        assert_eq!(target_name(Path::new(".gitconfig.flake-tmpl")),
+                   PathBuf::from(".gitconfig"));
+        assert!(!is_template(Path::new(".vimrc")));
+        assert_eq!(target_name(Path::new(".vimrc")), PathBuf::from(".vimrc"));
+    }
+}
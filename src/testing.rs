@@ -0,0 +1,213 @@
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use git2::{Oid, Repository};
+use rustc_serialize::base64::{ToBase64, STANDARD};
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+// A directory of its own for every test, removed when it's dropped. Tests run in parallel, so
+// none of them can use, or change, the real home directory.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("flake-test-{}-{}-{}",
+                                                name,
+                                                process::id(),
+                                                NEXT_DIR.fetch_add(1, Ordering::SeqCst)));
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Writes a file relative to the directory, creating its parents.
+    pub fn write(&self, name: &str, content: &str) -> PathBuf {
+        let path = self.path.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        File::create(&path).and_then(|mut file| file.write_all(content.as_bytes())).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+pub fn init_repo(path: &Path) -> Repository {
+    let repo = Repository::init(path).unwrap();
+    {
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Flake Test").unwrap();
+        config.set_str("user.email", "test@example.com").unwrap();
+    }
+    repo
+}
+
+// Commits every file in the working directory on top of HEAD, the first commit has no parent.
+pub fn commit_all(repo: &Repository, message: &str) -> Oid {
+    let mut index = repo.index().unwrap();
+    index.add_all(["*"].iter(), git2::ADD_DEFAULT, None).unwrap();
+    index.update_all(["*"].iter(), None).unwrap();
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+
+    let signature = repo.signature().unwrap();
+    let parent = repo.head().ok().and_then(|head| head.target()).map(|oid| {
+        repo.find_commit(oid).unwrap()
+    });
+    let parents = parent.iter().collect::<Vec<_>>();
+    repo.commit(Some("HEAD"), &signature, &signature, message, &tree, &parents).unwrap()
+}
+
+// The summaries of the commits reachable from HEAD, the newest first.
+pub fn summaries(repo: &Repository) -> Vec<String> {
+    let mut revwalk = repo.revwalk().unwrap();
+    revwalk.push_head().unwrap();
+    revwalk.map(|oid| {
+            let mut commit = repo.find_commit(oid.unwrap()).unwrap();
+            String::from(commit.summary().unwrap_or(""))
+        })
+        .collect()
+}
+
+pub fn set_modified(path: &Path, seconds: i64) {
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let time = ::libc::timeval {
+        tv_sec: seconds as ::libc::time_t,
+        tv_usec: 0,
+    };
+    assert_eq!(unsafe { ::libc::utimes(path.as_ptr(), [time, time].as_ptr()) }, 0);
+}
+
+pub fn make_fifo(path: &Path) {
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { ::libc::mkfifo(path.as_ptr(), 0o644) }, 0);
+}
+
+// Serves the repositories in `root` over git's smart http protocol, through `git http-backend`,
+// to clients that log in as `username` with `password`. Returns the url of the server, it
+// stops with the test process.
+pub fn serve_http(root: &Path, username: &str, password: &str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let root = PathBuf::from(root);
+    let login = format!("{}:{}", username, password);
+    let authorization = format!("Basic {}", login.as_bytes().to_base64(STANDARD));
+
+    thread::spawn(move || for stream in listener.incoming().flatten() {
+        let _ = serve_request(stream, &root, &authorization);
+    });
+    url
+}
+
+fn serve_request(mut stream: TcpStream, root: &Path, authorization: &str) -> ::std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let parts = request.split_whitespace().collect::<Vec<_>>();
+    if parts.len() < 2 {
+        return Ok(());
+    }
+    let (method, target) = (parts[0], parts[1]);
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(colon) = line.find(':') {
+            headers.push((line[..colon].to_lowercase(), String::from(line[colon + 1..].trim())));
+        }
+    }
+    let header = |name: &str| {
+        headers.iter().find(|header| header.0 == name).map(|header| header.1.clone())
+    };
+
+    if header("authorization") != Some(String::from(authorization)) {
+        return stream.write_all(b"HTTP/1.1 401 Unauthorized\r\n\
+                                  WWW-Authenticate: Basic realm=\"flake\"\r\n\
+                                  Content-Length: 0\r\nConnection: close\r\n\r\n");
+    }
+
+    // Pushes send their pack in chunks.
+    let mut body = Vec::new();
+    if header("transfer-encoding") == Some(String::from("chunked")) {
+        loop {
+            let mut size = String::new();
+            reader.read_line(&mut size)?;
+            let size = usize::from_str_radix(size.trim(), 16).unwrap_or(0);
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk)?;
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    } else {
+        let length = header("content-length").and_then(|length| length.parse().ok());
+        body.resize(length.unwrap_or(0), 0);
+        reader.read_exact(&mut body)?;
+    }
+
+    let (path, query) = match target.find('?') {
+        Some(question) => (&target[..question], &target[question + 1..]),
+        None => (target, ""),
+    };
+    let mut backend = Command::new("git").arg("http-backend")
+        .env("GIT_PROJECT_ROOT", root)
+        .env("GIT_HTTP_EXPORT_ALL", "1")
+        .env("REQUEST_METHOD", method)
+        .env("PATH_INFO", path)
+        .env("QUERY_STRING", query)
+        .env("CONTENT_TYPE", header("content-type").unwrap_or_default())
+        .env("CONTENT_LENGTH", body.len().to_string())
+        .env("REMOTE_USER", "flake")
+        .env("REMOTE_ADDR", "127.0.0.1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    backend.stdin.take().unwrap().write_all(&body)?;
+    let output = backend.wait_with_output()?.stdout;
+
+    // CGI headers end at the first empty line, and carry the status in a `Status` header.
+    let end = output.windows(4).position(|window| window == b"\r\n\r\n").unwrap_or(0);
+    let cgi_headers = String::from_utf8_lossy(&output[..end]).into_owned();
+    let content = &output[(end + 4).min(output.len())..];
+    let mut status = String::from("200 OK");
+    let mut response = String::new();
+    for line in cgi_headers.lines() {
+        if line.to_lowercase().starts_with("status:") {
+            status = String::from(line[7..].trim());
+        } else {
+            response.push_str(line);
+            response.push_str("\r\n");
+        }
+    }
+    stream.write_all(format!("HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                             status,
+                             response,
+                             content.len())
+        .as_bytes())?;
+    stream.write_all(content)
+}
@@ -0,0 +1,115 @@
+use std::collections::HashSet;
+
+use git2::{Error, ObjectType, Oid, Repository, ResetType, StatusOptions, Tree};
+
+use doctor::{Check, Status};
+
+const RECLONE_HINT: &str = "Move the local repository away and run `flake sync` to clone it again";
+const REPAIR_HINT: &str = "Run `flake verify --repair` to reset it to the last commit";
+
+// A consistency check of the local repository. libgit2 can't verify the object database by
+// itself, so every object reachable from HEAD is read, which fails on missing or corrupt ones.
+pub fn verify(repo: &Repository) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let head = match repo.refname_to_id("HEAD") {
+        Ok(head) => head,
+        Err(error) => {
+            checks.push(Check::failed("head",
+                                      Status::Error,
+                                      format!("HEAD doesn't resolve to a commit: {}",
+                                              error.message()),
+                                      RECLONE_HINT));
+            return checks;
+        }
+    };
+    checks.push(Check::ok("head", format!("HEAD is at {}", head)));
+
+    checks.push(match read_objects(repo, head) {
+        Ok(count) => Check::ok("objects", format!("Read {} objects", count)),
+        Err(error) => {
+            Check::failed("objects",
+                          Status::Error,
+                          format!("The history is damaged: {}", error.message()),
+                          RECLONE_HINT)
+        }
+    });
+
+    checks.push(match repo.index() {
+        Ok(index) => Check::ok("index", format!("The index has {} entries", index.len())),
+        Err(error) => {
+            Check::failed("index",
+                          Status::Error,
+                          format!("The index can't be read: {}", error.message()),
+                          REPAIR_HINT)
+        }
+    });
+
+    checks.push(match modified_files(repo) {
+        Ok(0) => Check::ok("workdir", String::from("The files match the index")),
+        Ok(count) => {
+            Check::failed("workdir",
+                          Status::Warning,
+                          format!("{} files differ from the index", count),
+                          REPAIR_HINT)
+        }
+        Err(error) => {
+            Check::failed("workdir",
+                          Status::Error,
+                          format!("The files can't be compared: {}", error.message()),
+                          REPAIR_HINT)
+        }
+    });
+
+    checks
+}
+
+// Resets the index and the files in the repository to HEAD. Damaged history can't be repaired
+// locally, it has to be cloned again.
+pub fn repair(repo: &Repository) -> Result<(), Error> {
+    let head = repo.refname_to_id("HEAD")?;
+    let object = repo.find_object(head, None)?;
+    repo.reset(&object, ResetType::Hard, None)
+}
+
+fn read_objects(repo: &Repository, head: Oid) -> Result<usize, Error> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(head)?;
+
+    let mut seen = HashSet::new();
+    for oid in revwalk {
+        let commit = repo.find_commit(oid?)?;
+        seen.insert(commit.id());
+        read_tree(repo, &commit.tree()?, &mut seen)?;
+    }
+    Ok(seen.len())
+}
+
+fn read_tree(repo: &Repository, tree: &Tree, seen: &mut HashSet<Oid>) -> Result<(), Error> {
+    if !seen.insert(tree.id()) {
+        return Ok(());
+    }
+
+    for entry in tree.iter() {
+        match entry.kind() {
+            Some(ObjectType::Tree) => read_tree(repo, &repo.find_tree(entry.id())?, seen)?,
+            Some(ObjectType::Blob) if seen.insert(entry.id()) => {
+                repo.find_blob(entry.id())?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn modified_files(repo: &Repository) -> Result<usize, Error> {
+    let mut options = StatusOptions::new();
+    options.include_untracked(false).include_ignored(false);
+    let statuses = repo.statuses(Some(&mut options))?;
+    Ok(statuses.iter()
+        .filter(|entry| {
+            entry.status().intersects(::git2::STATUS_WT_MODIFIED | ::git2::STATUS_WT_DELETED |
+                                      ::git2::STATUS_WT_TYPECHANGE)
+        })
+        .count())
+}
@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use rustc_serialize::json::{Json, ToJson};
+
+pub const MANIFEST_FILE: &str = ".flake-xattrs";
+
+// The extended attributes of the tracked files, which git can't store, kept in a JSON file at the
+// root of the repository: each path maps the names of its attributes to their base64 values.
+pub struct Manifest {
+    files: BTreeMap<String, BTreeMap<String, String>>,
+    changed: bool,
+}
+
+impl Manifest {
+    pub fn load(workdir: &Path) -> Manifest {
+        let mut manifest = Manifest {
+            files: BTreeMap::new(),
+            changed: false,
+        };
+
+        let mut content = String::new();
+        let path = workdir.join(MANIFEST_FILE);
+        if File::open(path).and_then(|mut f| f.read_to_string(&mut content)).is_err() {
+            return manifest;
+        }
+
+        let json = match Json::from_str(&content) {
+            Ok(json) => json,
+            Err(error) => {
+                warn!("Ignoring invalid {}: {}", MANIFEST_FILE, error);
+                return manifest;
+            }
+        };
+        for (name, attributes) in json.as_object().into_iter().flat_map(|files| files.iter()) {
+            let attributes = attributes.as_object()
+                .into_iter()
+                .flat_map(|attributes| attributes.iter())
+                .filter_map(|(key, value)| value.as_string().map(|v| (key.clone(), v.to_owned())))
+                .collect();
+            manifest.files.insert(name.clone(), attributes);
+        }
+        manifest
+    }
+
+    // Only writes the manifest when an attribute changed, so it doesn't show up in every commit.
+    pub fn save(&self, workdir: &Path) -> Result<(), io::Error> {
+        if !self.changed {
+            return Ok(());
+        }
+
+        let path = workdir.join(MANIFEST_FILE);
+        let mut file = File::create(path)?;
+        writeln!(file, "{}", self.files.to_json().pretty())
+    }
+
+    // Records the attributes of `path`, the home directory's copy of `name`.
+    pub fn capture(&mut self, name: &Path, path: &Path) -> Result<(), io::Error> {
+        let name = name.to_string_lossy().into_owned();
+        let mut attributes = BTreeMap::new();
+        if path.exists() {
+            for attribute in list(path)? {
+                let value = get(path, &attribute)?;
+                attributes.insert(attribute, value.to_base64(STANDARD));
+            }
+        }
+
+        if attributes.is_empty() {
+            self.changed |= self.files.remove(&name).is_some();
+        } else if self.files.get(&name) != Some(&attributes) {
+            self.files.insert(name, attributes);
+            self.changed = true;
+        }
+        Ok(())
+    }
+
+    // Sets every recorded attribute. Some, like security.selinux, need privileges in some
+    // systems, so each one is attempted separately.
+    pub fn restore(&self, name: &Path, path: &Path) {
+        let attributes = match self.files.get(&*name.to_string_lossy()) {
+            Some(attributes) => attributes,
+            None => return,
+        };
+
+        for (attribute, value) in attributes {
+            let result = value.from_base64()
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))
+                .and_then(|value| set(path, attribute, &value));
+            if let Err(error) = result {
+                warn!("Unable to set the attribute {} on {}: {}",
+                      attribute,
+                      name.display(),
+                      error);
+            }
+        }
+    }
+}
+
+fn list(path: &Path) -> Result<Vec<String>, io::Error> {
+    let path = c_path(path)?;
+    let size = unsafe { sys::listxattr(path.as_ptr(), ::std::ptr::null_mut(), 0) };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut names = vec![0u8; size as usize];
+    let size = unsafe {
+        sys::listxattr(path.as_ptr(), names.as_mut_ptr() as *mut _, names.len())
+    };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    names.truncate(size as usize);
+
+    Ok(names.split(|&byte| byte == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect())
+}
+
+fn get(path: &Path, attribute: &str) -> Result<Vec<u8>, io::Error> {
+    let path = c_path(path)?;
+    let attribute = c_name(attribute)?;
+    let size = unsafe {
+        sys::getxattr(path.as_ptr(), attribute.as_ptr(), ::std::ptr::null_mut(), 0)
+    };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut value = vec![0u8; size as usize];
+    let size = unsafe {
+        sys::getxattr(path.as_ptr(),
+                      attribute.as_ptr(),
+                      value.as_mut_ptr() as *mut _,
+                      value.len())
+    };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    value.truncate(size as usize);
+    Ok(value)
+}
+
+fn set(path: &Path, attribute: &str, value: &[u8]) -> Result<(), io::Error> {
+    let path = c_path(path)?;
+    let attribute = c_name(attribute)?;
+    let result = unsafe {
+        sys::setxattr(path.as_ptr(), attribute.as_ptr(), value.as_ptr() as *const _, value.len())
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn c_path(path: &Path) -> Result<CString, io::Error> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))
+}
+
+fn c_name(attribute: &str) -> Result<CString, io::Error> {
+    CString::new(attribute).map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))
+}
+
+// The xattr calls of every supported system. macOS also takes the position of the value, only
+// used by resource forks, and options, none of which flake needs.
+#[cfg(target_os = "linux")]
+mod sys {
+    use libc::{c_char, c_int, c_void, size_t, ssize_t};
+
+    pub unsafe fn listxattr(path: *const c_char, list: *mut c_char, size: size_t) -> ssize_t {
+        ::libc::listxattr(path, list, size)
+    }
+
+    pub unsafe fn getxattr(path: *const c_char,
+                           name: *const c_char,
+                           value: *mut c_void,
+                           size: size_t)
+                           -> ssize_t {
+        ::libc::getxattr(path, name, value, size)
+    }
+
+    pub unsafe fn setxattr(path: *const c_char,
+                           name: *const c_char,
+                           value: *const c_void,
+                           size: size_t)
+                           -> c_int {
+        ::libc::setxattr(path, name, value, size, 0)
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod sys {
+    use libc::{c_char, c_int, c_void, size_t, ssize_t};
+
+    pub unsafe fn listxattr(path: *const c_char, list: *mut c_char, size: size_t) -> ssize_t {
+        ::libc::listxattr(path, list, size, 0)
+    }
+
+    pub unsafe fn getxattr(path: *const c_char,
+                           name: *const c_char,
+                           value: *mut c_void,
+                           size: size_t)
+                           -> ssize_t {
+        ::libc::getxattr(path, name, value, size, 0, 0)
+    }
+
+    pub unsafe fn setxattr(path: *const c_char,
+                           name: *const c_char,
+                           value: *const c_void,
+                           size: size_t)
+                           -> c_int {
+        ::libc::setxattr(path, name, value, size, 0, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::TempDir;
+
+    #[test]
+    fn attributes_are_captured_and_restored() {
+        let store = TempDir::new("xattrs-store");
+        let home = TempDir::new("xattrs-home");
+        let path = home.write(".vimrc", "set number");
+        set(&path, "user.flake.origin", b"laptop").unwrap();
+        set(&path, "user.flake.binary", &[0, 159, 146, 150]).unwrap();
+
+        let mut manifest = Manifest::load(store.path());
+        manifest.capture(Path::new(".vimrc"), &path).unwrap();
+        manifest.save(store.path()).unwrap();
+        assert!(store.path().join(MANIFEST_FILE).is_file());
+
+        let other = TempDir::new("xattrs-other-home");
+        let restored = other.write(".vimrc", "set number");
+        Manifest::load(store.path()).restore(Path::new(".vimrc"), &restored);
+        assert_eq!(get(&restored, "user.flake.origin").unwrap(), b"laptop");
+        assert_eq!(get(&restored, "user.flake.binary").unwrap(), vec![0, 159, 146, 150]);
+    }
+
+    #[test]
+    fn unchanged_attributes_leave_the_manifest_alone() {
+        let store = TempDir::new("xattrs-unchanged-store");
+        let home = TempDir::new("xattrs-unchanged-home");
+        let path = home.write(".bashrc", "set -o vi");
+
+        let mut manifest = Manifest::load(store.path());
+        manifest.capture(Path::new(".bashrc"), &path).unwrap();
+        manifest.save(store.path()).unwrap();
+        assert!(!store.path().join(MANIFEST_FILE).exists());
+
+        set(&path, "user.flake.origin", b"laptop").unwrap();
+        manifest.capture(Path::new(".bashrc"), &path).unwrap();
+        manifest.save(store.path()).unwrap();
+
+        let mut manifest = Manifest::load(store.path());
+        manifest.capture(Path::new(".bashrc"), &path).unwrap();
+        assert!(!manifest.changed);
+
+        manifest.capture(Path::new(".bashrc"), &home.path().join(".zshrc")).unwrap();
+        assert!(manifest.changed);
+        assert!(manifest.files.is_empty());
+    }
+}